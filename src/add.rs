@@ -0,0 +1,39 @@
+use std::ffi::OsString;
+use std::process::Command;
+
+use anyhow::Result;
+use clap::Parser;
+
+use crate::external::External;
+
+/// Add dependencies to a Cargo.toml manifest file
+///
+/// Editing the manifest doesn't need the MSVC CRT and Windows SDK, all arguments are
+/// forwarded to `cargo add`.
+#[derive(Clone, Debug, Default, Parser)]
+#[command(display_order = 1, disable_help_flag = true)]
+pub struct Add {
+    /// Arguments passed to `cargo add`
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<String>,
+}
+
+impl Add {
+    /// Execute `cargo add` command
+    pub fn execute(&self) -> Result<()> {
+        self.external().execute()
+    }
+
+    /// Generate cargo subcommand
+    pub fn command(&self) -> Result<Command> {
+        self.external().command()
+    }
+
+    /// `cargo add` with the arguments, run as a plain external cargo subcommand
+    fn external(&self) -> External {
+        let args = std::iter::once("add".into())
+            .chain(self.args.iter().map(OsString::from))
+            .collect();
+        External::new(args)
+    }
+}
@@ -1,8 +1,10 @@
-use std::env;
 use std::ffi::OsString;
-use std::process::Command;
+use std::process;
 
-use cargo_xwin::{Build, Check, Clippy, Run, Rustc, Test};
+use cargo_xwin::{
+    Add, Build, Cache, CacheLockedError, Check, Clippy, CwdRunner, Doc, DumpConfig, Env, External,
+    Lock, Nextest, Provision, Remove, Run, Rustc, Targets, Test, Tree, Verify,
+};
 use clap::{Parser, Subcommand};
 
 #[derive(Debug, Parser)]
@@ -13,7 +15,7 @@ use clap::{Parser, Subcommand};
 )]
 pub enum Cli {
     #[command(subcommand, name = "xwin")]
-    Opt(Opt),
+    Opt(XWin),
     // flatten opt here so that `cargo-xwin build` also works
     #[command(flatten)]
     Cargo(Opt),
@@ -21,45 +23,85 @@ pub enum Cli {
     External(Vec<OsString>),
 }
 
+// unknown subcommands of `cargo xwin` are forwarded to cargo
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Subcommand)]
+#[command(version, display_order = 1)]
+pub enum XWin {
+    #[command(flatten)]
+    Opt(Opt),
+    #[command(external_subcommand)]
+    External(Vec<OsString>),
+}
+
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
 #[command(version, display_order = 1)]
 pub enum Opt {
+    Add(Add),
     #[command(name = "build", alias = "b")]
     Build(Build),
+    Cache(Cache),
     Check(Check),
     Clippy(Clippy),
+    CwdRunner(CwdRunner),
+    #[command(name = "doc", alias = "d")]
+    Doc(Doc),
+    DumpConfig(DumpConfig),
+    Env(Env),
+    Lock(Lock),
+    Nextest(Nextest),
+    Provision(Provision),
+    Remove(Remove),
     #[command(name = "run", alias = "r")]
     Run(Run),
     #[command(name = "rustc")]
     Rustc(Rustc),
+    Targets(Targets),
     #[command(name = "test", alias = "t")]
     Test(Test),
+    Tree(Tree),
+    Verify(Verify),
 }
 
 fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
     let cli = Cli::parse();
+    if let Err(err) = run(cli) {
+        if err.downcast_ref::<CacheLockedError>().is_some() {
+            eprintln!("Error: {err:?}");
+            process::exit(CacheLockedError::EXIT_CODE);
+        }
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn run(cli: Cli) -> anyhow::Result<()> {
     match cli {
-        Cli::Opt(opt) | Cli::Cargo(opt) => match opt {
+        Cli::Opt(XWin::Opt(opt)) | Cli::Cargo(opt) => match opt {
+            Opt::Add(add) => add.execute()?,
             Opt::Build(build) => build.execute()?,
+            Opt::Cache(cache) => cache.execute()?,
             Opt::Run(run) => run.execute()?,
             Opt::Rustc(rustc) => rustc.execute()?,
+            Opt::Targets(targets) => targets.execute()?,
             Opt::Test(test) => test.execute()?,
+            Opt::Tree(tree) => tree.execute()?,
             Opt::Check(check) => check.execute()?,
             Opt::Clippy(clippy) => clippy.execute()?,
+            Opt::CwdRunner(cwd_runner) => cwd_runner.execute()?,
+            Opt::Doc(doc) => doc.execute()?,
+            Opt::DumpConfig(dump_config) => dump_config.execute()?,
+            Opt::Env(env) => env.execute()?,
+            Opt::Lock(lock) => lock.execute()?,
+            Opt::Nextest(nextest) => nextest.execute()?,
+            Opt::Provision(provision) => provision.execute()?,
+            Opt::Remove(remove) => remove.execute()?,
+            Opt::Verify(verify) => verify.execute()?,
         },
-        Cli::External(args) => {
-            let mut child = Command::new(env::var_os("CARGO").unwrap_or("cargo".into()))
-                .args(args)
-                .env_remove("CARGO")
-                .spawn()?;
-            let status = child.wait().expect("Failed to wait on cargo process");
-            if !status.success() {
-                std::process::exit(status.code().unwrap_or(1));
-            }
-        }
+        Cli::Opt(XWin::External(args)) | Cli::External(args) => External::new(args).execute()?,
     }
     Ok(())
 }
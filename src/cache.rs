@@ -0,0 +1,253 @@
+use std::path::Path;
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use fs_err as fs;
+use indicatif::HumanBytes;
+
+use crate::compiler::clang::Clang;
+use crate::compiler::clang_cl::ClangCl;
+use crate::compiler::common::{decorate, use_color};
+use crate::lock::{CrtLock, LOCKFILE_NAME};
+use crate::options::XWinOptions;
+
+/// Manage the cached MSVC CRT, Windows SDK and sysroot
+#[derive(Clone, Debug, Parser)]
+#[command(display_order = 1)]
+pub struct Cache {
+    #[command(subcommand)]
+    pub subcommand: CacheSubcommand,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum CacheSubcommand {
+    /// Download the MSVC CRT and Windows SDK splatted by xwin
+    Xwin(CacheXwin),
+    /// Download the prebuilt windows-msvc-sysroot used by the clang backend
+    WindowsMsvcSysroot(CacheWindowsMsvcSysroot),
+    /// List the cached toolchains with their versions, sizes and modification times
+    List(CacheList),
+}
+
+#[derive(Clone, Debug, Default, Parser)]
+pub struct CacheXwin {
+    #[command(flatten)]
+    pub xwin: XWinOptions,
+
+    /// Print the resolved versions and the download size without downloading anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Only print the MSVC CRT version that would be downloaded
+    #[arg(long)]
+    pub print_crt_version: bool,
+
+    /// Only print the Windows SDK version that would be downloaded
+    #[arg(long)]
+    pub print_sdk_version: bool,
+
+    /// Coloring: auto, always, never
+    #[arg(long, value_name = "WHEN")]
+    pub color: Option<String>,
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct CacheWindowsMsvcSysroot {
+    #[command(flatten)]
+    pub xwin: XWinOptions,
+
+    /// The target to check the downloaded sysroot for
+    #[arg(long, value_name = "TRIPLE", default_value = "x86_64-pc-windows-msvc")]
+    pub target: String,
+
+    /// Print the resolved download URL and size without downloading anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Coloring: auto, always, never
+    #[arg(long, value_name = "WHEN")]
+    pub color: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Parser)]
+pub struct CacheList {
+    #[command(flatten)]
+    pub xwin: XWinOptions,
+}
+
+impl Cache {
+    /// Execute `cargo xwin cache` command
+    pub fn execute(&self) -> Result<()> {
+        match &self.subcommand {
+            CacheSubcommand::Xwin(xwin) => xwin.execute(),
+            CacheSubcommand::WindowsMsvcSysroot(sysroot) => sysroot.execute(),
+            CacheSubcommand::List(list) => list.execute(),
+        }
+    }
+}
+
+impl CacheXwin {
+    /// Execute `cargo xwin cache xwin` command
+    pub fn execute(&self) -> Result<()> {
+        let color = use_color(self.color.as_deref());
+        let xwin_cache_dir = self.xwin.xwin_cache_dir()?;
+        let clang_cl = ClangCl::new(&self.xwin);
+        if self.print_crt_version || self.print_sdk_version {
+            let pruned = clang_cl.resolve_download(xwin_cache_dir, color)?;
+            if self.print_crt_version {
+                println!("{}", pruned.crt_version);
+            }
+            if self.print_sdk_version {
+                println!("{}", pruned.sdk_version);
+            }
+            return Ok(());
+        }
+        if self.dry_run {
+            let pruned = clang_cl.resolve_download(xwin_cache_dir, color)?;
+            let size: u64 = pruned.payloads.iter().map(|payload| payload.size).sum();
+            println!("MSVC CRT: {}", pruned.crt_version);
+            println!("Windows SDK: {}", pruned.sdk_version);
+            println!(
+                "Download: {} payloads, {}",
+                pruned.payloads.len(),
+                HumanBytes(size)
+            );
+            return Ok(());
+        }
+        clang_cl.setup_msvc_crt(xwin_cache_dir.clone(), color, None)?;
+        eprintln!(
+            "{}",
+            decorate(
+                color,
+                "✅",
+                &format!(
+                    "Cached MSVC CRT and Windows SDK in {}",
+                    xwin_cache_dir.display()
+                )
+            )
+        );
+        Ok(())
+    }
+}
+
+impl CacheWindowsMsvcSysroot {
+    /// Execute `cargo xwin cache windows-msvc-sysroot` command
+    pub fn execute(&self) -> Result<()> {
+        let color = use_color(self.color.as_deref());
+        let clang = Clang::new(&self.xwin);
+        if self.dry_run {
+            let asset = clang.get_latest_msvc_sysroot_asset(self.xwin.http_agent(color)?)?;
+            println!("MSVC sysroot: {}", asset.url);
+            match asset.size {
+                Some(size) => println!("Download: {}", HumanBytes(size)),
+                None => println!("Download: unknown size"),
+            }
+            return Ok(());
+        }
+        let target = self.target.replace("-pc-", "-unknown-");
+        let dir = clang.setup_msvc_sysroot(self.xwin.sysroot_cache_dir()?, &target, color, None)?;
+        eprintln!(
+            "{}",
+            decorate(
+                color,
+                "✅",
+                &format!("Cached MSVC sysroot in {}", dir.display())
+            )
+        );
+        Ok(())
+    }
+}
+
+impl CacheList {
+    /// Execute `cargo xwin cache list` command
+    ///
+    /// Only reads the `DONE` markers and the lock files written next to them in the cache
+    /// directories, nothing is created, downloaded or removed.
+    pub fn execute(&self) -> Result<()> {
+        let mut found = false;
+        for root in self.xwin.cache_roots()? {
+            // A cache directory that doesn't exist yet has nothing to list
+            let Ok(entries) = fs::read_dir(&root) else {
+                continue;
+            };
+            let mut dirs: Vec<_> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|dir| dir.join("DONE").is_file())
+                .collect();
+            dirs.sort();
+            for dir in dirs {
+                print_cached_toolchain(&dir)?;
+                found = true;
+            }
+        }
+        if !found {
+            println!(
+                "No toolchains cached in {}",
+                self.xwin.cache_dir_path()?.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Prints the toolchain cached in `dir`, its `DONE` marker holds the download URL of a
+/// windows-msvc-sysroot and the architectures of an xwin splat
+fn print_cached_toolchain(dir: &Path) -> Result<()> {
+    let done_mark_file = dir.join("DONE");
+    let done = fs::read_to_string(&done_mark_file)?;
+    let done = done.trim();
+    if done.contains("://") {
+        println!("windows-msvc-sysroot: {}", dir.display());
+        // https://github.com/<repo>/releases/download/<tag>/<asset>
+        if let Some(tag) = done
+            .split_once("/releases/download/")
+            .and_then(|(_, rest)| rest.split_once('/'))
+            .map(|(tag, _)| tag)
+        {
+            println!("  Release: {tag}");
+        }
+        println!("  URL: {done}");
+    } else {
+        println!("xwin: {}", dir.display());
+        println!("  Architectures: {done}");
+        if let Ok(variants) = fs::read_to_string(dir.join("VARIANTS")) {
+            println!("  Variants: {}", variants.trim());
+        }
+        // Caches from before the lock file was written only know their architectures
+        if let Some(crt_lock) = fs::read_to_string(dir.join(LOCKFILE_NAME))
+            .ok()
+            .and_then(|content| serde_json::from_str::<CrtLock>(&content).ok())
+        {
+            println!("  MSVC CRT: {}", crt_lock.crt_version);
+            println!("  Windows SDK: {}", crt_lock.sdk_version);
+        }
+    }
+    print_size_and_modified(dir, &done_mark_file)
+}
+
+/// Prints the total size of `dir` and when `done_mark_file` was last written
+fn print_size_and_modified(dir: &Path, done_mark_file: &Path) -> Result<()> {
+    println!("  Size: {}", HumanBytes(dir_size(dir)?));
+    let modified = fs::metadata(done_mark_file)?.modified()?;
+    println!(
+        "  Modified: {}",
+        humantime::format_rfc3339_seconds(modified)
+    );
+    Ok(())
+}
+
+/// Returns the total size of the files in `dir`, symlinks aren't followed
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
@@ -1,28 +1,41 @@
 use std::env;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use fs_err as fs;
 use path_slash::PathExt;
 use serde::Deserialize;
 
+use crate::compiler::clang_cl::ClangCl;
 use crate::compiler::common::{
-    adjust_canonicalization, default_build_target_from_config, get_rustflags, http_agent,
-    setup_cmake_env, setup_env_path, setup_llvm_tools, setup_target_compiler_and_linker_env,
+    adjust_canonicalization, c_flags, decorate, default_build_targets, progress_style,
+    relocatable_cmake_paths, resolve_target_compilers, retry_download, setup_cmake_env,
+    setup_debug_symbol_path, setup_env_path, setup_llvm_tools,
+    setup_target_compiler_and_linker_env, setup_target_rustflags, split_targets, use_color,
+    user_rustflags, warn, write_file_if_changed, xwin_arch,
 };
+use crate::lock::{SysrootLock, ToolchainLock, LOCKFILE_NAME};
+use crate::options::{ClangSysroot, SdkInclude, XWinOptions};
 
 const MSVC_SYSROOT_REPOSITORY: &str = "trcrsired/windows-msvc-sysroot";
-const MSVC_SYSROOT_ASSET_NAME: &str = "windows-msvc-sysroot.tar.xz";
+/// Sysroot assets in order of preference, zstd decompresses much faster than xz
+const MSVC_SYSROOT_ASSET_NAMES: &[&str] = &[
+    "windows-msvc-sysroot.tar.zst",
+    "windows-msvc-sysroot.tar.xz",
+];
 const FALLBACK_DOWNLOAD_URL: &str = "https://github.com/trcrsired/windows-msvc-sysroot/releases/download/2024-12-25/windows-msvc-sysroot.tar.xz";
 
 #[derive(Debug)]
-pub struct Clang;
+pub struct Clang<'a> {
+    xwin_options: &'a XWinOptions,
+}
 
-impl Clang {
-    pub fn new() -> Self {
-        Clang
+impl<'a> Clang<'a> {
+    pub fn new(xwin_options: &'a XWinOptions) -> Self {
+        Self { xwin_options }
     }
 
     pub fn apply_command_env(
@@ -37,37 +50,136 @@ impl Clang {
             .and_then(|p| p.parent().map(|x| x.to_path_buf()))
             .or_else(|| env::current_dir().ok())
             .unwrap();
-        let mut targets = cargo.target.clone();
+        let lock = ToolchainLock::load_if_locked(self.xwin_options, &workdir)?;
+        let mut targets = split_targets(&cargo.target);
         if targets.is_empty() {
-            if let Some(build_target) = default_build_target_from_config(&workdir)? {
-                // if no target is specified, use the default build target
-                // Note that this is required, otherwise it may fail with link errors
-                cmd.arg("--target").arg(&build_target);
-                targets.push(build_target);
+            // if no target is specified, use the default build targets
+            // Note that this is required, otherwise it may fail with link errors
+            targets = default_build_targets(&workdir)?;
+            for target in &targets {
+                cmd.arg("--target").arg(target);
             }
         }
 
         for target in &targets {
             if target.contains("msvc") {
-                let msvc_sysroot_dir = self
-                    .setup_msvc_sysroot(cache_dir.clone())
-                    .context("Failed to setup MSVC sysroot")?;
                 // x86_64-pc-windows-msvc -> x86_64-windows-msvc
                 let target_no_vendor = target.replace("-pc-", "-");
                 let target_unknown_vendor = target.replace("-pc-", "-unknown-");
                 let env_target = target.to_lowercase().replace('-', "_");
+                let color = use_color(cargo.color.as_deref());
+                let (include_dirs, lib_dirs, headers_guard) = match self.xwin_options.clang_sysroot
+                {
+                    ClangSysroot::WindowsMsvcSysroot => {
+                        let msvc_sysroot_dir = self
+                            .setup_msvc_sysroot(
+                                self.xwin_options.sysroot_cache_dir()?,
+                                &target_unknown_vendor,
+                                color,
+                                lock.as_ref().map(ToolchainLock::msvc_sysroot).transpose()?,
+                            )
+                            .context("Failed to setup MSVC sysroot")?;
+                        let dir =
+                            adjust_canonicalization(msvc_sysroot_dir.to_slash_lossy().to_string());
+                        (
+                            vec![format!("{dir}/include"), format!("{dir}/include/c++/stl")],
+                            vec![format!("{dir}/lib/{target_unknown_vendor}")],
+                            None,
+                        )
+                    }
+                    ClangSysroot::Xwin => {
+                        let xwin_cache_dir = self.xwin_options.xwin_cache_dir()?;
+                        let clang_cl = ClangCl::new(self.xwin_options);
+                        clang_cl
+                            .setup_msvc_crt(
+                                xwin_cache_dir.clone(),
+                                color,
+                                lock.as_ref().map(ToolchainLock::crt).transpose()?,
+                            )
+                            .context("Failed to setup MSVC CRT")?;
+                        let dir =
+                            adjust_canonicalization(xwin_cache_dir.to_slash_lossy().to_string());
+                        let arch = xwin_arch(target)?;
+                        if self.xwin_options.xwin_include_debug_symbols {
+                            setup_debug_symbol_path(cmd, &xwin_cache_dir, arch);
+                        }
+                        // Unlike windows-msvc-sysroot, the MSVC STL headers live
+                        // next to the CRT headers in `crt/include`
+                        (
+                            self.xwin_options.xwin_include_dirs(&dir)?,
+                            vec![
+                                format!("{dir}/{}", self.xwin_options.crt_lib_dir(arch)),
+                                format!("{dir}/sdk/lib/um/{arch}"),
+                                format!("{dir}/sdk/lib/ucrt/{arch}"),
+                            ],
+                            clang_cl.excluded_headers_guard(&xwin_cache_dir)?,
+                        )
+                    }
+                };
 
-                setup_llvm_tools(&env_path, &cache_dir).context("Failed to setup LLVM tools")?;
-                setup_target_compiler_and_linker_env(cmd, &env_target, "clang");
+                let llvm_tools = setup_llvm_tools(
+                    &env_path,
+                    &cache_dir,
+                    self.xwin_options.no_symlink_tools,
+                    self.xwin_options.absolute_linker,
+                )
+                .context("Failed to setup LLVM tools")?;
+                let (cc, cxx) = resolve_target_compilers(
+                    self.xwin_options.cc.as_deref(),
+                    self.xwin_options.cxx.as_deref(),
+                    "clang",
+                    "clang",
+                    &env_path,
+                )?;
+                setup_target_compiler_and_linker_env(cmd, &env_target, cc, cxx, &llvm_tools);
 
                 let user_set_c_flags = env::var("CFLAGS").unwrap_or_default();
                 let user_set_cxx_flags = env::var("CXXFLAGS").unwrap_or_default();
-                let sysroot_dir =
-                    adjust_canonicalization(msvc_sysroot_dir.to_slash_lossy().to_string());
-                let clang_flags = format!(
-                    "--target={target_no_vendor} -fuse-ld=lld-link -I{dir}/include -I{dir}/include/c++/stl -L{dir}/lib/{target_unknown_vendor}",
-                    dir = sysroot_dir,
+                let include_dirs: Vec<_> = match self.xwin_options.clang_sysroot {
+                    // The prebuilt sysroot has a single include directory for the CRT and SDK
+                    ClangSysroot::WindowsMsvcSysroot => {
+                        let mut order = self.xwin_options.sdk_include_order();
+                        let extra_first = order.first() == Some(&SdkInclude::Extra);
+                        let extra_last = order.last() == Some(&SdkInclude::Extra);
+                        order.retain(|include| *include != SdkInclude::Extra);
+                        if !(extra_first || extra_last) || order != SdkInclude::DEFAULT_ORDER[1..] {
+                            return Err(self.xwin_options.unsupported_sdk_include_order(
+                                "the windows-msvc-sysroot",
+                                "it has a single include directory for the CRT and SDK that \
+                                 `extra` can only go before or after",
+                            ));
+                        }
+                        let extra = self.xwin_options.extra_include_dirs()?;
+                        if extra_first {
+                            extra.into_iter().chain(include_dirs).collect()
+                        } else {
+                            include_dirs.into_iter().chain(extra).collect()
+                        }
+                    }
+                    ClangSysroot::Xwin => include_dirs,
+                };
+                let user_rustflags = user_rustflags(&workdir, target, &cargo.config)?;
+                let runtime_lib_dirs =
+                    self.xwin_options
+                        .clang_runtime_lib_dirs(target, &env_path, &user_rustflags);
+                let lib_dirs: Vec<_> = lib_dirs
+                    .into_iter()
+                    .chain(self.xwin_options.extra_lib_dirs()?)
+                    .chain(runtime_lib_dirs.iter().cloned())
+                    .collect();
+                let include_flags: Vec<_> =
+                    include_dirs.iter().map(|dir| format!("-I{dir}")).collect();
+                let include_flags = c_flags(cmd, &include_flags);
+                let lib_flags: Vec<_> = lib_dirs.iter().map(|dir| format!("-L{dir}")).collect();
+                let mut clang_flags = format!(
+                    "--target={target_no_vendor} -fuse-ld=lld-link {abi_flags}{include_flags} {lib_flags}",
+                    abi_flags = self.xwin_options.abi_c_flags(false),
+                    lib_flags = c_flags(cmd, &lib_flags),
                 );
+                if let Some(guard) = headers_guard {
+                    let include = c_flags(cmd, &["-include".to_string(), guard]);
+                    clang_flags = format!("{include} {clang_flags}");
+                }
                 cmd.env(
                     format!("CFLAGS_{env_target}"),
                     format!("{clang_flags} {user_set_c_flags}",),
@@ -78,32 +190,48 @@ impl Clang {
                 );
                 cmd.env(
                     format!("BINDGEN_EXTRA_CLANG_ARGS_{env_target}"),
-                    format!("-I{dir}/include -I{dir}/include/c++/stl", dir = sysroot_dir),
-                );
-                cmd.env(
-                    "RCFLAGS",
-                    format!("-I{dir}/include -I{dir}/include/c++/stl", dir = sysroot_dir),
+                    &include_flags,
                 );
+                cmd.env("RCFLAGS", &include_flags);
 
-                let mut rustflags = get_rustflags(&workdir, target)?.unwrap_or_default();
-                rustflags.flags.extend([
+                let mut rustflags = vec![
                     "-C".to_string(),
                     "linker-flavor=lld-link".to_string(),
                     "-C".to_string(),
                     "link-arg=-defaultlib:oldnames".to_string(),
-                ]);
-                rustflags.push(format!(
-                    "-Lnative={dir}/lib/{target_unknown_vendor}",
-                    dir = sysroot_dir,
-                ));
-                cmd.env("CARGO_ENCODED_RUSTFLAGS", rustflags.encode()?);
+                ];
+                rustflags.extend(lib_dirs.iter().map(|dir| format!("-Lnative={dir}")));
+                rustflags.extend(self.xwin_options.pgo_rustflags()?);
+                rustflags.extend(self.xwin_options.link_arg_rustflags(&user_rustflags)?);
+                rustflags.extend(self.xwin_options.abi_rustflags());
+                rustflags.extend(self.xwin_options.extra_rustflags());
+                setup_target_rustflags(cmd, &workdir, target, &rustflags, &cargo.config, color)?;
                 cmd.env("PATH", &env_path);
 
                 // CMake support
-                let cmake_toolchain = self
-                    .setup_cmake_toolchain(target, &sysroot_dir, &cache_dir)
-                    .with_context(|| format!("Failed to setup CMake toolchain for {}", target))?;
-                setup_cmake_env(cmd, target, cmake_toolchain);
+                if !self.xwin_options.no_cmake {
+                    let cmake_toolchain = self
+                        .setup_cmake_toolchain(
+                            target,
+                            &include_dirs,
+                            &lib_dirs,
+                            &cache_dir,
+                            cc,
+                            self.xwin_options.cxx.as_deref().unwrap_or("clang++"),
+                        )
+                        .with_context(|| {
+                            format!("Failed to setup CMake toolchain for {}", target)
+                        })?;
+                    setup_cmake_env(
+                        cmd,
+                        target,
+                        cmake_toolchain,
+                        &self.xwin_options.cmake_generator,
+                        &env_path,
+                        &workdir,
+                        color,
+                    )?;
+                }
             }
         }
         Ok(())
@@ -114,67 +242,234 @@ impl Clang {
     /// If the sysroot is already downloaded and unpacked, it will be reused.
     /// The sysroot will be stored in `<cache_dir>/windows-msvc-sysroot`.
     /// A file named `DONE` will be created in the same directory with the
-    /// download URL as its content.
+    /// download URL as its content. The sysroot is downloaded again when key files
+    /// for `target` are missing despite the `DONE` marker.
     ///
     /// The environment variable `XWIN_MSVC_SYSROOT_DOWNLOAD_URL` can be used
     /// to override the download URL.
-    fn setup_msvc_sysroot(&self, cache_dir: PathBuf) -> Result<PathBuf> {
+    pub(crate) fn setup_msvc_sysroot(
+        &self,
+        cache_dir: PathBuf,
+        target: &str,
+        color: bool,
+        lock: Option<&SysrootLock>,
+    ) -> Result<PathBuf> {
         let msvc_sysroot_dir = cache_dir.join("windows-msvc-sysroot");
         let done_mark_file = msvc_sysroot_dir.join("DONE");
         if msvc_sysroot_dir.is_dir() {
-            if done_mark_file.is_file() {
+            let downloaded_url = fs::read_to_string(&done_mark_file).ok();
+            let locked = match (lock, &downloaded_url) {
+                (Some(lock), Some(url)) => url.trim() == lock.url,
+                _ => true,
+            };
+            if downloaded_url.is_some()
+                && locked
+                && is_msvc_sysroot_complete(&msvc_sysroot_dir, target)
+            {
                 // Already downloaded and unpacked
                 return Ok(msvc_sysroot_dir);
-            } else {
-                // Download again
-                fs::remove_dir_all(&msvc_sysroot_dir)
-                    .context("Failed to remove existing msvc sysroot")?;
             }
+            self.xwin_options
+                .ensure_cache_unlocked("MSVC sysroot", &msvc_sysroot_dir)?;
+            if !locked {
+                warn(
+                    color,
+                    &format!(
+                        "cached MSVC sysroot doesn't match {LOCKFILE_NAME}, downloading it again"
+                    ),
+                );
+            } else if downloaded_url.is_some() {
+                warn(
+                    color,
+                    &format!(
+                        "MSVC sysroot at {} is incomplete, downloading it again",
+                        msvc_sysroot_dir.display()
+                    ),
+                );
+            }
+            // Download again
+            fs::remove_dir_all(&msvc_sysroot_dir)
+                .context("Failed to remove existing msvc sysroot")?;
         }
 
-        let agent = http_agent()?;
+        self.xwin_options
+            .ensure_cache_unlocked("MSVC sysroot", &msvc_sysroot_dir)?;
+        self.xwin_options.ensure_license_accepted()?;
+        let agent = self.xwin_options.http_agent(color)?;
         // fetch release info to get download url
-        let download_url = self
-            .get_latest_msvc_sysroot_download_url(agent.clone())
-            .unwrap_or_else(|_| FALLBACK_DOWNLOAD_URL.to_string());
-        self.download_msvc_sysroot(&cache_dir, agent, &download_url)
+        let last_url_file = cache_dir.join("windows-msvc-sysroot-url");
+        let latest_url = match lock {
+            Some(lock) => Ok(lock.url.clone()),
+            None => self
+                .get_latest_msvc_sysroot_asset(agent.clone())
+                .map(|asset| asset.url),
+        };
+        let download_url = match latest_url {
+            Ok(url) => {
+                fs::write(&last_url_file, &url)?;
+                url
+            }
+            Err(err) if self.xwin_options.xwin_no_sysroot_fallback => return Err(err),
+            Err(err) => {
+                // Prefer the last resolved URL over the pinned fallback,
+                // which is only used for the default repository
+                let last_url = fs::read_to_string(&last_url_file)
+                    .ok()
+                    .map(|url| url.trim().to_string())
+                    .filter(|url| !url.is_empty());
+                let url = match last_url {
+                    Some(url) => url,
+                    None if self.xwin_options.sysroot_repo.is_none() => {
+                        FALLBACK_DOWNLOAD_URL.to_string()
+                    }
+                    None => return Err(err),
+                };
+                warn(
+                    color,
+                    &format!("{err:#}, falling back to MSVC sysroot {url}"),
+                );
+                url
+            }
+        };
+        let sha256 = self
+            .download_msvc_sysroot(&cache_dir, agent, &download_url, color)
             .context("Failed to unpack msvc sysroot")?;
+        if let Some(lock) = lock.filter(|lock| lock.sha256 != sha256) {
+            fs::remove_dir_all(&msvc_sysroot_dir)?;
+            bail!(
+                "MSVC sysroot downloaded from {download_url} has sha256 {sha256}, expected {} from {LOCKFILE_NAME}",
+                lock.sha256
+            );
+        }
+        if !is_msvc_sysroot_complete(&msvc_sysroot_dir, target) {
+            bail!(
+                "MSVC sysroot downloaded from {download_url} is missing windows.h or the libraries for {target}"
+            );
+        }
         fs::write(done_mark_file, download_url)?;
         Ok(msvc_sysroot_dir)
     }
 
-    /// Retrieves the latest MSVC sysroot download URL from GitHub Releases.
+    /// Retrieves the latest MSVC sysroot asset from GitHub Releases.
     ///
     /// The function uses the `ureq` agent to make an HTTP GET request to the GitHub API. If a
     /// `GITHUB_TOKEN` environment variable is present, it includes it as a Bearer token for
     /// authentication.
     ///
-    fn get_latest_msvc_sysroot_download_url(&self, agent: ureq::Agent) -> Result<String> {
+    /// Returns the download URL with the sha256 digest and size reported by GitHub, if any.
+    pub(crate) fn get_latest_msvc_sysroot_asset(
+        &self,
+        agent: ureq::Agent,
+    ) -> Result<MsvcSysrootAsset> {
         if let Ok(url) = env::var("XWIN_MSVC_SYSROOT_DOWNLOAD_URL") {
-            return Ok(url);
+            return Ok(MsvcSysrootAsset {
+                url,
+                sha256: None,
+                size: None,
+            });
         }
-        let mut request = agent
-            .get(&format!(
-                "https://api.github.com/repos/{}/releases/latest",
-                MSVC_SYSROOT_REPOSITORY
-            ))
-            .set("X-GitHub-Api-Version", "2022-11-28");
-        if let Ok(token) = env::var("GITHUB_TOKEN") {
-            request = request.set("Authorization", &format!("Bearer {token}"));
-        }
-        let response = request.call().context("Failed to get GitHub release")?;
-        let release: GitHubRelease = response
-            .into_json()
-            .context("Failed to deserialize GitHub release")?;
-        let asset = release
-            .assets
+        let release = self.get_latest_release(&agent)?;
+        let asset = MSVC_SYSROOT_ASSET_NAMES
             .iter()
-            .find(|x| x.name == MSVC_SYSROOT_ASSET_NAME)
+            .find_map(|name| release.assets.iter().find(|x| x.name == *name))
             .with_context(|| {
-                format!("Failed to find {MSVC_SYSROOT_ASSET_NAME} in GitHub release")
+                format!(
+                    "Failed to find any of {} in GitHub release",
+                    MSVC_SYSROOT_ASSET_NAMES.join(", ")
+                )
             })?;
-        let download_url = asset.browser_download_url.clone();
-        Ok(download_url)
+        let sha256 = asset
+            .digest
+            .as_deref()
+            .and_then(|digest| digest.strip_prefix("sha256:"))
+            .map(String::from);
+        Ok(MsvcSysrootAsset {
+            url: asset.browser_download_url.clone(),
+            sha256,
+            size: asset.size,
+        })
+    }
+
+    /// Fetches the latest release of the sysroot repository, retrying transient failures
+    fn get_latest_release(&self, agent: &ureq::Agent) -> Result<GitHubRelease> {
+        const MAX_RETRIES: u32 = 3;
+        let repo = self
+            .xwin_options
+            .sysroot_repo
+            .as_deref()
+            .unwrap_or(MSVC_SYSROOT_REPOSITORY);
+        let token = env::var("GITHUB_TOKEN").ok();
+        let auth = if token.is_some() {
+            "authenticated with `GITHUB_TOKEN`"
+        } else {
+            "unauthenticated"
+        };
+        let mut retry_count = 0;
+        loop {
+            let mut request = agent
+                .get(&format!(
+                    "https://api.github.com/repos/{repo}/releases/latest"
+                ))
+                .set("X-GitHub-Api-Version", "2022-11-28");
+            if let Some(token) = &token {
+                request = request.set("Authorization", &format!("Bearer {token}"));
+            }
+            let err = match request.call() {
+                Ok(response) => {
+                    return response
+                        .into_json()
+                        .context("Failed to deserialize GitHub release");
+                }
+                Err(ureq::Error::Status(status @ (403 | 429), _)) if token.is_none() => {
+                    bail!(
+                        "GitHub API rate limit exceeded (HTTP {status}) while resolving the latest MSVC sysroot, \
+                         set `GITHUB_TOKEN` to authenticate the request"
+                    );
+                }
+                // Server errors and network failures are usually transient
+                Err(ureq::Error::Status(status, _)) if status < 500 => {
+                    bail!("Failed to get GitHub release of {repo}: HTTP {status} ({auth})");
+                }
+                Err(err) => err,
+            };
+            retry_count += 1;
+            if retry_count >= MAX_RETRIES {
+                let reason = match &err {
+                    ureq::Error::Status(status, _) => format!("HTTP {status}"),
+                    ureq::Error::Transport(transport) => transport.to_string(),
+                };
+                bail!(
+                    "Failed to get GitHub release of {repo} after {MAX_RETRIES} attempts: {reason} ({auth})"
+                );
+            }
+            eprintln!(
+                "Retrying GitHub release request (attempt {}/{})",
+                retry_count + 1,
+                MAX_RETRIES
+            );
+            std::thread::sleep(Duration::from_secs(2u64.pow(retry_count - 1)));
+        }
+    }
+
+    /// Resolves the latest MSVC sysroot and its sha256 checksum for `cargo xwin lock`
+    ///
+    /// The sysroot is downloaded to compute the checksum when GitHub doesn't report it.
+    pub(crate) fn resolve_msvc_sysroot_lock(&self, color: bool) -> Result<SysrootLock> {
+        use sha2::Digest;
+
+        let agent = self.xwin_options.http_agent(color)?;
+        let MsvcSysrootAsset { url, sha256, .. } =
+            self.get_latest_msvc_sysroot_asset(agent.clone())?;
+        let sha256 = match sha256 {
+            Some(sha256) => sha256,
+            None => {
+                let response = agent.get(&url).call()?;
+                let mut hasher = sha2::Sha256::new();
+                io::copy(&mut response.into_reader(), &mut hasher)?;
+                format!("{:x}", hasher.finalize())
+            }
+        };
+        Ok(SysrootLock { url, sha256 })
     }
 
     fn download_msvc_sysroot_once(
@@ -182,8 +477,10 @@ impl Clang {
         cache_dir: &Path,
         agent: &ureq::Agent,
         download_url: &str,
-    ) -> Result<()> {
-        use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+        color: bool,
+    ) -> Result<String> {
+        use indicatif::ProgressBar;
+        use sha2::Digest;
         use xz2::read::XzDecoder;
 
         let response = agent.get(download_url).call()?;
@@ -192,32 +489,47 @@ impl Clang {
             .and_then(|s| s.parse::<u64>().ok())
             .unwrap_or(0);
         let pb = ProgressBar::new(len);
-        pb.set_draw_target(ProgressDrawTarget::stdout());
-        pb.set_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.green} {prefix:.bold} [{elapsed}] {wide_bar:.green} {bytes}/{total_bytes} {msg}",
-                )?
-                .progress_chars("=> "),
-        );
+        pb.set_draw_target(self.xwin_options.progress_target().into());
+        pb.set_style(progress_style(color));
         pb.set_prefix("sysroot");
-        pb.set_message("📥 downloading");
+        pb.set_message(decorate(color, "📥", "downloading"));
         if pb.is_hidden() {
-            eprintln!("📥 Downloading MSVC sysroot...");
+            eprintln!("{}", decorate(color, "📥", "Downloading MSVC sysroot..."));
         }
         let start_time = Instant::now();
-        let reader = pb.wrap_read(response.into_reader());
-        let tar = XzDecoder::new(reader);
+        let mut hasher = sha2::Sha256::new();
+        let mut reader = pb.wrap_read(HashReader {
+            inner: response.into_reader(),
+            hasher: &mut hasher,
+        });
+        // Pick the decoder from the file extension of the asset
+        let tar: Box<dyn io::Read + '_> = if download_url.ends_with(".zst") {
+            Box::new(zstd::Decoder::new(&mut reader)?)
+        } else {
+            Box::new(XzDecoder::new(&mut reader))
+        };
         let mut archive = tar::Archive::new(tar);
         archive.unpack(cache_dir)?;
+        // Read the rest of the download, e.g. the tar padding, so that all of it is hashed
+        io::copy(&mut archive.into_inner(), &mut io::sink())?;
+        io::copy(&mut reader, &mut io::sink())?;
+        drop(reader);
+        let sha256 = format!("{:x}", hasher.finalize());
         pb.finish_with_message("Download completed");
         if pb.is_hidden() {
             // Display elapsed time in human-readable format to seconds only
             let elapsed =
                 humantime::format_duration(Duration::from_secs(start_time.elapsed().as_secs()));
-            eprintln!("✅ Downloaded MSVC sysroot in {elapsed}.");
+            eprintln!(
+                "{}",
+                decorate(
+                    color,
+                    "✅",
+                    &format!("Downloaded MSVC sysroot in {elapsed}.")
+                )
+            );
         }
-        Ok(())
+        Ok(sha256)
     }
 
     fn download_msvc_sysroot(
@@ -225,47 +537,25 @@ impl Clang {
         cache_dir: &Path,
         agent: ureq::Agent,
         download_url: &str,
-    ) -> Result<()> {
-        use std::time::Duration;
-
-        const MAX_RETRIES: u32 = 3;
-        let mut retry_count = 0;
-        let mut last_error = None;
-
-        while retry_count < MAX_RETRIES {
-            if retry_count > 0 {
-                let wait_time = Duration::from_secs(2u64.pow(retry_count - 1));
-                std::thread::sleep(wait_time);
-                eprintln!(
-                    "Retrying download (attempt {}/{})",
-                    retry_count + 1,
-                    MAX_RETRIES
-                );
-            }
-
-            match self.download_msvc_sysroot_once(cache_dir, &agent, download_url) {
-                Ok(()) => return Ok(()),
-                Err(e) => {
-                    last_error = Some(e);
-                    retry_count += 1;
-                }
-            }
-        }
-
-        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("Failed to download MSVC sysroot")))
+        color: bool,
+    ) -> Result<String> {
+        retry_download("MSVC sysroot", color, || {
+            self.download_msvc_sysroot_once(cache_dir, &agent, download_url, color)
+        })
     }
 
     fn setup_cmake_toolchain(
         &self,
         target: &str,
-        sysroot_dir: &str,
+        include_dirs: &[String],
+        lib_dirs: &[String],
         cache_dir: &Path,
+        cc: &str,
+        cxx: &str,
     ) -> Result<PathBuf> {
         // x86_64-pc-windows-msvc -> x86_64-windows-msvc
         let target_no_vendor = target.replace("-pc-", "-");
-        let target_unknown_vendor = target.replace("-pc-", "-unknown-");
-        let cmake_cache_dir = cache_dir.join("cmake").join("clang");
-        fs::create_dir_all(&cmake_cache_dir)?;
+        let cmake_cache_dir = self.xwin_options.cmake_dir(cache_dir, "clang")?;
 
         let toolchain_file = cmake_cache_dir.join(format!("{}-toolchain.cmake", target));
         let target_arch = target
@@ -283,10 +573,10 @@ impl Clang {
         let content = format!(
             r#"
 set(CMAKE_SYSTEM_NAME Windows)
-set(CMAKE_SYSTEM_PROCESSOR {processor})
+set(CMAKE_SYSTEM_PROCESSOR {processor}){system_version}
 
-set(CMAKE_C_COMPILER clang CACHE FILEPATH "")
-set(CMAKE_CXX_COMPILER clang++ CACHE FILEPATH "")
+set(CMAKE_C_COMPILER {cc} CACHE FILEPATH "")
+set(CMAKE_CXX_COMPILER {cxx} CACHE FILEPATH "")
 set(CMAKE_LINKER lld-link CACHE FILEPATH "")
 set(CMAKE_RC_COMPILER llvm-rc CACHE FILEPATH "")
 set(CMAKE_C_COMPILER_TARGET {target} CACHE STRING "")
@@ -294,17 +584,23 @@ set(CMAKE_CXX_COMPILER_TARGET {target} CACHE STRING "")
 
 set(COMPILE_FLAGS
     --target={target_no_vendor}
-    -fuse-ld=lld-link
-    -I{dir}/include
-    -I{dir}/include/c++/stl)
+    -fuse-ld=lld-link{include_flags})
 
 set(LINK_FLAGS
-    /manifest:no
-    -libpath:"{dir}/lib/{target_unknown_vendor}")
+    /manifest:no{lib_flags})
         "#,
-            dir = sysroot_dir,
+            system_version = self.xwin_options.cmake_system_version_line(),
+            include_flags = include_dirs
+                .iter()
+                .map(|dir| format!("\n    -I\"{dir}\""))
+                .collect::<String>(),
+            lib_flags = lib_dirs
+                .iter()
+                .map(|dir| format!("\n    -libpath:\"{dir}\""))
+                .collect::<String>(),
         );
-        fs::write(&toolchain_file, content)?;
+        let content = relocatable_cmake_paths(&content, cache_dir, &cmake_cache_dir);
+        write_file_if_changed(&toolchain_file, content)?;
         Ok(toolchain_file)
     }
 }
@@ -318,4 +614,42 @@ struct GitHubRelease {
 struct GitHubReleaseAsset {
     browser_download_url: String,
     name: String,
+    digest: Option<String>,
+    size: Option<u64>,
+}
+
+/// A resolved windows-msvc-sysroot release asset
+#[derive(Debug)]
+pub(crate) struct MsvcSysrootAsset {
+    pub url: String,
+    pub sha256: Option<String>,
+    pub size: Option<u64>,
+}
+
+/// Checks that a few key files of the MSVC sysroot exist, so that a sysroot left behind
+/// by an interrupted extraction isn't trusted just because of the `DONE` marker
+fn is_msvc_sysroot_complete(msvc_sysroot_dir: &Path, target: &str) -> bool {
+    let include_dir = msvc_sysroot_dir.join("include");
+    let has_windows_h =
+        include_dir.join("windows.h").is_file() || include_dir.join("Windows.h").is_file();
+    let has_target_libs = fs::read_dir(msvc_sysroot_dir.join("lib").join(target))
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+    has_windows_h && has_target_libs
+}
+
+/// Feeds everything read from `inner` to a sha256 hasher
+struct HashReader<'a, R> {
+    inner: R,
+    hasher: &'a mut sha2::Sha256,
+}
+
+impl<R: io::Read> io::Read for HashReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        use sha2::Digest;
+
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
 }
@@ -6,18 +6,25 @@ use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
 use fs_err as fs;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar};
 use path_slash::PathExt;
 use which::which_in;
 use xwin::util::ProgressTarget;
 
 use crate::compiler::common::{
-    adjust_canonicalization, default_build_target_from_config, get_rustflags, http_agent,
-    setup_cmake_env, setup_env_path, setup_llvm_tools, setup_target_compiler_and_linker_env,
+    adjust_canonicalization, c_flags, decorate, default_build_targets, progress_style,
+    relocatable_cmake_paths, resolve_target_compilers, retry_download, setup_cmake_env,
+    setup_debug_symbol_path, setup_env_path, setup_llvm_tools,
+    setup_target_compiler_and_linker_env, setup_target_rustflags, split_targets, use_color,
+    user_rustflags, warn, write_file_if_changed, xwin_arch,
 };
-use crate::options::XWinOptions;
+use crate::lock::{CrtLock, ToolchainLock, LOCKFILE_NAME};
+use crate::options::{XWinExclude, XWinOptions};
+
+/// The license of the Visual Studio packages the MSVC CRT and Windows SDK are downloaded from
 
 #[derive(Debug)]
 pub struct ClangCl<'a> {
@@ -36,48 +43,75 @@ impl<'a> ClangCl<'a> {
         cache_dir: PathBuf,
         cmd: &mut Command,
     ) -> Result<()> {
-        let env_path = setup_env_path(&cache_dir)?;
+        let tools_dir = self
+            .xwin_options
+            .tools_dir(manifest_path, cargo, &cache_dir)?;
+        let env_path = setup_env_path(&tools_dir)?;
 
-        let xwin_cache_dir = cache_dir.join("xwin");
-        fs::create_dir_all(&xwin_cache_dir).context("Failed to create xwin cache dir")?;
-        let xwin_cache_dir = xwin_cache_dir
-            .canonicalize()
-            .context("Failed to canonicalize xwin cache dir")?;
+        let xwin_cache_dir = self.xwin_options.xwin_cache_dir()?;
 
         let workdir = manifest_path
             .and_then(|p| p.parent().map(|x| x.to_path_buf()))
             .or_else(|| env::current_dir().ok())
             .unwrap();
-        let mut targets = cargo.target.clone();
+        let lock = ToolchainLock::load_if_locked(self.xwin_options, &workdir)?;
+        let crt_lock = lock.as_ref().map(ToolchainLock::crt).transpose()?;
+        let mut targets = split_targets(&cargo.target);
         if targets.is_empty() {
-            if let Some(build_target) = default_build_target_from_config(&workdir)? {
-                // if no target is specified, use the default build target
-                // Note that this is required, otherwise it may fail with link errors
-                cmd.arg("--target").arg(&build_target);
-                targets.push(build_target);
+            // if no target is specified, use the default build targets
+            // Note that this is required, otherwise it may fail with link errors
+            targets = default_build_targets(&workdir)?;
+            for target in &targets {
+                cmd.arg("--target").arg(target);
             }
         }
 
+        let color = use_color(cargo.color.as_deref());
         for target in &targets {
             if target.contains("msvc") {
-                self.setup_msvc_crt(xwin_cache_dir.clone())
+                self.setup_msvc_crt(xwin_cache_dir.clone(), color, crt_lock)
                     .context("Failed to setup MSVC CRT")?;
                 let env_target = target.to_lowercase().replace('-', "_");
 
-                setup_clang_cl_symlink(&env_path, &cache_dir)
+                setup_clang_cl_symlink(&env_path, &tools_dir)
                     .context("Failed to setup clang-cl symlink")?;
-                setup_llvm_tools(&env_path, &cache_dir).context("Failed to setup LLVM tools")?;
-                setup_target_compiler_and_linker_env(cmd, &env_target, "clang-cl");
+                let llvm_tools = setup_llvm_tools(
+                    &env_path,
+                    &cache_dir,
+                    self.xwin_options.no_symlink_tools,
+                    self.xwin_options.absolute_linker,
+                )
+                .context("Failed to setup LLVM tools")?;
+                let (cc, cxx) = resolve_target_compilers(
+                    self.xwin_options.cc.as_deref(),
+                    self.xwin_options.cxx.as_deref(),
+                    "clang-cl",
+                    "clang-cl",
+                    &env_path,
+                )?;
+                setup_target_compiler_and_linker_env(cmd, &env_target, cc, cxx, &llvm_tools);
 
                 let user_set_cl_flags = env::var("CL_FLAGS").unwrap_or_default();
                 let user_set_c_flags = env::var("CFLAGS").unwrap_or_default();
                 let user_set_cxx_flags = env::var("CXXFLAGS").unwrap_or_default();
 
                 let xwin_dir = adjust_canonicalization(xwin_cache_dir.to_slash_lossy().to_string());
-                let cl_flags = format!(
-                    "--target={target} -Wno-unused-command-line-argument -fuse-ld=lld-link /imsvc{dir}/crt/include /imsvc{dir}/sdk/include/ucrt /imsvc{dir}/sdk/include/um /imsvc{dir}/sdk/include/shared {user_set_cl_flags}",
-                    dir = xwin_dir,
+                let include_dirs = self.xwin_options.xwin_include_dirs(&xwin_dir)?;
+                let imsvc_flags: Vec<_> = include_dirs
+                    .iter()
+                    .map(|dir| format!("/imsvc{dir}"))
+                    .collect();
+                let include_flags: Vec<_> =
+                    include_dirs.iter().map(|dir| format!("-I{dir}")).collect();
+                let include_flags = c_flags(cmd, &include_flags);
+                let mut cl_flags = format!(
+                    "--target={target} -Wno-unused-command-line-argument -fuse-ld=lld-link {abi_flags}{imsvc_flags} {user_set_cl_flags}",
+                    abi_flags = self.xwin_options.abi_c_flags(true),
+                    imsvc_flags = c_flags(cmd, &imsvc_flags),
                 );
+                if let Some(guard) = self.excluded_headers_guard(&xwin_cache_dir)? {
+                    cl_flags = format!("{} {cl_flags}", c_flags(cmd, &[format!("/FI{guard}")]));
+                }
                 cmd.env("CL_FLAGS", &cl_flags);
                 cmd.env(
                     format!("CFLAGS_{env_target}"),
@@ -90,64 +124,104 @@ impl<'a> ClangCl<'a> {
 
                 cmd.env(
                     format!("BINDGEN_EXTRA_CLANG_ARGS_{env_target}"),
-                    format!(
-                        "-I{dir}/crt/include -I{dir}/sdk/include/ucrt -I{dir}/sdk/include/um -I{dir}/sdk/include/shared",
-                        dir = xwin_dir
-                    )
+                    &include_flags,
                 );
+                cmd.env("RCFLAGS", &include_flags);
 
-                cmd.env(
-                    "RCFLAGS",
+                let xwin_arch = xwin_arch(target)?;
+                let user_rustflags = user_rustflags(&workdir, target, &cargo.config)?;
+                let runtime_lib_dirs =
+                    self.xwin_options
+                        .clang_runtime_lib_dirs(target, &env_path, &user_rustflags);
+
+                let mut rustflags = vec![
+                    "-C".to_string(),
+                    "linker-flavor=lld-link".to_string(),
                     format!(
-                        "-I{dir}/crt/include -I{dir}/sdk/include/ucrt -I{dir}/sdk/include/um -I{dir}/sdk/include/shared",
-                        dir = xwin_dir
-                    )
+                        "-Lnative={dir}/{crt_lib}",
+                        dir = xwin_dir,
+                        crt_lib = self.xwin_options.crt_lib_dir(xwin_arch)
+                    ),
+                    format!(
+                        "-Lnative={dir}/sdk/lib/um/{arch}",
+                        dir = xwin_dir,
+                        arch = xwin_arch
+                    ),
+                    format!(
+                        "-Lnative={dir}/sdk/lib/ucrt/{arch}",
+                        dir = xwin_dir,
+                        arch = xwin_arch
+                    ),
+                ];
+                rustflags.extend(
+                    self.xwin_options
+                        .extra_lib_dirs()?
+                        .into_iter()
+                        .chain(runtime_lib_dirs.iter().cloned())
+                        .map(|dir| format!("-Lnative={dir}")),
                 );
-
-                let target_arch = target
-                    .split_once('-')
-                    .map(|(x, _)| x)
-                    .context("invalid target triple")?;
-                let xwin_arch = match target_arch {
-                    "i586" | "i686" => "x86",
-                    _ => target_arch,
-                };
-
-                let mut rustflags = get_rustflags(&workdir, target)?.unwrap_or_default();
-                rustflags
-                    .flags
-                    .extend(["-C".to_string(), "linker-flavor=lld-link".to_string()]);
-                rustflags.push(format!(
-                    "-Lnative={dir}/crt/lib/{arch}",
-                    dir = xwin_dir,
-                    arch = xwin_arch
-                ));
-                rustflags.push(format!(
-                    "-Lnative={dir}/sdk/lib/um/{arch}",
-                    dir = xwin_dir,
-                    arch = xwin_arch
-                ));
-                rustflags.push(format!(
-                    "-Lnative={dir}/sdk/lib/ucrt/{arch}",
-                    dir = xwin_dir,
-                    arch = xwin_arch
-                ));
-                cmd.env("CARGO_ENCODED_RUSTFLAGS", rustflags.encode()?);
+                rustflags.extend(self.xwin_options.pgo_rustflags()?);
+                rustflags.extend(self.xwin_options.link_arg_rustflags(&user_rustflags)?);
+                rustflags.extend(self.xwin_options.abi_rustflags());
+                rustflags.extend(self.xwin_options.extra_rustflags());
+                setup_target_rustflags(cmd, &workdir, target, &rustflags, &cargo.config, color)?;
+                if self.xwin_options.xwin_include_debug_symbols {
+                    setup_debug_symbol_path(cmd, &xwin_cache_dir, xwin_arch);
+                }
                 cmd.env("PATH", &env_path);
 
                 // CMake support
-                let cmake_toolchain = self
-                    .setup_cmake_toolchain(target, &xwin_cache_dir)
-                    .with_context(|| format!("Failed to setup CMake toolchain for {}", target))?;
-                setup_cmake_env(cmd, target, cmake_toolchain);
+                if !self.xwin_options.no_cmake {
+                    let cmake_toolchain = self
+                        .setup_cmake_toolchain(target, &xwin_cache_dir, cc, cxx)
+                        .with_context(|| {
+                            format!("Failed to setup CMake toolchain for {}", target)
+                        })?;
+                    setup_cmake_env(
+                        cmd,
+                        target,
+                        cmake_toolchain,
+                        &self.xwin_options.cmake_generator,
+                        &env_path,
+                        &workdir,
+                        color,
+                    )?;
+                }
             }
         }
         Ok(())
     }
 
+    /// Writes a header that fails the compilation with a clear message when the CRT and SDK
+    /// headers are left out with `--xwin-exclude headers`, returns its path to force include.
+    pub(crate) fn excluded_headers_guard(&self, xwin_cache_dir: &Path) -> Result<Option<String>> {
+        if !self
+            .xwin_options
+            .xwin_exclude
+            .contains(&XWinExclude::Headers)
+        {
+            return Ok(None);
+        }
+        let guard = xwin_cache_dir.join("excluded-headers.h");
+        write_file_if_changed(
+            &guard,
+            "#error \"The MSVC CRT and Windows SDK headers are excluded with `--xwin-exclude headers`, \
+             remove it to compile C/C++ code\"\n",
+        )?;
+        Ok(Some(adjust_canonicalization(
+            guard.to_slash_lossy().to_string(),
+        )))
+    }
+
     /// Downloads and extracts the specified MSVC CRT components into the specified `cache_dir`.
-    fn setup_msvc_crt(&self, cache_dir: PathBuf) -> Result<()> {
+    pub fn setup_msvc_crt(
+        &self,
+        cache_dir: PathBuf,
+        color: bool,
+        lock: Option<&CrtLock>,
+    ) -> Result<()> {
         let done_mark_file = cache_dir.join("DONE");
+        let lock_file = cache_dir.join(LOCKFILE_NAME);
         let xwin_arches: HashSet<_> = self
             .xwin_options
             .xwin_arch
@@ -160,148 +234,424 @@ impl<'a> ClangCl<'a> {
                 downloaded_arches.insert(arch.to_string());
             }
         }
-        if xwin_arches.difference(&downloaded_arches).next().is_none() {
-            return Ok(());
+        // payloads left out by an earlier `--xwin-exclude` but needed now
+        let excluded_file = cache_dir.join("EXCLUDED");
+        let missing_payloads = fs::read_to_string(&excluded_file)
+            .unwrap_or_default()
+            .split_whitespace()
+            .filter_map(|name| XWinExclude::from_str(name, false).ok())
+            .any(|exclude| !self.xwin_options.xwin_exclude.contains(&exclude));
+        // xwin only splats the requested variants, caches from before `VARIANTS` was written are desktop only
+        let variants_file = cache_dir.join("VARIANTS");
+        let xwin_variants: HashSet<_> = self
+            .xwin_options
+            .xwin_variant
+            .iter()
+            .map(|x| x.as_str().to_string())
+            .collect();
+        let splatted_variants: HashSet<_> = fs::read_to_string(&variants_file)
+            .unwrap_or_else(|_| "desktop".to_string())
+            .split_whitespace()
+            .map(|x| x.to_string())
+            .collect();
+        // the splat map decides the layout of the whole cache, so a changed map is splatted again
+        let splat_map_file = cache_dir.join("SPLAT_MAP");
+        let splat_map = match &self.xwin_options.xwin_splat_map {
+            Some(path) => {
+                if !path.is_file() {
+                    bail!("--xwin-splat-map {} doesn't exist", path.display());
+                }
+                fs::read_to_string(path)?
+            }
+            None => String::new(),
+        };
+        let splatted_map = fs::read_to_string(&splat_map_file).unwrap_or_default();
+        if done_mark_file.is_file()
+            && (splatted_variants != xwin_variants || splatted_map != splat_map)
+        {
+            self.xwin_options
+                .ensure_cache_unlocked("MSVC CRT", &cache_dir)?;
+            warn(
+                color,
+                "cached MSVC CRT was splatted for other variants or another splat map, splatting it again",
+            );
+            for dir in ["crt", "sdk"] {
+                let dir = cache_dir.join(dir);
+                if dir.exists() {
+                    fs::remove_dir_all(dir)?;
+                }
+            }
+            downloaded_arches.clear();
+        }
+        if xwin_arches.difference(&downloaded_arches).next().is_none() && !missing_payloads {
+            let Some(lock) = lock else {
+                return self.check_cached_sdk_version(&lock_file, color);
+            };
+            let cached_lock = fs::read_to_string(&lock_file)
+                .ok()
+                .and_then(|content| serde_json::from_str::<CrtLock>(&content).ok());
+            if cached_lock.as_ref() == Some(lock) {
+                return Ok(());
+            }
+            self.xwin_options
+                .ensure_cache_unlocked("MSVC CRT", &cache_dir)?;
+            warn(
+                color,
+                &format!("cached MSVC CRT doesn't match {LOCKFILE_NAME}, downloading it again"),
+            );
+            for dir in ["crt", "sdk"] {
+                let dir = cache_dir.join(dir);
+                if dir.exists() {
+                    fs::remove_dir_all(dir)?;
+                }
+            }
+            downloaded_arches.clear();
         }
 
-        let draw_target = ProgressTarget::Stdout;
+        self.xwin_options
+            .ensure_cache_unlocked("MSVC CRT", &cache_dir)?;
+        self.xwin_options.ensure_license_accepted()?;
+        let draw_target = self.xwin_options.progress_target();
 
-        let agent = http_agent()?;
-        let xwin_dir = adjust_canonicalization(cache_dir.to_slash_lossy().to_string());
-        // timeout defaults to 60s
-        let ctx = xwin::Ctx::with_dir(xwin::PathBuf::from(xwin_dir), draw_target, agent)?;
+        let ctx = self.xwin_ctx(&cache_dir, draw_target, color)?;
         let ctx = std::sync::Arc::new(ctx);
-        let pkg_manifest = self.load_manifest(&ctx, draw_target)?;
-
+        let (pkg_manifest, mut pruned) = self.resolve_payloads(&ctx, draw_target, color, lock)?;
+        let crt_lock = CrtLock::new(&self.xwin_options.xwin_version, &pruned);
+        self.exclude_payloads(&mut pruned);
+        let excludes = &self.xwin_options.xwin_exclude;
+        let (_, variants) = self.arches_and_variants();
+        // Only splat the newly requested architectures on top of a cache of the same versions
+        let cached_versions = fs::read_to_string(&lock_file)
+            .ok()
+            .and_then(|content| serde_json::from_str::<CrtLock>(&content).ok())
+            .map(|lock| (lock.crt_version, lock.sdk_version));
+        let incremental = !missing_payloads
+            && cached_versions == Some((pruned.crt_version.clone(), pruned.sdk_version.clone()));
+        if !incremental {
+            downloaded_arches.clear();
+        }
+        pruned.payloads.retain(|payload| match payload.target_arch {
+            Some(arch) => !downloaded_arches.contains(arch.as_str()),
+            // Architecture independent headers were splatted with the cached architectures,
+            // the UCRT payload holds the libraries of all architectures
+            None => {
+                downloaded_arches.is_empty()
+                    || !matches!(
+                        payload.kind,
+                        xwin::PayloadKind::CrtHeaders
+                            | xwin::PayloadKind::AtlHeaders
+                            | xwin::PayloadKind::SdkHeaders
+                    )
+            }
+        });
+        self.check_download_size(&pruned.payloads)?;
         let arches = self
             .xwin_options
             .xwin_arch
             .iter()
+            .filter(|arch| !downloaded_arches.contains(arch.as_str()))
             .fold(0, |acc, arch| acc | *arch as u32);
-        let variants = self
-            .xwin_options
-            .xwin_variant
-            .iter()
-            .fold(0, |acc, var| acc | *var as u32);
-        let pruned = xwin::prune_pkg_list(&pkg_manifest, arches, variants, false, None, None)?;
-        let op = xwin::Ops::Splat(xwin::SplatConfig {
-            include_debug_libs: self.xwin_options.xwin_include_debug_libs,
-            include_debug_symbols: self.xwin_options.xwin_include_debug_symbols,
-            enable_symlinks: !cfg!(target_os = "macos"),
-            preserve_ms_arch_notation: false,
-            use_winsysroot_style: false,
-            copy: false,
-            output: cache_dir.clone().try_into()?,
-            map: None,
-        });
+        let op = || -> Result<_> {
+            Ok(xwin::Ops::Splat(xwin::SplatConfig {
+                include_debug_libs: self.xwin_options.xwin_include_debug_libs,
+                include_debug_symbols: self.xwin_options.xwin_include_debug_symbols,
+                enable_symlinks: !cfg!(target_os = "macos"),
+                preserve_ms_arch_notation: false,
+                use_winsysroot_style: false,
+                copy: false,
+                output: cache_dir.clone().try_into()?,
+                map: self
+                    .xwin_options
+                    .xwin_splat_map
+                    .as_ref()
+                    .map(|path| path.clone().try_into())
+                    .transpose()?,
+            }))
+        };
         let pkgs = pkg_manifest.packages;
 
         let mp = MultiProgress::with_draw_target(draw_target.into());
-        let work_items: Vec<_> = pruned.payloads
-        .into_iter()
-        .map(|pay| {
-            let prefix = match pay.kind {
-                xwin::PayloadKind::CrtHeaders => "CRT.headers".to_owned(),
-                xwin::PayloadKind::AtlHeaders => "ATL.headers".to_owned(),
-                xwin::PayloadKind::CrtLibs => {
-                    format!(
-                        "CRT.libs.{}.{}",
-                        pay.target_arch.map(|ta| ta.as_str()).unwrap_or("all"),
-                        pay.variant.map(|v| v.as_str()).unwrap_or("none")
-                    )
-                }
-                xwin::PayloadKind::AtlLibs => {
-                    format!(
-                        "ATL.libs.{}",
-                        pay.target_arch.map(|ta| ta.as_str()).unwrap_or("all"),
-                    )
-                }
-                xwin::PayloadKind::SdkHeaders => {
-                    format!(
-                        "SDK.headers.{}.{}",
-                        pay.target_arch.map(|v| v.as_str()).unwrap_or("all"),
-                        pay.variant.map(|v| v.as_str()).unwrap_or("none")
-                    )
-                }
-                xwin::PayloadKind::SdkLibs => {
-                    format!(
-                        "SDK.libs.{}",
-                        pay.target_arch.map(|ta| ta.as_str()).unwrap_or("all")
-                    )
-                }
-                xwin::PayloadKind::SdkStoreLibs => "SDK.libs.store.all".to_owned(),
-                xwin::PayloadKind::Ucrt => "SDK.ucrt.all".to_owned(),
-            };
-
-            let pb = mp.add(
-                ProgressBar::with_draw_target(Some(0), draw_target.into()).with_prefix(prefix).with_style(
-                    ProgressStyle::default_bar()
-                        .template("{spinner:.green} {prefix:.bold} [{elapsed}] {wide_bar:.green} {bytes}/{total_bytes} {msg}").unwrap()
-                        .progress_chars("=> "),
-                ),
-            );
-            xwin::WorkItem {
-                payload: std::sync::Arc::new(pay),
-                progress: pb,
-            }
-        })
-        .collect();
+        let work_items = || -> Vec<_> {
+            pruned
+                .payloads
+                .iter()
+                .cloned()
+                .map(|pay| {
+                    let prefix = match pay.kind {
+                        xwin::PayloadKind::CrtHeaders => "CRT.headers".to_owned(),
+                        xwin::PayloadKind::AtlHeaders => "ATL.headers".to_owned(),
+                        xwin::PayloadKind::CrtLibs => {
+                            format!(
+                                "CRT.libs.{}.{}",
+                                pay.target_arch.map(|ta| ta.as_str()).unwrap_or("all"),
+                                pay.variant.map(|v| v.as_str()).unwrap_or("none")
+                            )
+                        }
+                        xwin::PayloadKind::AtlLibs => {
+                            format!(
+                                "ATL.libs.{}",
+                                pay.target_arch.map(|ta| ta.as_str()).unwrap_or("all"),
+                            )
+                        }
+                        xwin::PayloadKind::SdkHeaders => {
+                            format!(
+                                "SDK.headers.{}.{}",
+                                pay.target_arch.map(|v| v.as_str()).unwrap_or("all"),
+                                pay.variant.map(|v| v.as_str()).unwrap_or("none")
+                            )
+                        }
+                        xwin::PayloadKind::SdkLibs => {
+                            format!(
+                                "SDK.libs.{}",
+                                pay.target_arch.map(|ta| ta.as_str()).unwrap_or("all")
+                            )
+                        }
+                        xwin::PayloadKind::SdkStoreLibs => "SDK.libs.store.all".to_owned(),
+                        xwin::PayloadKind::Ucrt => "SDK.ucrt.all".to_owned(),
+                    };
+
+                    let pb = mp.add(
+                        ProgressBar::with_draw_target(Some(0), draw_target.into())
+                            .with_prefix(prefix)
+                            .with_style(progress_style(color)),
+                    );
+                    xwin::WorkItem {
+                        payload: std::sync::Arc::new(pay),
+                        progress: pb,
+                    }
+                })
+                .collect()
+        };
 
         mp.set_move_cursor(true);
         if mp.is_hidden() {
-            eprintln!("⏬ Downloading MSVC CRT...");
+            eprintln!("{}", decorate(color, "⏬", "Downloading MSVC CRT..."));
         }
         let start_time = Instant::now();
-        ctx.execute(
-            pkgs,
-            work_items,
-            pruned.crt_version,
-            pruned.sdk_version,
-            arches,
-            variants,
-            op,
-        )?;
+        // Downloaded payloads are kept in the `dl` directory and only the failed ones are fetched again
+        let execute = || {
+            retry_download("MSVC CRT", color, || {
+                ctx.clone().execute(
+                    pkgs.clone(),
+                    work_items(),
+                    pruned.crt_version.clone(),
+                    pruned.sdk_version.clone(),
+                    arches,
+                    variants,
+                    op()?,
+                )?;
+                Ok(())
+            })
+        };
+        match self.xwin_options.download_jobs {
+            // xwin downloads and unpacks the payloads in the current rayon thread pool
+            Some(jobs) => rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .context("Failed to create download thread pool")?
+                .install(execute)?,
+            None => execute()?,
+        }
 
-        let downloaded_arches: Vec<_> = self
+        downloaded_arches.extend(xwin_arches);
+        let mut downloaded_arches: Vec<_> = downloaded_arches.into_iter().collect();
+        downloaded_arches.sort();
+        fs::write(done_mark_file, downloaded_arches.join(" "))?;
+        let splatted_variants: Vec<_> = self
             .xwin_options
-            .xwin_arch
+            .xwin_variant
             .iter()
-            .map(|x| x.as_str().to_string())
+            .map(|x| x.as_str())
             .collect();
-        fs::write(done_mark_file, downloaded_arches.join(" "))?;
+        fs::write(variants_file, splatted_variants.join(" "))?;
+        fs::write(splat_map_file, splat_map)?;
+        fs::write(lock_file, serde_json::to_string_pretty(&crt_lock)?)?;
+        let excluded: Vec<_> = excludes
+            .iter()
+            .filter_map(|exclude| exclude.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        fs::write(excluded_file, excluded.join(" "))?;
 
         let dl = cache_dir.join("dl");
-        if dl.exists() {
-            let _ = fs::remove_dir_all(dl);
-        }
         let unpack = cache_dir.join("unpack");
-        if unpack.exists() {
-            let _ = fs::remove_dir_all(unpack);
+        if self.xwin_options.keep_temp {
+            eprintln!(
+                "Kept the downloaded payloads in {} and the unpacked files in {}",
+                dl.display(),
+                unpack.display()
+            );
+        } else {
+            if dl.exists() {
+                let _ = fs::remove_dir_all(dl);
+            }
+            if unpack.exists() {
+                let _ = fs::remove_dir_all(unpack);
+            }
         }
         if mp.is_hidden() {
             // Display elapsed time in human-readable format to seconds only
             let elapsed =
                 humantime::format_duration(Duration::from_secs(start_time.elapsed().as_secs()));
-            eprintln!("✅ Downloaded MSVC CRT in {elapsed}.");
+            eprintln!(
+                "{}",
+                decorate(color, "✅", &format!("Downloaded MSVC CRT in {elapsed}."))
+            );
         }
         Ok(())
     }
 
+    /// Warns, or fails with `--strict`, when the cached Windows SDK isn't the `--xwin-sdk-version`
+    ///
+    /// The cache isn't versioned, so a cache splatted with another SDK version would otherwise
+    /// be used silently.
+    fn check_cached_sdk_version(&self, lock_file: &Path, color: bool) -> Result<()> {
+        let Some(requested) = self.xwin_options.xwin_sdk_version.as_deref() else {
+            return Ok(());
+        };
+        let cached = fs::read_to_string(lock_file)
+            .ok()
+            .and_then(|content| serde_json::from_str::<CrtLock>(&content).ok())
+            .map(|lock| lock.sdk_version);
+        if cached.as_deref() == Some(requested) {
+            return Ok(());
+        }
+        let cached = cached.as_deref().unwrap_or("an unknown version");
+        let message = format!(
+            "Windows SDK {cached} is cached but {requested} was requested, \
+             remove {} to download it again",
+            lock_file.parent().unwrap_or(lock_file).display()
+        );
+        if self.xwin_options.strict {
+            bail!("{message}");
+        }
+        warn(color, &message);
+        Ok(())
+    }
+
+    /// Resolves the CRT and SDK versions and the payload checksums for `cargo xwin lock`
+    pub(crate) fn resolve_crt_lock(&self, cache_dir: PathBuf, color: bool) -> Result<CrtLock> {
+        let draw_target = self.xwin_options.progress_target();
+        let ctx = self.xwin_ctx(&cache_dir, draw_target, color)?;
+        let (_, pruned) = self.resolve_payloads(&ctx, draw_target, color, None)?;
+        Ok(CrtLock::new(&self.xwin_options.xwin_version, &pruned))
+    }
+
+    /// Resolves the CRT and SDK payloads that `setup_msvc_crt` would download, without downloading them
+    pub(crate) fn resolve_download(
+        &self,
+        cache_dir: PathBuf,
+        color: bool,
+    ) -> Result<xwin::PrunedPackageList> {
+        let draw_target = self.xwin_options.progress_target();
+        let ctx = self.xwin_ctx(&cache_dir, draw_target, color)?;
+        let (_, mut pruned) = self.resolve_payloads(&ctx, draw_target, color, None)?;
+        self.exclude_payloads(&mut pruned);
+        Ok(pruned)
+    }
+
+    /// Fails when the payloads to download exceed `--max-download-size`
+    fn check_download_size(&self, payloads: &[xwin::Payload]) -> Result<()> {
+        let Some(max_size) = self.xwin_options.max_download_size else {
+            return Ok(());
+        };
+        let size: u64 = payloads.iter().map(|payload| payload.size).sum();
+        if size <= max_size {
+            return Ok(());
+        }
+        let mut payloads: Vec<_> = payloads.iter().collect();
+        payloads.sort_by_key(|payload| std::cmp::Reverse(payload.size));
+        let selected: Vec<_> = payloads
+            .iter()
+            .map(|payload| format!("  {} ({})", payload.filename, HumanBytes(payload.size)))
+            .collect();
+        bail!(
+            "MSVC CRT and Windows SDK download of {} exceeds --max-download-size {}, \
+             narrow it down with `--xwin-arch`, `--xwin-variant` or `--xwin-exclude`. Selected payloads:\n{}",
+            HumanBytes(size),
+            HumanBytes(max_size),
+            selected.join("\n")
+        );
+    }
+
+    /// Drops the payloads left out with `--xwin-exclude`
+    fn exclude_payloads(&self, pruned: &mut xwin::PrunedPackageList) {
+        let excludes = &self.xwin_options.xwin_exclude;
+        pruned
+            .payloads
+            .retain(|payload| !excludes.iter().any(|exclude| exclude.matches(payload.kind)));
+    }
+
+    fn xwin_ctx(
+        &self,
+        cache_dir: &Path,
+        draw_target: ProgressTarget,
+        color: bool,
+    ) -> Result<xwin::Ctx> {
+        let agent = self.xwin_options.http_agent(color)?;
+        let xwin_dir = adjust_canonicalization(cache_dir.to_slash_lossy().to_string());
+        xwin::Ctx::with_dir(xwin::PathBuf::from(xwin_dir), draw_target, agent)
+    }
+
+    fn arches_and_variants(&self) -> (u32, u32) {
+        let arches = self
+            .xwin_options
+            .xwin_arch
+            .iter()
+            .fold(0, |acc, arch| acc | *arch as u32);
+        let variants = self
+            .xwin_options
+            .xwin_variant
+            .iter()
+            .fold(0, |acc, var| acc | *var as u32);
+        (arches, variants)
+    }
+
+    /// Selects the payloads to download, pinned to the versions of `lock` if given
+    fn resolve_payloads(
+        &self,
+        ctx: &xwin::Ctx,
+        draw_target: ProgressTarget,
+        color: bool,
+        lock: Option<&CrtLock>,
+    ) -> Result<(xwin::manifest::PackageManifest, xwin::PrunedPackageList)> {
+        let pkg_manifest = retry_download("Visual Studio manifest", color, || {
+            self.load_manifest(ctx, draw_target, color)
+        })?;
+        let (arches, variants) = self.arches_and_variants();
+        let pruned = xwin::prune_pkg_list(
+            &pkg_manifest,
+            arches,
+            variants,
+            false,
+            lock.map(|lock| lock.sdk_version.clone())
+                .or_else(|| self.xwin_options.xwin_sdk_version.clone()),
+            lock.map(|lock| lock.crt_version.clone()),
+        )?;
+        if let Some(lock) = lock {
+            let resolved = CrtLock::new(&self.xwin_options.xwin_version, &pruned);
+            if &resolved != lock {
+                bail!(
+                    "MSVC CRT {} and Windows SDK {} payloads don't match {LOCKFILE_NAME}, \
+                     run `cargo xwin lock` with the same `--xwin-version`, `--xwin-arch` and `--xwin-variant` options to update it",
+                    resolved.crt_version,
+                    resolved.sdk_version
+                );
+            }
+        }
+        Ok((pkg_manifest, pruned))
+    }
+
     fn load_manifest(
         &self,
         ctx: &xwin::Ctx,
         dt: ProgressTarget,
+        color: bool,
     ) -> Result<xwin::manifest::PackageManifest> {
-        let manifest_pb = ProgressBar::with_draw_target(Some(0), dt.into())
-            .with_style(
-            ProgressStyle::default_bar()
-                .template(
-                    "{spinner:.green} {prefix:.bold} [{elapsed}] {wide_bar:.green} {bytes}/{total_bytes} {msg}",
-                )?
-                .progress_chars("=> "),
-        );
+        let manifest_pb =
+            ProgressBar::with_draw_target(Some(0), dt.into()).with_style(progress_style(color));
         manifest_pb.set_prefix("Manifest");
-        manifest_pb.set_message("📥 downloading");
+        manifest_pb.set_message(decorate(color, "📥", "downloading"));
 
         let manifest = xwin::manifest::get_manifest(
             ctx,
@@ -311,20 +661,23 @@ impl<'a> ClangCl<'a> {
         )?;
         let pkg_manifest =
             xwin::manifest::get_package_manifest(ctx, &manifest, manifest_pb.clone())?;
-        manifest_pb.finish_with_message("📥 downloaded");
+        manifest_pb.finish_with_message(decorate(color, "📥", "downloaded"));
         Ok(pkg_manifest)
     }
 
-    fn setup_cmake_toolchain(&self, target: &str, xwin_cache_dir: &Path) -> Result<PathBuf> {
-        let cmake_cache_dir = xwin_cache_dir
-            .parent()
-            .unwrap()
-            .join("cmake")
-            .join("clang-cl");
-        fs::create_dir_all(&cmake_cache_dir)?;
+    fn setup_cmake_toolchain(
+        &self,
+        target: &str,
+        xwin_cache_dir: &Path,
+        cc: &str,
+        cxx: &str,
+    ) -> Result<PathBuf> {
+        let cmake_cache_dir = self
+            .xwin_options
+            .cmake_dir(xwin_cache_dir.parent().unwrap(), "clang-cl")?;
 
         let override_file = cmake_cache_dir.join("override.cmake");
-        fs::write(override_file, include_bytes!("override.cmake"))?;
+        write_file_if_changed(&override_file, include_bytes!("override.cmake"))?;
 
         let toolchain_file = cmake_cache_dir.join(format!("{}-toolchain.cmake", target));
         let target_arch = target
@@ -338,18 +691,15 @@ impl<'a> ClangCl<'a> {
             "arm64ec" => "ARM64EC",
             _ => target_arch,
         };
-        let xwin_arch = match target_arch {
-            "i586" | "i686" => "x86",
-            _ => target_arch,
-        };
+        let xwin_arch = xwin_arch(target)?;
 
         let content = format!(
             r#"
 set(CMAKE_SYSTEM_NAME Windows)
-set(CMAKE_SYSTEM_PROCESSOR {processor})
+set(CMAKE_SYSTEM_PROCESSOR {processor}){system_version}
 
-set(CMAKE_C_COMPILER clang-cl CACHE FILEPATH "")
-set(CMAKE_CXX_COMPILER clang-cl CACHE FILEPATH "")
+set(CMAKE_C_COMPILER {cc} CACHE FILEPATH "")
+set(CMAKE_CXX_COMPILER {cxx} CACHE FILEPATH "")
 set(CMAKE_AR llvm-lib)
 set(CMAKE_LINKER lld-link CACHE FILEPATH "")
 
@@ -357,18 +707,14 @@ set(COMPILE_FLAGS
     --target={target}
     -Wno-unused-command-line-argument
     -fuse-ld=lld-link
-
-    /imsvc{xwin_dir}/crt/include
-    /imsvc{xwin_dir}/sdk/include/ucrt
-    /imsvc{xwin_dir}/sdk/include/um
-    /imsvc{xwin_dir}/sdk/include/shared)
+{imsvc_flags})
 
 set(LINK_FLAGS
     /manifest:no
 
-    -libpath:"{xwin_dir}/crt/lib/{xwin_arch}"
+    -libpath:"{xwin_dir}/{crt_lib}"
     -libpath:"{xwin_dir}/sdk/lib/um/{xwin_arch}"
-    -libpath:"{xwin_dir}/sdk/lib/ucrt/{xwin_arch}")
+    -libpath:"{xwin_dir}/sdk/lib/ucrt/{xwin_arch}"{extra_lib_flags})
 
 string(REPLACE ";" " " COMPILE_FLAGS "${{COMPILE_FLAGS}}")
 
@@ -400,12 +746,32 @@ set(CMAKE_TRY_COMPILE_CONFIGURATION Release)
 # Allow clang-cl to work with macOS paths.
 set(CMAKE_USER_MAKE_RULES_OVERRIDE "${{CMAKE_CURRENT_LIST_DIR}}/override.cmake")
         "#,
+            system_version = self.xwin_options.cmake_system_version_line(),
             target = target,
             processor = processor,
             xwin_dir = adjust_canonicalization(xwin_cache_dir.to_slash_lossy().to_string()),
             xwin_arch = xwin_arch,
+            imsvc_flags = self
+                .xwin_options
+                .xwin_include_dirs(&adjust_canonicalization(
+                    xwin_cache_dir.to_slash_lossy().to_string()
+                ))?
+                .iter()
+                .map(|dir| format!("\n    /imsvc\"{dir}\""))
+                .collect::<String>(),
+            crt_lib = self.xwin_options.crt_lib_dir(xwin_arch),
+            extra_lib_flags = self
+                .xwin_options
+                .extra_lib_dirs()?
+                .iter()
+                .map(|dir| format!("\n    -libpath:\"{dir}\""))
+                .collect::<String>(),
+            cc = cc,
+            cxx = cxx,
         );
-        fs::write(&toolchain_file, content)?;
+        let content =
+            relocatable_cmake_paths(&content, xwin_cache_dir.parent().unwrap(), &cmake_cache_dir);
+        write_file_if_changed(&toolchain_file, content)?;
         Ok(toolchain_file)
     }
 }
@@ -1,9 +1,16 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use fs_err as fs;
+use indicatif::ProgressStyle;
+use path_slash::PathExt;
+use serde::Deserialize;
 use std::env;
 use std::ffi::{OsStr, OsString};
+use std::io::{self, IsTerminal};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::{Once, OnceLock};
+use std::time::Duration;
 use which::which_in;
 
 /// Sets up the environment path by adding necessary directories to the existing `PATH`.
@@ -34,6 +41,14 @@ pub fn setup_env_path(cache_dir: &Path) -> Result<OsString> {
     Ok(env::join_paths(env_paths)?)
 }
 
+/// The linker and archiver to use for the target
+pub struct LlvmTools {
+    /// `CARGO_TARGET_<triple>_LINKER`
+    pub linker: String,
+    /// `TARGET_AR` and `AR_<triple>`
+    pub ar: String,
+}
+
 /// Sets up symlinks for LLVM tools in the provided environment path and cache directory.
 ///
 /// This function creates symlinks for the following tools:
@@ -42,11 +57,42 @@ pub fn setup_env_path(cache_dir: &Path) -> Result<OsString> {
 /// - `llvm-ar` to `llvm-dlltool`
 ///
 /// These symlinks are established if they do not already exist in the specified environment path.
-pub fn setup_llvm_tools(env_path: &OsStr, cache_dir: &Path) -> Result<()> {
-    symlink_llvm_tool("rust-lld", "lld-link", env_path, cache_dir)?;
-    symlink_llvm_tool("llvm-ar", "llvm-lib", env_path, cache_dir)?;
-    symlink_llvm_tool("llvm-ar", "llvm-dlltool", env_path, cache_dir)?;
-    Ok(())
+/// With `no_symlink`, nothing is written to `cache_dir` and tools missing from the environment path
+/// are referenced by the absolute path of the rustc provided ones instead, rustc passes
+/// `-flavor link` to `rust-lld` for the `lld-link` linker flavor.
+pub fn setup_llvm_tools(
+    env_path: &OsStr,
+    cache_dir: &Path,
+    no_symlink: bool,
+    absolute_linker: bool,
+) -> Result<LlvmTools> {
+    if !no_symlink {
+        symlink_llvm_tool("rust-lld", "lld-link", env_path, cache_dir)?;
+        symlink_llvm_tool("llvm-ar", "llvm-lib", env_path, cache_dir)?;
+        symlink_llvm_tool("llvm-ar", "llvm-dlltool", env_path, cache_dir)?;
+        return Ok(LlvmTools {
+            linker: resolve_linker(absolute_linker, env_path)?,
+            ar: "llvm-lib".to_string(),
+        });
+    }
+    let cwd = env::current_dir()?;
+    let bin_dir = rustc_target_bin_dir()?;
+    let resolve = |name: &str, rust_tool: &str| {
+        let rust_tool = bin_dir.join(rust_tool);
+        if which_in(name, Some(env_path), &cwd).is_err() && rust_tool.is_file() {
+            rust_tool.to_string_lossy().into_owned()
+        } else {
+            name.to_string()
+        }
+    };
+    let linker = match resolve("lld-link", "rust-lld") {
+        linker if linker == "lld-link" => resolve_linker(absolute_linker, env_path)?,
+        linker => linker,
+    };
+    Ok(LlvmTools {
+        linker,
+        ar: resolve("llvm-lib", "llvm-ar"),
+    })
 }
 
 /// Configures the environment variables for the target compiler and linker.
@@ -54,38 +100,288 @@ pub fn setup_llvm_tools(env_path: &OsStr, cache_dir: &Path) -> Result<()> {
 /// This function sets up environment variables for the specified target compiler and linker,
 /// allowing the build system to correctly use the desired tools for compilation and linking.
 /// It sets up the following environment variables:
-/// - `TARGET_CC` and `TARGET_CXX` with the provided compiler.
-/// - `CC_<env_target>` and `CXX_<env_target>` with the provided compiler.
-/// - `TARGET_AR` and `AR_<env_target>` with "llvm-lib".
-/// - `CARGO_TARGET_<env_target>_LINKER` with "lld-link".
-pub fn setup_target_compiler_and_linker_env(cmd: &mut Command, env_target: &str, compiler: &str) {
-    cmd.env("TARGET_CC", compiler);
-    cmd.env("TARGET_CXX", compiler);
-    cmd.env(format!("CC_{}", env_target), compiler);
-    cmd.env(format!("CXX_{}", env_target), compiler);
-    cmd.env("TARGET_AR", "llvm-lib");
-    cmd.env(format!("AR_{}", env_target), "llvm-lib");
-    cmd.env(
-        format!("CARGO_TARGET_{}_LINKER", env_target.to_uppercase()),
-        "lld-link",
-    );
+/// - `TARGET_CC` and `TARGET_CXX` with the provided C and C++ compilers.
+/// - `CC_<env_target>` and `CXX_<env_target>` with the provided C and C++ compilers.
+/// - `TARGET_AR` and `AR_<env_target>` with the provided archiver.
+/// - `CARGO_TARGET_<env_target>_LINKER` with the provided linker, unless the user already set it.
+///
+/// The generic `CC`, `CXX` and `AR` a user may have exported are left alone, they keep applying
+/// to the host: the cc crate prefers `CC_<target>` over them, and it reads `HOST_CC` rather than
+/// `TARGET_CC` when compiling for the host, e.g. in the build scripts of build dependencies.
+///
+/// Other cargo and rustc control variables such as `RUSTC_BOOTSTRAP` or `RUSTC_WRAPPER` are never set.
+pub fn setup_target_compiler_and_linker_env(
+    cmd: &mut Command,
+    env_target: &str,
+    cc: &str,
+    cxx: &str,
+    tools: &LlvmTools,
+) {
+    cmd.env("TARGET_CC", cc);
+    cmd.env("TARGET_CXX", cxx);
+    cmd.env(format!("CC_{}", env_target), cc);
+    cmd.env(format!("CXX_{}", env_target), cxx);
+    cmd.env("TARGET_AR", &tools.ar);
+    cmd.env(format!("AR_{}", env_target), &tools.ar);
+    let linker_key = format!("CARGO_TARGET_{}_LINKER", env_target.to_uppercase());
+    if env::var_os(&linker_key).map_or(true, |linker| linker.is_empty()) {
+        cmd.env(linker_key, &tools.linker);
+    }
+}
+
+/// Joins the generated C compiler `flags`, the ones containing whitespace, e.g. include
+/// directories in a cache directory with spaces, are quoted like shell words.
+///
+/// The cc crate only parses quoted `*FLAGS` with `CC_SHELL_ESCAPED_FLAGS`, which is set as well
+/// then, bindgen always parses `BINDGEN_EXTRA_CLANG_ARGS` that way.
+pub fn c_flags(cmd: &mut Command, flags: &[String]) -> String {
+    let mut quoted = Vec::with_capacity(flags.len());
+    for flag in flags {
+        if flag.contains(char::is_whitespace) {
+            cmd.env("CC_SHELL_ESCAPED_FLAGS", "1");
+            quoted.push(
+                shlex::try_quote(flag).map_or_else(|_| flag.clone(), |flag| flag.into_owned()),
+            );
+        } else {
+            quoted.push(flag.clone());
+        }
+    }
+    quoted.join(" ")
+}
+
+/// Returns the linker for `CARGO_TARGET_<triple>_LINKER`.
+///
+/// With `absolute`, `lld-link` is resolved in the provided environment path so that
+/// linking doesn't depend on rustc inheriting the modified `PATH`.
+fn resolve_linker(absolute: bool, env_path: &OsStr) -> Result<String> {
+    if !absolute {
+        return Ok("lld-link".to_string());
+    }
+    let linker = which_in("lld-link", Some(env_path), env::current_dir()?)
+        .context("Failed to find `lld-link` in PATH")?;
+    Ok(linker.to_string_lossy().into_owned())
+}
+
+/// Adds the directories of the splatted CRT and SDK PDBs for `arch` to `_NT_SYMBOL_PATH`.
+///
+/// xwin puts the PDBs next to the libraries, Windows debuggers such as WinDbg or winedbg
+/// look them up through `_NT_SYMBOL_PATH`.
+pub fn setup_debug_symbol_path(cmd: &mut Command, xwin_cache_dir: &Path, arch: &str) {
+    const KEY: &str = "_NT_SYMBOL_PATH";
+    let mut paths: Vec<String> = cmd
+        .get_envs()
+        .find(|(key, _)| *key == KEY)
+        .and_then(|(_, value)| value)
+        .map(|value| value.to_string_lossy().into_owned())
+        .or_else(|| env::var(KEY).ok())
+        .filter(|value| !value.is_empty())
+        .into_iter()
+        .collect();
+    for dir in ["crt/lib", "sdk/lib/ucrt", "sdk/lib/um"] {
+        let dir = crate::wine::wine_path(&xwin_cache_dir.join(dir).join(arch));
+        if !paths.contains(&dir) {
+            paths.push(dir);
+        }
+    }
+    cmd.env(KEY, paths.join(";"));
+}
+
+/// Returns the directories of the clang runtime libraries for `target`, such as the sanitizer and
+/// profiling ones, which don't exist unless compiler-rt was built for Windows.
+///
+/// The clang resource directory is queried once with `clang -print-resource-dir` unless
+/// `resource_dir` is set, a failing probe is ignored and only leaves the libraries unlinked.
+pub fn clang_runtime_lib_dirs(
+    resource_dir: Option<&Path>,
+    target: &str,
+    env_path: &OsStr,
+) -> Vec<String> {
+    static PROBED_RESOURCE_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+    let resource_dir = match resource_dir {
+        Some(dir) => Some(dir.to_path_buf()),
+        None => PROBED_RESOURCE_DIR
+            .get_or_init(|| {
+                Command::new("clang")
+                    .arg("-print-resource-dir")
+                    .env("PATH", env_path)
+                    .output()
+                    .ok()
+                    .filter(|output| output.status.success())
+                    .and_then(|output| String::from_utf8(output.stdout).ok())
+                    .map(|dir| PathBuf::from(dir.trim()))
+            })
+            .clone(),
+    };
+    let Some(resource_dir) = resource_dir else {
+        return Vec::new();
+    };
+    // Older layouts put all Windows runtimes in `lib/windows`, per-target runtime directories
+    // are named after the target triple
+    [
+        resource_dir.join("lib").join("windows"),
+        resource_dir.join("lib").join(target),
+    ]
+    .into_iter()
+    .filter(|dir| dir.is_dir())
+    .filter_map(|dir| dir.canonicalize().ok())
+    .map(|dir| adjust_canonicalization(dir.to_string_lossy().replace('\\', "/")))
+    .collect()
 }
 
-/// Configures the environment variables for CMake to use the Ninja generator and Windows system.
+/// Configures the environment variables for CMake to use `generator` and the toolchain file of `target`.
 ///
 /// This function sets up the following environment variables:
-/// - `CMAKE_GENERATOR` as "Ninja".
-/// - `CMAKE_SYSTEM_NAME` as "Windows".
+/// - `CMAKE_GENERATOR_<env_target>` as `generator`, "Ninja" unless `--cmake-generator` is set.
 /// - `CMAKE_TOOLCHAIN_FILE_<env_target>` with the provided toolchain path, where `<env_target>` is the target string
 ///   converted to lowercase and hyphens replaced with underscores.
-pub fn setup_cmake_env(cmd: &mut Command, target: &str, toolchain_path: PathBuf) {
+///
+/// The cmake crate prefers these target specific variables, so with multiple `--target`s and for
+/// host build scripts nothing leaks between targets. `CMAKE_SYSTEM_NAME` is set by the toolchain file.
+pub fn setup_cmake_env(
+    cmd: &mut Command,
+    target: &str,
+    toolchain_path: PathBuf,
+    generator: &str,
+    env_path: &OsStr,
+    workdir: &Path,
+    color: bool,
+) -> Result<()> {
+    if generator.eq_ignore_ascii_case("ninja") {
+        warn_missing_ninja(env_path, workdir, color);
+    }
     let env_target = target.to_lowercase().replace('-', "_");
-    cmd.env("CMAKE_GENERATOR", "Ninja")
-        .env("CMAKE_SYSTEM_NAME", "Windows")
+    cmd.env(format!("CMAKE_GENERATOR_{}", env_target), generator)
         .env(
             format!("CMAKE_TOOLCHAIN_FILE_{}", env_target),
             toolchain_path,
         );
+    Ok(())
+}
+
+/// Warns once when `ninja` isn't installed but the workspace depends on the cmake crate,
+/// otherwise the CMake failure is buried in the build script output.
+///
+/// This is only a hint, errors finding or reading Cargo.lock skip the warning.
+fn warn_missing_ninja(env_path: &OsStr, workdir: &Path, color: bool) {
+    static WARN_ONCE: Once = Once::new();
+
+    if WARN_ONCE.is_completed() || which_in("ninja", Some(env_path), workdir).is_ok() {
+        return;
+    }
+    let Ok(workdir) = workdir.canonicalize() else {
+        return;
+    };
+    let Some(lock_file) = workdir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|path| path.is_file())
+    else {
+        return;
+    };
+    let uses_cmake = fs::read_to_string(lock_file).is_ok_and(|content| {
+        content
+            .lines()
+            .any(|line| line.trim() == r#"name = "cmake""#)
+    });
+    if uses_cmake {
+        WARN_ONCE.call_once(|| {
+            warn(
+                color,
+                "`ninja` is not installed but a dependency builds with CMake, \
+                 install ninja or pass `--cmake-generator` to use another CMake generator.",
+            );
+        });
+    }
+}
+
+/// Whether to decorate progress bars and status messages with colors and emoji.
+///
+/// Like cargo, `--color` takes precedence over `CARGO_TERM_COLOR`. In `auto` mode decoration
+/// is disabled when `NO_COLOR` is set or stderr isn't a terminal.
+pub fn use_color(color: Option<&str>) -> bool {
+    let color = color
+        .map(|x| x.to_string())
+        .or_else(|| env::var("CARGO_TERM_COLOR").ok());
+    match color.as_deref() {
+        Some("always") => true,
+        Some("never") => false,
+        _ => env::var_os("NO_COLOR").map_or(true, |x| x.is_empty()) && io::stderr().is_terminal(),
+    }
+}
+
+/// Returns the style of download progress bars with transfer rate and ETA, without colors if `color` is false.
+pub fn progress_style(color: bool) -> ProgressStyle {
+    let template = if color {
+        "{spinner:.green} {prefix:.bold} [{elapsed}] {wide_bar:.green} {bytes}/{total_bytes} {bytes_per_sec} ETA {eta} {msg}"
+    } else {
+        "{prefix} [{elapsed}] {wide_bar} {bytes}/{total_bytes} {bytes_per_sec} ETA {eta} {msg}"
+    };
+    ProgressStyle::default_bar()
+        .template(template)
+        .expect("invalid progress bar template")
+        .progress_chars("=> ")
+}
+
+/// Prefixes `message` with `emoji` if decoration is enabled.
+pub fn decorate(color: bool, emoji: &str, message: &str) -> String {
+    if color {
+        format!("{emoji} {message}")
+    } else {
+        message.to_string()
+    }
+}
+
+/// Prints the warning `message` to stderr, decorated like the other status messages.
+pub fn warn(color: bool, message: &str) {
+    eprintln!("{}", decorate(color, "⚠️", &format!("Warning: {message}")));
+}
+
+/// Writes `content` to `path` unless the file already has exactly that content.
+///
+/// Keeping the mtime of generated files such as CMake toolchain files stable avoids
+/// needless reconfiguration on incremental builds.
+pub fn write_file_if_changed(path: &Path, content: impl AsRef<[u8]>) -> Result<()> {
+    let content = content.as_ref();
+    if fs::read(path).is_ok_and(|existing| existing == content) {
+        return Ok(());
+    }
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// Rewrites the paths under `cache_dir` in the CMake toolchain file `content` relative to
+/// `${CMAKE_CURRENT_LIST_DIR}`, so that the toolchain files in `cmake_dir` keep working when the
+/// cache directory is moved or mounted somewhere else, see `cargo xwin env --relocatable-cache`.
+/// Toolchain files written outside the cache directory with `--cmake-dir` keep absolute paths.
+pub fn relocatable_cmake_paths(content: &str, cache_dir: &Path, cmake_dir: &Path) -> String {
+    let Ok(relative) = cmake_dir.strip_prefix(cache_dir) else {
+        return content.to_string();
+    };
+    let mut list_dir_to_cache = "${CMAKE_CURRENT_LIST_DIR}".to_string();
+    for _ in relative.components() {
+        list_dir_to_cache.push_str("/..");
+    }
+    let cache_dir = adjust_canonicalization(cache_dir.to_slash_lossy().to_string());
+    content.replace(&cache_dir, &list_dir_to_cache)
+}
+
+/// Resolves the C and C++ compilers to use for the target.
+///
+/// User provided compilers (via `--cc`/`--cxx`) take precedence over the
+/// backend defaults and are validated to exist in the provided environment path.
+pub fn resolve_target_compilers<'a>(
+    cc: Option<&'a str>,
+    cxx: Option<&'a str>,
+    default_cc: &'a str,
+    default_cxx: &'a str,
+    env_path: &OsStr,
+) -> Result<(&'a str, &'a str)> {
+    for compiler in [cc, cxx].into_iter().flatten() {
+        which_in(compiler, Some(env_path), env::current_dir()?)
+            .with_context(|| format!("Failed to find compiler `{compiler}` in PATH"))?;
+    }
+    Ok((cc.unwrap_or(default_cc), cxx.unwrap_or(default_cxx)))
 }
 
 pub fn rustc_target_bin_dir() -> Result<PathBuf> {
@@ -146,36 +442,363 @@ pub fn adjust_canonicalization(p: String) -> String {
     }
 }
 
-pub fn default_build_target_from_config(workdir: &Path) -> Result<Option<String>> {
-    let output = Command::new("cargo")
-        .current_dir(workdir)
-        .args([
-            "config",
-            "get",
-            "-Z",
-            "unstable-options",
-            "--format",
-            "json-value",
-            "build.target",
-        ])
-        .env("__CARGO_TEST_CHANNEL_OVERRIDE_DO_NOT_USE_THIS", "nightly")
-        .output()?;
+/// Returns the xwin architecture name of an MSVC `target`, which names the CRT and SDK library directories.
+pub fn xwin_arch(target: &str) -> Result<&str> {
+    let target_arch = target
+        .split_once('-')
+        .map(|(x, _)| x)
+        .context("invalid target triple")?;
+    Ok(match target_arch {
+        "i586" | "i686" => "x86",
+        _ => target_arch,
+    })
+}
+
+/// Splits target triples separated by commas or whitespace, as accepted by `CARGO_BUILD_TARGET`.
+pub fn split_targets<S: AsRef<str>>(targets: &[S]) -> Vec<String> {
+    targets
+        .iter()
+        .flat_map(|target| target.as_ref().split([',', ' ', '\t', '\n']))
+        .filter(|target| !target.is_empty())
+        .map(|target| target.to_string())
+        .collect()
+}
+
+/// Returns the build targets to use when no `--target` is specified.
+///
+/// Like cargo, the `CARGO_BUILD_TARGET` environment variable takes precedence over
+/// the `build.target` config, which is read with cargo-config2 so that no unstable
+/// cargo invocation is needed.
+pub fn default_build_targets(workdir: &Path) -> Result<Vec<String>> {
+    if let Ok(build_target) = env::var("CARGO_BUILD_TARGET") {
+        let targets = split_targets(&[build_target]);
+        if !targets.is_empty() {
+            return Ok(targets);
+        }
+    }
+    let config =
+        cargo_config2::Config::load_with_cwd(workdir).context("Failed to load cargo config")?;
+    Ok(config.build_target_for_cli(std::iter::empty::<&str>())?)
+}
+
+/// Resolves the cargo target directory of the workspace.
+///
+/// `--target-dir` takes precedence over `CARGO_TARGET_DIR`, like cargo a relative path is
+/// relative to the current directory. Otherwise `cargo metadata` is queried so that the
+/// `build.target-dir` configuration is respected.
+pub fn resolve_target_dir(
+    manifest_path: Option<&Path>,
+    cargo: &cargo_options::CommonOptions,
+) -> Result<PathBuf> {
+    let target_dir = cargo.target_dir.clone().or_else(|| {
+        env::var_os("CARGO_TARGET_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+    });
+    if let Some(target_dir) = target_dir {
+        return Ok(env::current_dir()?.join(target_dir));
+    }
+
+    // Run the cargo that runs us, e.g. the one of a `cargo +nightly xwin` toolchain
+    let mut metadata = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()));
+    metadata.args(["metadata", "--format-version", "1", "--no-deps"]);
+    if let Some(manifest_path) = manifest_path {
+        metadata.arg("--manifest-path").arg(manifest_path);
+    }
+    for config in &cargo.config {
+        metadata.arg("--config").arg(config);
+    }
+    let output = metadata.output().context("Failed to run cargo metadata")?;
     if !output.status.success() {
-        return Ok(None);
+        bail!(
+            "Failed to run cargo metadata: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    #[derive(Deserialize)]
+    struct Metadata {
+        target_directory: PathBuf,
+    }
+    let metadata: Metadata =
+        serde_json::from_slice(&output.stdout).context("Failed to deserialize cargo metadata")?;
+    Ok(metadata.target_directory)
+}
+
+/// Returns the name of the output directory for the selected cargo profile.
+pub fn profile_dir_name(release: bool, profile: Option<&str>) -> &str {
+    match profile {
+        Some("dev") | Some("test") => "debug",
+        Some("release") | Some("bench") => "release",
+        Some(profile) => profile,
+        None if release => "release",
+        None => "debug",
     }
-    let stdout = String::from_utf8(output.stdout)?;
-    let target = stdout.trim().trim_matches('"');
-    Ok(Some(target.to_string()))
 }
 
-/// Get RUSTFLAGS in the following order:
+/// Sets up `flags` needed to link `target` on top of the user's rustflags.
+///
+/// The flags are scoped to `target` with `CARGO_TARGET_<triple>_RUSTFLAGS` so that other
+/// targets, such as artifact dependencies built for the host, don't get them. Cargo ignores
+/// target specific rustflags when `RUSTFLAGS` or `CARGO_ENCODED_RUSTFLAGS` is set, in which
+/// case the flags are appended to them. Flags containing whitespace are an error, they can't
+/// be passed in the space separated `CARGO_TARGET_<triple>_RUSTFLAGS`.
 ///
-/// 1. `RUSTFLAGS` environment variable.
-/// 2. `rustflags` cargo configuration
-pub fn get_rustflags(workdir: &Path, target: &str) -> Result<Option<cargo_config2::Flags>> {
+/// The user's rustflags from config files and from `cli_config`, cargo's `--config` values,
+/// are kept in front of `flags`, so that codegen options such as `-C opt-level=z` still apply.
+///
+/// The flags are added to the rustdocflags the same way, cargo links doctests with rustdoc
+/// and runs them for cross targets with the target runner since Rust 1.89.
+pub fn setup_target_rustflags(
+    cmd: &mut Command,
+    workdir: &Path,
+    target: &str,
+    flags: &[String],
+    cli_config: &[String],
+    color: bool,
+) -> Result<()> {
     let cargo_config = cargo_config2::Config::load_with_cwd(workdir)?;
-    let rustflags = cargo_config.rustflags(target)?;
-    Ok(rustflags)
+    warn_rustflags_precedence(workdir, target, color)?;
+    setup_target_flags(
+        cmd,
+        target,
+        flags,
+        "RUSTFLAGS",
+        cargo_config.rustflags(target)?,
+        &cargo_config.build.rustflags,
+        &CliConfigFlags::parse(cli_config, target, "rustflags")?,
+    )?;
+    setup_target_flags(
+        cmd,
+        target,
+        flags,
+        "RUSTDOCFLAGS",
+        cargo_config.rustdocflags(target)?,
+        &cargo_config.build.rustdocflags,
+        &CliConfigFlags::parse(cli_config, target, "rustdocflags")?,
+    )
+}
+
+/// Appends `flags` to the resolved `RUSTFLAGS` or `RUSTDOCFLAGS` (`name`) of `target`
+fn setup_target_flags(
+    cmd: &mut Command,
+    target: &str,
+    flags: &[String],
+    name: &str,
+    resolved: Option<cargo_config2::Flags>,
+    build_flags: &Option<cargo_config2::Flags>,
+    cli: &CliConfigFlags,
+) -> Result<()> {
+    let encoded_key = format!("CARGO_ENCODED_{name}");
+    let env_flags_set = [encoded_key.as_str(), name]
+        .into_iter()
+        .any(|key| env::var_os(key).is_some_and(|value| !value.is_empty()));
+    // Like cargo, the target flags of config files and `--config` are merged and take
+    // precedence over the merged build flags
+    let has_target_flags = &resolved != build_flags || cli.target.is_some();
+    let config_flags = || {
+        let mut config_flags = resolved.clone().unwrap_or_default();
+        if has_target_flags {
+            if &resolved == build_flags {
+                config_flags.flags.clear();
+            }
+            config_flags
+                .flags
+                .extend(cli.target.iter().flatten().cloned());
+        } else {
+            config_flags.flags.extend(cli.build.iter().cloned());
+        }
+        config_flags
+    };
+    if env_flags_set {
+        // Cargo ignores all config rustflags when they're set in the environment
+        let mut resolved = resolved.clone().unwrap_or_default();
+        resolved.flags.extend_from_slice(flags);
+        cmd.env(encoded_key, resolved.encode()?);
+        return Ok(());
+    }
+    // `CARGO_TARGET_<triple>_RUSTFLAGS` is space separated, `CARGO_ENCODED_RUSTFLAGS` would
+    // pass the flags to all targets, such as artifact dependencies built for the host
+    if let Some(flag) = flags.iter().find(|flag| flag.contains(char::is_whitespace)) {
+        bail!(
+            "`{flag}` contains whitespace and can't be passed to {target} only, \
+             use an `XWIN_CACHE_DIR` and target directory without spaces"
+        );
+    }
+
+    let env_key = format!(
+        "CARGO_TARGET_{}_{name}",
+        target.to_uppercase().replace('-', "_")
+    );
+    // Cargo merges target rustflags from config files and `--config` with the environment
+    // variable, but ignores `build.rustflags` when there are target rustflags, so carry them over.
+    let mut target_flags = if has_target_flags {
+        cargo_config2::Flags::from_space_separated(&env::var(&env_key).unwrap_or_default())
+    } else {
+        config_flags()
+    };
+    target_flags.flags.extend_from_slice(flags);
+    cmd.env(env_key, target_flags.encode_space_separated()?);
+    Ok(())
+}
+
+/// Returns the user's rustflags of `target` from the environment, cargo config files and
+/// `cli_config`, cargo's `--config` values
+pub fn user_rustflags(workdir: &Path, target: &str, cli_config: &[String]) -> Result<Vec<String>> {
+    let cargo_config = cargo_config2::Config::load_with_cwd(workdir)?;
+    let cli = CliConfigFlags::parse(cli_config, target, "rustflags")?;
+    let mut flags = cargo_config
+        .rustflags(target)?
+        .map(|flags| flags.flags)
+        .unwrap_or_default();
+    flags.extend(cli.build);
+    flags.extend(cli.target.unwrap_or_default());
+    Ok(flags)
+}
+
+/// Whether `flags` enable the `-C` or `-Z` (`kind`) option `name`, such as `-Zsanitizer=address`
+/// or `-C linker-plugin-lto`, unless it's disabled with e.g. `-C lto=off`
+pub fn has_flag_option(flags: &[String], kind: &str, name: &str) -> bool {
+    let enables = |option: &str| match option.split_once('=') {
+        Some((option, value)) => option == name && !matches!(value, "n" | "no" | "off" | "false"),
+        None => option == name,
+    };
+    flags
+        .iter()
+        .enumerate()
+        .any(|(index, flag)| match flag.strip_prefix(kind) {
+            Some("") => flags.get(index + 1).is_some_and(|option| enables(option)),
+            Some(option) => enables(option),
+            None => false,
+        })
+}
+
+/// Rustflags or rustdocflags set with cargo's `--config` option, which cargo-config2 doesn't load
+#[derive(Debug, Default)]
+struct CliConfigFlags {
+    /// `build.<key>`
+    build: Vec<String>,
+    /// `target.<triple>.<key>`
+    target: Option<Vec<String>>,
+}
+
+impl CliConfigFlags {
+    /// Collects the `build.<key>` and `target.<triple>.<key>` flags of `--config` values,
+    /// which are either `KEY=VALUE` pairs or paths to config files
+    fn parse(cli_config: &[String], target: &str, key: &str) -> Result<Self> {
+        let mut cli_flags = Self::default();
+        for config in cli_config {
+            match config.split_once('=') {
+                Some((config_key, value)) if !Path::new(config).is_file() => {
+                    let config_key: Vec<_> = config_key
+                        .trim()
+                        .split('.')
+                        .map(|part| part.trim_matches(['"', '\'']))
+                        .collect();
+                    let flags = || {
+                        parse_toml_strings(value.trim()).with_context(|| {
+                            format!("Failed to parse `--config {config}`, expected a string or an array of strings")
+                        })
+                    };
+                    match config_key.as_slice() {
+                        ["build", k] if *k == key => cli_flags.build.extend(flags()?),
+                        ["target", triple, k] if *k == key && *triple == target => cli_flags
+                            .target
+                            .get_or_insert_with(Vec::new)
+                            .extend(flags()?),
+                        _ => {}
+                    }
+                }
+                _ => {
+                    let config = cargo_config2::de::Config::load_file(config)?;
+                    let (build, target) = match key {
+                        "rustflags" => (
+                            config.build.rustflags,
+                            config.target.get(target).and_then(|t| t.rustflags.clone()),
+                        ),
+                        _ => (
+                            config.build.rustdocflags,
+                            config
+                                .target
+                                .get(target)
+                                .and_then(|t| t.rustdocflags.clone()),
+                        ),
+                    };
+                    if let Some(build) = build {
+                        cli_flags
+                            .build
+                            .extend(build.flags.into_iter().map(|flag| flag.val));
+                    }
+                    if let Some(target) = target {
+                        cli_flags
+                            .target
+                            .get_or_insert_with(Vec::new)
+                            .extend(target.flags.into_iter().map(|flag| flag.val));
+                    }
+                }
+            }
+        }
+        Ok(cli_flags)
+    }
+}
+
+/// Parses a TOML string, which is split on whitespace like cargo does, or an array of strings
+fn parse_toml_strings(value: &str) -> Option<Vec<String>> {
+    match value.parse::<toml_edit::Value>().ok()? {
+        toml_edit::Value::String(value) => Some(
+            value
+                .value()
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+        ),
+        toml_edit::Value::Array(values) => values
+            .iter()
+            .map(|value| value.as_str().map(str::to_string))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Warns once when `RUSTFLAGS` from the environment shadows rustflags in cargo config files.
+///
+/// The cargo config is resolved without the environment variable, so that `target.<cfg>`
+/// entries are evaluated for `target` like cargo does.
+fn warn_rustflags_precedence(workdir: &Path, target: &str, color: bool) -> Result<()> {
+    static WARN_ONCE: Once = Once::new();
+
+    let env_rustflags = ["CARGO_ENCODED_RUSTFLAGS", "RUSTFLAGS"]
+        .into_iter()
+        .find(|key| env::var_os(key).is_some_and(|value| !value.is_empty()));
+    let Some(env_rustflags) = env_rustflags else {
+        return Ok(());
+    };
+    if WARN_ONCE.is_completed() {
+        return Ok(());
+    }
+
+    let env =
+        env::vars_os().filter(|(key, _)| key != "CARGO_ENCODED_RUSTFLAGS" && key != "RUSTFLAGS");
+    let file_config = cargo_config2::Config::load_with_options(
+        workdir,
+        cargo_config2::ResolveOptions::default().env(env),
+    )?;
+    let config_has_rustflags = file_config
+        .rustflags(target)?
+        .is_some_and(|flags| !flags.flags.is_empty());
+    if config_has_rustflags {
+        WARN_ONCE.call_once(|| {
+            warn(
+                color,
+                &format!(
+                    "both `{env_rustflags}` and cargo config rustflags are set, \
+                     cargo only uses `{env_rustflags}` and ignores the rustflags from cargo config files. \
+                     cargo-xwin appends its linker flags on top of `{env_rustflags}`."
+                ),
+            );
+        });
+    }
+    Ok(())
 }
 
 #[cfg(any(feature = "native-tls", feature = "rustls"))]
@@ -185,13 +808,117 @@ fn tls_ca_bundle() -> Option<std::ffi::OsString> {
         .or_else(|| env::var_os("SSL_CERT_FILE"))
 }
 
+/// Runs the download `f` up to 3 times with an exponential backoff
+///
+/// Connections closed before the whole body was read are common on flaky networks,
+/// `what` names the download in the retry messages. Only network and I/O transport
+/// errors are retried, others such as HTTP errors or checksum mismatches fail right away.
+pub fn retry_download<T>(what: &str, color: bool, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    const MAX_RETRIES: u32 = 3;
+    let mut retry_count = 0;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                retry_count += 1;
+                if retry_count >= MAX_RETRIES || !is_transport_error(&err) {
+                    return Err(err);
+                }
+                warn(
+                    color,
+                    &format!(
+                        "failed to download {what}: {err:#}\nRetrying download (attempt {}/{MAX_RETRIES})",
+                        retry_count + 1
+                    ),
+                );
+                std::thread::sleep(Duration::from_secs(2u64.pow(retry_count - 1)));
+            }
+        }
+    }
+}
+
+/// Whether `err` was caused by the network connection, e.g. a connection reset,
+/// a timeout or a response body closed before all bytes were read
+fn is_transport_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<ureq::Error>() {
+            return matches!(
+                err,
+                ureq::Error::Transport(transport) if matches!(
+                    transport.kind(),
+                    ureq::ErrorKind::Dns
+                        | ureq::ErrorKind::ConnectionFailed
+                        | ureq::ErrorKind::Io
+                        | ureq::ErrorKind::ProxyConnect
+                )
+            );
+        }
+        cause.downcast_ref::<io::Error>().is_some_and(|err| {
+            matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::UnexpectedEof
+                    | io::ErrorKind::Interrupted
+            )
+        })
+    })
+}
+
+/// Returns an agent builder honoring the proxy environment variables,
+/// `timeout` limits connecting and every single read or write
+fn agent_builder(timeout: Option<Duration>, ipv4_only: bool) -> ureq::AgentBuilder {
+    let mut builder = ureq::builder().try_proxy_from_env(true);
+    if let Some(timeout) = timeout {
+        builder = builder
+            .timeout_connect(timeout)
+            .timeout_read(timeout)
+            .timeout_write(timeout);
+    }
+    if ipv4_only {
+        builder = builder.resolver(|addr: &str| -> io::Result<Vec<SocketAddr>> {
+            let addrs: Vec<_> = addr.to_socket_addrs()?.filter(|a| a.is_ipv4()).collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{addr} has no IPv4 address"),
+                ));
+            }
+            Ok(addrs)
+        });
+    }
+    builder
+}
+
+/// Warns once that TLS certificates aren't verified under `--allow-insecure-download`
+fn warn_insecure_download(color: bool) {
+    static WARN_ONCE: Once = Once::new();
+
+    WARN_ONCE.call_once(|| {
+        warn(
+            color,
+            "--allow-insecure-download is set, TLS certificates of download servers \
+             are NOT verified and the downloads can be tampered with.",
+        );
+    });
+}
+
 #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
-pub fn http_agent() -> Result<ureq::Agent> {
+pub fn http_agent(
+    timeout: Option<Duration>,
+    ipv4_only: bool,
+    insecure: bool,
+    color: bool,
+) -> Result<ureq::Agent> {
     use fs_err::File;
     use std::io;
     use std::sync::Arc;
 
-    let mut builder = ureq::builder().try_proxy_from_env(true);
+    let mut builder = agent_builder(timeout, ipv4_only);
     let mut tls_builder = native_tls_crate::TlsConnector::builder();
     if let Some(ca_bundle) = tls_ca_bundle() {
         let mut reader = io::BufReader::new(File::open(ca_bundle)?);
@@ -200,17 +927,38 @@ pub fn http_agent() -> Result<ureq::Agent> {
             tls_builder.add_root_certificate(native_tls_crate::Certificate::from_pem(&cert)?);
         }
     }
+    if insecure {
+        warn_insecure_download(color);
+        tls_builder
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+    }
     builder = builder.tls_connector(Arc::new(tls_builder.build()?));
     Ok(builder.build())
 }
 
 #[cfg(feature = "rustls")]
-pub fn http_agent() -> Result<ureq::Agent> {
+pub fn http_agent(
+    timeout: Option<Duration>,
+    ipv4_only: bool,
+    insecure: bool,
+    color: bool,
+) -> Result<ureq::Agent> {
     use fs_err::File;
     use std::io;
     use std::sync::Arc;
 
-    let builder = ureq::builder().try_proxy_from_env(true);
+    let builder = agent_builder(timeout, ipv4_only);
+    if insecure {
+        warn_insecure_download(color);
+        let client_config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(
+                rustls::crypto::ring::default_provider(),
+            )))
+            .with_no_client_auth();
+        return Ok(builder.tls_config(Arc::new(client_config)).build());
+    }
     if let Some(ca_bundle) = tls_ca_bundle() {
         let mut reader = io::BufReader::new(File::open(ca_bundle)?);
         let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
@@ -225,8 +973,267 @@ pub fn http_agent() -> Result<ureq::Agent> {
     }
 }
 
+/// Accepts any server certificate for `--allow-insecure-download`, the handshake signatures
+/// are still checked so that the connection itself works as usual
+#[cfg(feature = "rustls")]
+#[derive(Debug)]
+struct NoCertificateVerification(rustls::crypto::CryptoProvider);
+
+#[cfg(feature = "rustls")]
+impl rustls::client::danger::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::pki_types::CertificateDer<'_>,
+        _intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
 #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
-pub fn http_agent() -> Result<ureq::Agent> {
-    let builder = ureq::builder().try_proxy_from_env(true);
+pub fn http_agent(
+    timeout: Option<Duration>,
+    ipv4_only: bool,
+    insecure: bool,
+    color: bool,
+) -> Result<ureq::Agent> {
+    // Without TLS support only plain HTTP mirrors work, there are no certificates to skip
+    if insecure {
+        warn_insecure_download(color);
+    }
+    let builder = agent_builder(timeout, ipv4_only);
     Ok(builder.build())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Option<Vec<String>> {
+        Some(values.iter().map(|value| value.to_string()).collect())
+    }
+
+    #[test]
+    fn parse_toml_string_values() {
+        assert_eq!(
+            parse_toml_strings(r#""-C target-cpu=native""#),
+            strings(&["-C", "target-cpu=native"])
+        );
+        assert_eq!(
+            parse_toml_strings(r#"'-Clink-arg=C:\lib'"#),
+            strings(&[r"-Clink-arg=C:\lib"])
+        );
+        assert_eq!(
+            parse_toml_strings(r#"["-C", "link-arg=/a, b", '-Ctarget-feature=+crt-static']"#),
+            strings(&["-C", "link-arg=/a, b", "-Ctarget-feature=+crt-static"])
+        );
+        assert_eq!(
+            parse_toml_strings(r#"[ "a \"quoted\" arg" , ]"#),
+            strings(&[r#"a "quoted" arg"#])
+        );
+        assert_eq!(
+            parse_toml_strings(r#"["caf\u00e9", """-Cdebuginfo=1"""]"#),
+            strings(&["café", "-Cdebuginfo=1"])
+        );
+        assert_eq!(parse_toml_strings("[]"), strings(&[]));
+        assert_eq!(parse_toml_strings("-Cdebuginfo=1"), None);
+        assert_eq!(parse_toml_strings(r#"["unterminated]"#), None);
+        assert_eq!(parse_toml_strings("[1, 2]"), None);
+    }
+
+    #[test]
+    fn detect_flag_options() {
+        let flags = |flags: &[&str]| {
+            flags
+                .iter()
+                .map(|flag| flag.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert!(has_flag_option(
+            &flags(&["-Zsanitizer=address"]),
+            "-Z",
+            "sanitizer"
+        ));
+        assert!(has_flag_option(
+            &flags(&["-C", "opt-level=1", "-Z", "sanitizer=address"]),
+            "-Z",
+            "sanitizer"
+        ));
+        assert!(!has_flag_option(
+            &flags(&["-Z", "build-std"]),
+            "-Z",
+            "sanitizer"
+        ));
+        assert!(has_flag_option(
+            &flags(&["-Clinker-plugin-lto"]),
+            "-C",
+            "linker-plugin-lto"
+        ));
+        assert!(has_flag_option(&flags(&["-C", "lto=thin"]), "-C", "lto"));
+        assert!(!has_flag_option(&flags(&["-C", "lto=off"]), "-C", "lto"));
+        assert!(!has_flag_option(
+            &flags(&["-Clinker-plugin-lto"]),
+            "-C",
+            "lto"
+        ));
+        // The value of another option isn't an option
+        assert!(!has_flag_option(&flags(&["--cfg", "lto"]), "-C", "lto"));
+    }
+
+    #[test]
+    fn retry_only_transport_errors() {
+        let closed = io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "response body closed before all bytes were read",
+        );
+        assert!(is_transport_error(&anyhow::Error::new(closed)));
+        let reset = anyhow::Error::new(io::Error::from(io::ErrorKind::ConnectionReset))
+            .context("failed to download foo.cab");
+        assert!(is_transport_error(&reset));
+
+        assert!(!is_transport_error(&anyhow::Error::new(io::Error::from(
+            io::ErrorKind::StorageFull
+        ))));
+        assert!(!is_transport_error(&anyhow::anyhow!(
+            "checksum mismatch for foo.cab"
+        )));
+
+        let mut attempts = 0;
+        let result: Result<()> = retry_download("foo", false, || {
+            attempts += 1;
+            bail!("checksum mismatch")
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn parse_cli_config_flags() {
+        let cli_config = [
+            r#"build.rustflags=["-C", "opt-level=1"]"#.to_string(),
+            r#"target.x86_64-pc-windows-msvc.rustflags = '-Clink-arg=/DEBUG:FULL'"#.to_string(),
+            r#"target."aarch64-pc-windows-msvc".rustflags = ["-Cdebuginfo=0"]"#.to_string(),
+            r#"build.rustdocflags="--cfg docsrs""#.to_string(),
+        ];
+        let flags =
+            CliConfigFlags::parse(&cli_config, "x86_64-pc-windows-msvc", "rustflags").unwrap();
+        assert_eq!(Some(flags.build), strings(&["-C", "opt-level=1"]));
+        assert_eq!(flags.target, strings(&["-Clink-arg=/DEBUG:FULL"]));
+
+        let flags =
+            CliConfigFlags::parse(&cli_config, "aarch64-pc-windows-msvc", "rustflags").unwrap();
+        assert_eq!(flags.target, strings(&["-Cdebuginfo=0"]));
+
+        let flags =
+            CliConfigFlags::parse(&cli_config, "x86_64-pc-windows-msvc", "rustdocflags").unwrap();
+        assert_eq!(Some(flags.build), strings(&["--cfg", "docsrs"]));
+        assert_eq!(flags.target, None);
+
+        let err = CliConfigFlags::parse(
+            &["build.rustflags=[-Cdebuginfo=1]".to_string()],
+            "x86_64-pc-windows-msvc",
+            "rustflags",
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("expected a string or an array of strings"));
+    }
+
+    #[test]
+    fn target_flags_stay_scoped_to_the_target() {
+        if ["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS"]
+            .iter()
+            .any(|key| env::var_os(key).is_some_and(|value| !value.is_empty()))
+        {
+            return;
+        }
+        let target = "x86_64-pc-windows-msvc";
+        let cli = CliConfigFlags::default();
+        let mut cmd = Command::new("cargo");
+        let flags = strings(&["-C", "linker-flavor=lld-link"]).unwrap();
+        setup_target_flags(&mut cmd, target, &flags, "RUSTFLAGS", None, &None, &cli).unwrap();
+        let envs: Vec<_> = cmd.get_envs().map(|(key, _)| key.to_os_string()).collect();
+        assert_eq!(envs, ["CARGO_TARGET_X86_64_PC_WINDOWS_MSVC_RUSTFLAGS"]);
+
+        let flags = strings(&["-Lnative=/home/user/My Cache/xwin/crt/lib/x86_64"]).unwrap();
+        let err = setup_target_flags(
+            &mut Command::new("cargo"),
+            target,
+            &flags,
+            "RUSTFLAGS",
+            None,
+            &None,
+            &cli,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("contains whitespace"));
+    }
+
+    #[test]
+    fn c_flags_quote_paths_with_spaces() {
+        let mut cmd = Command::new("cargo");
+        let flags = strings(&["-I/cache/xwin/crt/include"]).unwrap();
+        assert_eq!(c_flags(&mut cmd, &flags), "-I/cache/xwin/crt/include");
+        assert_eq!(cmd.get_envs().count(), 0);
+
+        let flags = strings(&["-I/my cache/xwin/crt/include", "-L/cache/lib"]).unwrap();
+        assert_eq!(
+            c_flags(&mut cmd, &flags),
+            "'-I/my cache/xwin/crt/include' -L/cache/lib"
+        );
+        assert!(cmd
+            .get_envs()
+            .any(|(key, value)| key == "CC_SHELL_ESCAPED_FLAGS" && value == Some("1".as_ref())));
+    }
+
+    #[test]
+    fn cmake_paths_relative_to_toolchain_file() {
+        let cache_dir = Path::new("/cache/cargo-xwin");
+        let content = "/imsvc/cache/cargo-xwin/xwin/crt/include\n-libpath:\"/opt/lib\"";
+        assert_eq!(
+            relocatable_cmake_paths(content, cache_dir, &cache_dir.join("cmake/clang-cl")),
+            "/imsvc${CMAKE_CURRENT_LIST_DIR}/../../xwin/crt/include\n-libpath:\"/opt/lib\""
+        );
+        // Toolchain files outside the cache keep absolute paths
+        assert_eq!(
+            relocatable_cmake_paths(content, cache_dir, Path::new("/build/cmake/clang-cl")),
+            content
+        );
+    }
+}
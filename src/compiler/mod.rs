@@ -1,3 +1,5 @@
 pub mod clang;
 pub mod clang_cl;
 pub mod common;
+pub mod msvc;
+pub mod zig;
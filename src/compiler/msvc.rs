@@ -0,0 +1,126 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Result};
+
+use crate::compiler::common::{
+    c_flags, default_build_targets, setup_target_rustflags, split_targets, use_color,
+};
+use crate::options::XWinOptions;
+
+/// Uses the MSVC toolchain of a Visual Studio installation on Windows hosts
+///
+/// rustc and the cc crate find `link.exe` and `cl.exe` on their own, so nothing is downloaded
+/// and only the cargo-xwin specific flags are set up.
+#[derive(Debug)]
+pub struct Msvc<'a> {
+    xwin_options: &'a XWinOptions,
+}
+
+impl<'a> Msvc<'a> {
+    pub fn new(xwin_options: &'a XWinOptions) -> Self {
+        Self { xwin_options }
+    }
+
+    pub fn apply_command_env(
+        &self,
+        manifest_path: Option<&Path>,
+        cargo: &cargo_options::CommonOptions,
+        cmd: &mut Command,
+    ) -> Result<()> {
+        if find_visual_studio().is_none() {
+            bail!(
+                "Failed to find a Visual Studio installation with the C++ build tools, \
+                 the msvc cross compiler needs a Windows host with MSVC installed"
+            );
+        }
+        if self.xwin_options.lto_cache_dir.is_some() {
+            bail!("--lto-cache-dir is an lld-link option, it isn't supported by the msvc cross compiler");
+        }
+        let workdir = manifest_path
+            .and_then(|p| p.parent().map(|x| x.to_path_buf()))
+            .or_else(|| env::current_dir().ok())
+            .unwrap();
+        let mut targets = split_targets(&cargo.target);
+        if targets.is_empty() {
+            targets = default_build_targets(&workdir)?;
+            for target in &targets {
+                cmd.arg("--target").arg(target);
+            }
+        }
+
+        let extra_includes: Vec<_> = self
+            .xwin_options
+            .extra_include_dirs()?
+            .iter()
+            .map(|dir| format!("-I{dir}"))
+            .collect();
+        let mut target_c_flags = self.xwin_options.abi_c_flags(true);
+        if !extra_includes.is_empty() {
+            target_c_flags.push_str(&c_flags(cmd, &extra_includes));
+            target_c_flags.push(' ');
+        }
+        let color = use_color(cargo.color.as_deref());
+        for target in targets.iter().filter(|target| target.contains("msvc")) {
+            if !target_c_flags.is_empty() {
+                let env_target = target.to_lowercase().replace('-', "_");
+                for (key, user_flags) in [
+                    (format!("CFLAGS_{env_target}"), env::var("CFLAGS")),
+                    (format!("CXXFLAGS_{env_target}"), env::var("CXXFLAGS")),
+                ] {
+                    let user_flags = user_flags.unwrap_or_default();
+                    cmd.env(key, format!("{target_c_flags}{user_flags}"));
+                }
+            }
+
+            let mut rustflags: Vec<_> = self
+                .xwin_options
+                .extra_lib_dirs()?
+                .iter()
+                .map(|dir| format!("-Lnative={dir}"))
+                .collect();
+            rustflags.extend(self.xwin_options.pgo_rustflags()?);
+            // `--lto-cache-dir` is rejected above, no need to look at the user's rustflags
+            rustflags.extend(self.xwin_options.link_arg_rustflags(&[])?);
+            rustflags.extend(self.xwin_options.abi_rustflags());
+            rustflags.extend(self.xwin_options.extra_rustflags());
+            if !rustflags.is_empty() {
+                setup_target_rustflags(cmd, &workdir, target, &rustflags, &cargo.config, color)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the Visual Studio installation with the C++ build tools, if any
+///
+/// A developer command prompt sets `VCINSTALLDIR`, otherwise `vswhere` is asked.
+pub(crate) fn find_visual_studio() -> Option<PathBuf> {
+    if !cfg!(windows) {
+        return None;
+    }
+    if let Some(dir) = env::var_os("VCINSTALLDIR").filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+    let program_files = env::var_os("ProgramFiles(x86)")?;
+    let vswhere = Path::new(&program_files)
+        .join("Microsoft Visual Studio")
+        .join("Installer")
+        .join("vswhere.exe");
+    let output = Command::new(vswhere)
+        .args([
+            "-latest",
+            "-products",
+            "*",
+            "-requires",
+            "Microsoft.VisualStudio.Component.VC.Tools.x86.x64",
+            "-property",
+            "installationPath",
+        ])
+        .output()
+        .ok()?;
+    let path = String::from_utf8(output.stdout).ok()?;
+    let path = path.trim();
+    (output.status.success() && !path.is_empty()).then(|| PathBuf::from(path))
+}
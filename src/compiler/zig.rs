@@ -0,0 +1,378 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use path_slash::PathExt;
+use which::which_in;
+
+use crate::compiler::clang_cl::ClangCl;
+use crate::compiler::common::{
+    adjust_canonicalization, c_flags, default_build_targets, relocatable_cmake_paths,
+    resolve_target_compilers, setup_cmake_env, setup_debug_symbol_path, setup_env_path,
+    setup_llvm_tools, setup_target_compiler_and_linker_env, setup_target_rustflags, split_targets,
+    use_color, user_rustflags, write_file_if_changed, xwin_arch,
+};
+use crate::lock::ToolchainLock;
+use crate::options::{SdkInclude, XWinOptions};
+
+#[derive(Debug)]
+pub struct ZigCc<'a> {
+    xwin_options: &'a XWinOptions,
+}
+
+impl<'a> ZigCc<'a> {
+    pub fn new(xwin_options: &'a XWinOptions) -> Self {
+        Self { xwin_options }
+    }
+
+    pub fn apply_command_env(
+        &self,
+        manifest_path: Option<&Path>,
+        cargo: &cargo_options::CommonOptions,
+        cache_dir: PathBuf,
+        cmd: &mut Command,
+    ) -> Result<()> {
+        let tools_dir = self
+            .xwin_options
+            .tools_dir(manifest_path, cargo, &cache_dir)?;
+        let env_path = setup_env_path(&tools_dir)?;
+        let color = use_color(cargo.color.as_deref());
+
+        let xwin_cache_dir = self.xwin_options.xwin_cache_dir()?;
+
+        let workdir = manifest_path
+            .and_then(|p| p.parent().map(|x| x.to_path_buf()))
+            .or_else(|| env::current_dir().ok())
+            .unwrap();
+        let lock = ToolchainLock::load_if_locked(self.xwin_options, &workdir)?;
+        let crt_lock = lock.as_ref().map(ToolchainLock::crt).transpose()?;
+        let mut targets = split_targets(&cargo.target);
+        if targets.is_empty() {
+            // if no target is specified, use the default build targets
+            // Note that this is required, otherwise it may fail with link errors
+            targets = default_build_targets(&workdir)?;
+            for target in &targets {
+                cmd.arg("--target").arg(target);
+            }
+        }
+
+        for target in &targets {
+            if target.contains("msvc") {
+                which_in("zig", Some(&env_path), env::current_dir()?)
+                    .context("Failed to find zig in PATH, please install zig")?;
+                ClangCl::new(self.xwin_options)
+                    .setup_msvc_crt(xwin_cache_dir.clone(), color, crt_lock)
+                    .context("Failed to setup MSVC CRT")?;
+                let env_target = target.to_lowercase().replace('-', "_");
+
+                let llvm_tools = setup_llvm_tools(
+                    &env_path,
+                    &cache_dir,
+                    self.xwin_options.no_symlink_tools,
+                    self.xwin_options.absolute_linker,
+                )
+                .context("Failed to setup LLVM tools")?;
+                let xwin_arch = xwin_arch(target)?;
+
+                let xwin_dir = adjust_canonicalization(xwin_cache_dir.to_slash_lossy().to_string());
+                let (zig_cc, zig_cxx) = self
+                    .setup_zig_wrappers(target, &tools_dir, &xwin_dir, xwin_arch)
+                    .context("Failed to setup zig cc wrappers")?;
+                let zig_cc = zig_cc.to_string_lossy();
+                let zig_cxx = zig_cxx.to_string_lossy();
+                let (cc, cxx) = resolve_target_compilers(
+                    self.xwin_options.cc.as_deref(),
+                    self.xwin_options.cxx.as_deref(),
+                    &zig_cc,
+                    &zig_cxx,
+                    &env_path,
+                )?;
+                setup_target_compiler_and_linker_env(cmd, &env_target, cc, cxx, &llvm_tools);
+
+                let user_set_c_flags = env::var("CFLAGS").unwrap_or_default();
+                let user_set_cxx_flags = env::var("CXXFLAGS").unwrap_or_default();
+                // zig provides the CRT headers through the libc file, `-I` directories are
+                // searched before the `-isystem` ones
+                let include_dirs = self.xwin_options.xwin_include_dirs(&xwin_dir)?;
+                let extra_include_dirs = self.xwin_options.extra_include_dirs()?;
+                let zig_flags: Vec<_> = include_dirs
+                    .iter()
+                    .filter_map(|dir| {
+                        if extra_include_dirs.contains(dir) {
+                            Some(format!("-I{dir}"))
+                        } else if dir.ends_with("/sdk/include/um")
+                            || dir.ends_with("/sdk/include/shared")
+                        {
+                            Some(format!("-isystem{dir}"))
+                        } else {
+                            None
+                        }
+                    })
+                    .collect();
+                let mut zig_flags = format!(
+                    "{abi_flags}{}",
+                    c_flags(cmd, &zig_flags),
+                    abi_flags = self.xwin_options.abi_c_flags(false)
+                );
+                if let Some(guard) =
+                    ClangCl::new(self.xwin_options).excluded_headers_guard(&xwin_cache_dir)?
+                {
+                    let include = c_flags(cmd, &["-include".to_string(), guard]);
+                    zig_flags = format!("{include} {zig_flags}");
+                }
+                let include_flags: Vec<_> =
+                    include_dirs.iter().map(|dir| format!("-I{dir}")).collect();
+                let include_flags = c_flags(cmd, &include_flags);
+                cmd.env(
+                    format!("CFLAGS_{env_target}"),
+                    format!("{zig_flags} {user_set_c_flags}",),
+                );
+                cmd.env(
+                    format!("CXXFLAGS_{env_target}"),
+                    format!("{zig_flags} {user_set_cxx_flags}",),
+                );
+
+                cmd.env(
+                    format!("BINDGEN_EXTRA_CLANG_ARGS_{env_target}"),
+                    &include_flags,
+                );
+                cmd.env("RCFLAGS", &include_flags);
+
+                let mut rustflags = vec![
+                    "-C".to_string(),
+                    "linker-flavor=lld-link".to_string(),
+                    format!(
+                        "-Lnative={dir}/{crt_lib}",
+                        dir = xwin_dir,
+                        crt_lib = self.xwin_options.crt_lib_dir(xwin_arch)
+                    ),
+                    format!(
+                        "-Lnative={dir}/sdk/lib/um/{arch}",
+                        dir = xwin_dir,
+                        arch = xwin_arch
+                    ),
+                    format!(
+                        "-Lnative={dir}/sdk/lib/ucrt/{arch}",
+                        dir = xwin_dir,
+                        arch = xwin_arch
+                    ),
+                ];
+                rustflags.extend(
+                    self.xwin_options
+                        .extra_lib_dirs()?
+                        .iter()
+                        .map(|dir| format!("-Lnative={dir}")),
+                );
+                rustflags.extend(self.xwin_options.pgo_rustflags()?);
+                let user_rustflags = user_rustflags(&workdir, target, &cargo.config)?;
+                rustflags.extend(self.xwin_options.link_arg_rustflags(&user_rustflags)?);
+                rustflags.extend(self.xwin_options.abi_rustflags());
+                rustflags.extend(self.xwin_options.extra_rustflags());
+                setup_target_rustflags(cmd, &workdir, target, &rustflags, &cargo.config, color)?;
+                if self.xwin_options.xwin_include_debug_symbols {
+                    setup_debug_symbol_path(cmd, &xwin_cache_dir, xwin_arch);
+                }
+                cmd.env("PATH", &env_path);
+
+                // CMake support
+                if !self.xwin_options.no_cmake {
+                    let cmake_toolchain = self
+                        .setup_cmake_toolchain(target, &xwin_dir, &cache_dir, cc, cxx)
+                        .with_context(|| {
+                            format!("Failed to setup CMake toolchain for {}", target)
+                        })?;
+                    setup_cmake_env(
+                        cmd,
+                        target,
+                        cmake_toolchain,
+                        &self.xwin_options.cmake_generator,
+                        &env_path,
+                        &workdir,
+                        color,
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Generates `zig cc`/`zig c++` wrapper scripts for `target` in `<tools_dir>/zig`.
+    ///
+    /// `zig cc` doesn't accept the `--target` flag passed by the `cc` crate for clang
+    /// compilers and can't find the MSVC CRT on its own, so the wrappers drop that flag
+    /// and point zig at the xwin splatted CRT and SDK through a libc paths file.
+    fn setup_zig_wrappers(
+        &self,
+        target: &str,
+        tools_dir: &Path,
+        xwin_dir: &str,
+        xwin_arch: &str,
+    ) -> Result<(PathBuf, PathBuf)> {
+        let zig_cache_dir = tools_dir.join("zig");
+        fs::create_dir_all(&zig_cache_dir)?;
+
+        // zig searches `-I` directories first, then the include directories of the libc file and
+        // then the `-isystem` ones
+        let order = self.xwin_options.sdk_include_order();
+        let libc_includes = &order[1..3];
+        if order[0] != SdkInclude::Extra
+            || !libc_includes.contains(&SdkInclude::Crt)
+            || !libc_includes.contains(&SdkInclude::Ucrt)
+        {
+            return Err(self.xwin_options.unsupported_sdk_include_order(
+                "zig",
+                "it searches `extra` first and the `crt` and `ucrt` headers of its libc file \
+                 before `um` and `shared`",
+            ));
+        }
+        let (include_dir, sys_include_dir) = if libc_includes[0] == SdkInclude::Crt {
+            ("crt/include", "sdk/include/ucrt")
+        } else {
+            ("sdk/include/ucrt", "crt/include")
+        };
+        let libc_file = zig_cache_dir.join(format!("{target}-libc.txt"));
+        write_file_if_changed(
+            &libc_file,
+            format!(
+                "include_dir={dir}/{include_dir}\n\
+                 sys_include_dir={dir}/{sys_include_dir}\n\
+                 crt_dir={dir}/sdk/lib/ucrt/{arch}\n\
+                 msvc_lib_dir={dir}/{crt_lib}\n\
+                 kernel32_lib_dir={dir}/sdk/lib/um/{arch}\n\
+                 gcc_dir=\n",
+                dir = xwin_dir,
+                arch = xwin_arch,
+                crt_lib = self.xwin_options.crt_lib_dir(xwin_arch),
+            ),
+        )?;
+
+        let zig_target = zig_target(target)?;
+        let libc_file = adjust_canonicalization(libc_file.to_slash_lossy().to_string());
+        let cc = write_zig_wrapper(&zig_cache_dir, target, "cc", &zig_target, &libc_file)?;
+        let cxx = write_zig_wrapper(&zig_cache_dir, target, "c++", &zig_target, &libc_file)?;
+        Ok((cc, cxx))
+    }
+
+    fn setup_cmake_toolchain(
+        &self,
+        target: &str,
+        xwin_dir: &str,
+        cache_dir: &Path,
+        cc: &str,
+        cxx: &str,
+    ) -> Result<PathBuf> {
+        let cmake_cache_dir = self.xwin_options.cmake_dir(cache_dir, "zig")?;
+
+        let toolchain_file = cmake_cache_dir.join(format!("{}-toolchain.cmake", target));
+        let target_arch = target
+            .split_once('-')
+            .map(|(x, _)| x)
+            .context("invalid target triple")?;
+        let processor = match target_arch {
+            "i586" | "i686" => "X86",
+            "x86_64" => "AMD64",
+            "aarch64" => "ARM64",
+            "arm64ec" => "ARM64EC",
+            _ => target_arch,
+        };
+
+        let content = format!(
+            r#"
+set(CMAKE_SYSTEM_NAME Windows)
+set(CMAKE_SYSTEM_PROCESSOR {processor}){system_version}
+
+set(CMAKE_C_COMPILER "{cc}" CACHE FILEPATH "")
+set(CMAKE_CXX_COMPILER "{cxx}" CACHE FILEPATH "")
+set(CMAKE_RC_COMPILER llvm-rc CACHE FILEPATH "")
+
+set(COMPILE_FLAGS
+    -isystem"{dir}/sdk/include/um"
+    -isystem"{dir}/sdk/include/shared")
+
+string(REPLACE ";" " " COMPILE_FLAGS "${{COMPILE_FLAGS}}")
+
+set(_CMAKE_C_FLAGS_INITIAL "${{CMAKE_C_FLAGS}}" CACHE STRING "")
+set(CMAKE_C_FLAGS "${{_CMAKE_C_FLAGS_INITIAL}} ${{COMPILE_FLAGS}}" CACHE STRING "" FORCE)
+
+set(_CMAKE_CXX_FLAGS_INITIAL "${{CMAKE_CXX_FLAGS}}" CACHE STRING "")
+set(CMAKE_CXX_FLAGS "${{_CMAKE_CXX_FLAGS_INITIAL}} ${{COMPILE_FLAGS}}" CACHE STRING "" FORCE)
+        "#,
+            system_version = self.xwin_options.cmake_system_version_line(),
+            dir = xwin_dir,
+            cc = cc.replace('\\', "/"),
+            cxx = cxx.replace('\\', "/"),
+        );
+        let content = relocatable_cmake_paths(&content, cache_dir, &cmake_cache_dir);
+        write_file_if_changed(&toolchain_file, content)?;
+        Ok(toolchain_file)
+    }
+}
+
+/// Converts a Rust MSVC target triple to a zig target, for example
+/// `x86_64-pc-windows-msvc` -> `x86_64-windows-msvc`.
+fn zig_target(target: &str) -> Result<String> {
+    let target_arch = target
+        .split_once('-')
+        .map(|(x, _)| x)
+        .context("invalid target triple")?;
+    let zig_arch = match target_arch {
+        "i586" | "i686" => "x86",
+        "x86_64" | "aarch64" => target_arch,
+        _ => bail!("zig cc doesn't support target {target}"),
+    };
+    Ok(format!("{zig_arch}-windows-msvc"))
+}
+
+#[cfg(unix)]
+fn write_zig_wrapper(
+    zig_cache_dir: &Path,
+    target: &str,
+    command: &str,
+    zig_target: &str,
+    libc_file: &str,
+) -> Result<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let wrapper = zig_cache_dir.join(format!("{target}-zig{command}"));
+    let content = format!(
+        r#"#!/bin/sh
+# Drop the --target flag passed by the cc crate, zig uses -target instead
+for arg do
+    shift
+    case "$arg" in
+        --target=*) ;;
+        *) set -- "$@" "$arg" ;;
+    esac
+done
+exec zig {command} -target {zig_target} --libc "{libc_file}" "$@"
+"#
+    );
+    // Keep the mtime of an unchanged wrapper so that it doesn't trigger rebuilds
+    write_file_if_changed(&wrapper, content)?;
+    fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+    Ok(wrapper)
+}
+
+#[cfg(windows)]
+fn write_zig_wrapper(
+    zig_cache_dir: &Path,
+    target: &str,
+    command: &str,
+    zig_target: &str,
+    libc_file: &str,
+) -> Result<PathBuf> {
+    let wrapper = zig_cache_dir.join(format!("{target}-zig{command}.bat"));
+    // cmd can't replace text containing `=`, so the --target flag passed by the cc crate is
+    // turned into an unused macro definition, zig uses -target instead
+    let content = format!(
+        "@echo off\r\n\
+         setlocal\r\n\
+         set \"args=%*\"\r\n\
+         if defined args set \"args=%args:--target=-D__CARGO_XWIN_CC_TARGET%\"\r\n\
+         zig {command} -target {zig_target} --libc \"{libc_file}\" %args%\r\n"
+    );
+    write_file_if_changed(&wrapper, content)?;
+    Ok(wrapper)
+}
@@ -0,0 +1,45 @@
+use std::path::Path;
+
+use anyhow::Result;
+use clap::Parser;
+use serde_json::{json, Value};
+
+use crate::options::XWinOptions;
+
+/// Print the effective cargo-xwin configuration as JSON
+///
+/// The values are resolved from the command line and the `XWIN_*` environment variables,
+/// with `--cross-compiler auto` and the cache directories resolved the same way as for a build.
+#[derive(Clone, Debug, Default, Parser)]
+#[command(display_order = 1)]
+pub struct DumpConfig {
+    #[command(flatten)]
+    pub xwin: XWinOptions,
+}
+
+impl DumpConfig {
+    /// Execute `cargo xwin dump-config` command
+    pub fn execute(&self) -> Result<()> {
+        println!("{}", serde_json::to_string_pretty(&self.resolve()?)?);
+        Ok(())
+    }
+
+    /// Serializes the options, with the backend, the cache directories and the rustflags
+    /// resolved the same way as for a build
+    fn resolve(&self) -> Result<Value> {
+        let xwin = &self.xwin;
+        let mut config = serde_json::to_value(XWinOptions {
+            cross_compiler: xwin.resolve_cross_compiler()?,
+            ..xwin.clone()
+        })?;
+        config["cache-dir"] = json!(display(&xwin.cache_dir()?));
+        config["crt-cache-dir"] = json!(display(&xwin.xwin_cache_dir()?));
+        config["sysroot-cache-dir"] = json!(display(&xwin.sysroot_cache_dir()?));
+        config["xwin-rustflags"] = json!(xwin.extra_rustflags());
+        Ok(config)
+    }
+}
+
+fn display(path: &Path) -> String {
+    path.display().to_string()
+}
@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Result};
+use clap::{Parser, ValueEnum};
+use fs_err as fs;
+
+use crate::compiler::common::{default_build_targets, split_targets};
+use crate::options::XWinOptions;
+
+/// Output format of `cargo xwin env`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum EnvFormat {
+    /// shell `export` statements
+    #[default]
+    Shell,
+    /// `[target.<triple>.env]` sections of a `Cross.toml` for the cross tool
+    CrossToml,
+}
+
+/// Print the environment variables used to cross compile to Windows MSVC targets
+#[derive(Clone, Debug, Default, Parser)]
+#[command(
+    display_order = 1,
+    after_help = "Use `eval \"$(cargo xwin env --target <TRIPLE>)\"` to apply them to the current shell."
+)]
+pub struct Env {
+    #[command(flatten)]
+    pub xwin: XWinOptions,
+
+    #[command(flatten)]
+    pub cargo: cargo_options::CommonOptions,
+
+    /// Path to Cargo.toml
+    #[arg(long, value_name = "PATH", help_heading = cargo_options::heading::MANIFEST_OPTIONS)]
+    pub manifest_path: Option<PathBuf>,
+
+    /// Refer to the xwin cache directory through `$XWIN_CACHE_DIR` instead of its absolute path,
+    /// so that the output still works when the cache is mounted somewhere else
+    #[arg(long)]
+    pub relocatable_cache: bool,
+
+    /// Only print the variables whose value differs from the current environment
+    #[arg(long)]
+    pub diff: bool,
+
+    /// The output format
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        conflicts_with = "relocatable_cache"
+    )]
+    pub emit: EnvFormat,
+
+    /// Write the variables as single quoted `KEY='VALUE'` lines to a dotenv file instead of
+    /// printing them, for dotenv loaders and `set -a; . <file>`
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["relocatable_cache", "emit"]
+    )]
+    pub emit_env_file: Option<PathBuf>,
+}
+
+impl Env {
+    /// Print the environment variables in the `--emit` format, or write them to `--emit-env-file`
+    pub fn execute(&self) -> Result<()> {
+        if let Some(path) = &self.emit_env_file {
+            let envs = self.target_envs(&self.cargo)?;
+            let mut content = String::new();
+            for (key, value) in &envs {
+                let key = key.to_string_lossy();
+                let value = dotenv_quote(&key, &value.to_string_lossy())?;
+                content.push_str(&format!("{key}={value}\n"));
+            }
+            fs::write(path, content)?;
+            return Ok(());
+        }
+        let targets = self.targets()?;
+        if self.emit == EnvFormat::CrossToml {
+            return self.print_cross_toml(&targets);
+        }
+        let cache_dir = if self.relocatable_cache {
+            let cache_dir = self.xwin.cache_dir()?;
+            println!(
+                "export XWIN_CACHE_DIR=\"${{XWIN_CACHE_DIR:-{}}}\";",
+                shell_escape(&cache_dir.to_string_lossy())
+            );
+            Some(cache_dir)
+        } else {
+            None
+        };
+        let print_envs =
+            |envs: &BTreeMap<OsString, OsString>| print_envs(envs, cache_dir.as_deref());
+        if targets.len() <= 1 {
+            let envs = self.target_envs(&self.cargo)?;
+            print_envs(&envs);
+            return Ok(());
+        }
+
+        // Group the variables per target, variables that are the same for all targets
+        // are printed once at the top
+        let mut target_envs = Vec::new();
+        for target in &targets {
+            let mut cargo = self.cargo.clone();
+            cargo.target = vec![target.clone()];
+            target_envs.push((target, self.target_envs(&cargo)?));
+        }
+        let mut common_envs = target_envs[0].1.clone();
+        common_envs.retain(|key, value| {
+            target_envs
+                .iter()
+                .all(|(_, envs)| envs.get(key) == Some(value))
+        });
+        println!("# all targets");
+        print_envs(&common_envs);
+        for (target, mut envs) in target_envs {
+            envs.retain(|key, _| !common_envs.contains_key(key));
+            println!();
+            println!("# {target}");
+            print_envs(&envs);
+        }
+        Ok(())
+    }
+
+    /// Print the variables as `Cross.toml` sections
+    ///
+    /// The variables are passed through per target, the xwin cache is mounted into the
+    /// container at the same path so that the include and library paths stay valid.
+    fn print_cross_toml(&self, targets: &[String]) -> Result<()> {
+        let cache_dir = self.xwin.cache_dir()?;
+        println!("[build.env]");
+        println!(
+            "volumes = [{}]",
+            toml_string(&format!("XWIN_CACHE_DIR={}", cache_dir.display()))
+        );
+        for target in targets {
+            let mut cargo = self.cargo.clone();
+            cargo.target = vec![target.clone()];
+            let envs = self.target_envs(&cargo)?;
+            println!();
+            println!("[target.{target}.env]");
+            println!("passthrough = [");
+            // `PATH` of the host is meaningless inside the container
+            for (key, value) in envs.iter().filter(|(key, _)| *key != "PATH") {
+                println!(
+                    "    {},",
+                    toml_string(&format!(
+                        "{}={}",
+                        key.to_string_lossy(),
+                        value.to_string_lossy()
+                    ))
+                );
+            }
+            println!("]");
+        }
+        Ok(())
+    }
+
+    /// Returns the targets to print the environment variables for
+    fn targets(&self) -> Result<Vec<String>> {
+        let targets = split_targets(&self.cargo.target);
+        if !targets.is_empty() {
+            return Ok(targets);
+        }
+        let workdir = match self
+            .manifest_path
+            .as_deref()
+            .and_then(|p| p.parent())
+            .filter(|p| !p.as_os_str().is_empty())
+        {
+            Some(dir) => dir.to_path_buf(),
+            None => env::current_dir()?,
+        };
+        default_build_targets(&workdir)
+    }
+
+    fn target_envs(
+        &self,
+        cargo: &cargo_options::CommonOptions,
+    ) -> Result<BTreeMap<OsString, OsString>> {
+        let mut cmd = Command::new("cargo");
+        self.xwin
+            .apply_command_env(self.manifest_path.as_deref(), cargo, &mut cmd)?;
+        Ok(cmd
+            .get_envs()
+            .filter_map(|(key, value)| Some((key.to_os_string(), value?.to_os_string())))
+            .filter(|(key, value)| !self.diff || env::var_os(key).as_ref() != Some(value))
+            .collect())
+    }
+}
+
+/// Prints the variables as `export` statements, occurrences of `cache_dir` are replaced
+/// with a reference to `$XWIN_CACHE_DIR`
+fn print_envs(envs: &BTreeMap<OsString, OsString>, cache_dir: Option<&Path>) {
+    let cache_dir = cache_dir.map(|dir| shell_escape(&dir.to_string_lossy()));
+    for (key, value) in envs {
+        if key == "XWIN_CACHE_DIR" && cache_dir.is_some() {
+            continue;
+        }
+        let mut value = shell_escape(&value.to_string_lossy());
+        if let Some(cache_dir) = &cache_dir {
+            value = value.replace(cache_dir.as_str(), "${XWIN_CACHE_DIR}");
+        }
+        println!("export {}=\"{}\";", key.to_string_lossy(), value);
+    }
+}
+
+/// Escapes characters that are special inside double quotes
+fn shell_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Quotes the `value` of `key` for a dotenv file with single quotes, which dotenv loaders such
+/// as python-dotenv and docker compose as well as shells read back literally.
+///
+/// There's no escaping that all of them read back the same way, so values containing single
+/// quotes, line breaks or backslashes that python-dotenv would unescape are an error.
+fn dotenv_quote(key: &str, value: &str) -> Result<String> {
+    if value.contains(['\'', '\n', '\r']) || value.contains("\\\\") || value.ends_with('\\') {
+        bail!("The value of `{key}` can't be written to a dotenv file unchanged: {value}");
+    }
+    Ok(format!("'{value}'"))
+}
+
+/// Formats `value` as a TOML basic string
+fn toml_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
@@ -0,0 +1,579 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::{self, Child, Command, ExitStatus, Stdio};
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+
+use crate::compiler::common::{decorate, use_color};
+use crate::options::XWinOptions;
+
+/// How cargo's `compiler-artifact` JSON messages are read for signing and `--post-build-exec`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ArtifactMessages {
+    /// cargo-xwin requested the messages, they aren't printed
+    Requested,
+    /// The user requested JSON messages with `--message-format`, they're forwarded to stdout
+    Forwarded,
+}
+
+/// Adds `--message-format json-render-diagnostics` when the linked binaries are signed or
+/// passed to `--post-build-exec`, cargo then reports them in `compiler-artifact` messages.
+///
+/// JSON message formats requested by the user are kept, others can't be combined with it.
+pub(crate) fn artifact_messages(
+    xwin: &XWinOptions,
+    message_format: &mut Vec<String>,
+) -> Result<Option<ArtifactMessages>> {
+    if !xwin.sign.enabled() && xwin.post_build_exec.is_none() {
+        return Ok(None);
+    }
+    if message_format.is_empty() {
+        message_format.push("json-render-diagnostics".to_string());
+        return Ok(Some(ArtifactMessages::Requested));
+    }
+    let is_json = message_format
+        .iter()
+        .flat_map(|format| format.split(','))
+        .any(|format| format.trim().starts_with("json"));
+    if !is_json {
+        bail!(
+            "Signing and --post-build-exec read cargo's JSON messages, \
+             they can't be used with `--message-format {}`",
+            message_format.join(",")
+        );
+    }
+    Ok(Some(ArtifactMessages::Forwarded))
+}
+
+/// Runs a cargo subcommand and exits with its exit code if it fails.
+///
+/// With `--explain-errors`, stderr is captured while being forwarded so that hints for
+/// common link failures can be printed when the command fails. With `--retry-on-link-error`,
+/// the command is run once more when it failed with a transient lld-link I/O error.
+/// With `--failure-summary`, a JSON line with the exit code and the stderr tail is printed
+/// to stderr when the command fails.
+///
+/// With `artifacts`, the executables and DLLs linked by cargo are read from its JSON messages
+/// on stdout and returned.
+pub(crate) fn execute_cargo(
+    mut cmd: Command,
+    subcommand: &str,
+    xwin: &XWinOptions,
+    cargo: &cargo_options::CommonOptions,
+    artifacts: Option<ArtifactMessages>,
+) -> Result<Vec<PathBuf>> {
+    if artifacts.is_some() {
+        cmd.stdout(Stdio::piped());
+    }
+    if !xwin.explain_errors && !xwin.retry_on_link_error && xwin.failure_summary.is_none() {
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Failed to run cargo {subcommand}"))?;
+        let reader = artifacts.map(|artifacts| read_artifact_messages(&mut child, artifacts));
+        let status = child
+            .wait()
+            .unwrap_or_else(|_| panic!("Failed to wait on cargo {subcommand} process"));
+        let binaries = join_artifact_reader(reader)?;
+        if !status.success() {
+            process::exit(status.code().unwrap_or(1));
+        }
+        return Ok(binaries);
+    }
+
+    // Keep cargo's colored output even though stderr is piped
+    if cargo.color.is_none()
+        && env::var_os("CARGO_TERM_COLOR").is_none()
+        && io::stderr().is_terminal()
+    {
+        cmd.env("CARGO_TERM_COLOR", "always");
+    }
+    cmd.stderr(Stdio::piped());
+    let color = use_color(cargo.color.as_deref());
+    let (mut status, mut captured, mut binaries) = run_captured(&mut cmd, subcommand, artifacts)?;
+    if !status.success() && xwin.retry_on_link_error && is_transient_link_error(&captured) {
+        eprintln!(
+            "{}",
+            decorate(
+                color,
+                "🔁",
+                &format!("Linking failed with a transient I/O error, retrying cargo {subcommand}")
+            )
+        );
+        // Binaries linked before the failure are fresh when retrying
+        let retried_binaries;
+        (status, captured, retried_binaries) = run_captured(&mut cmd, subcommand, artifacts)?;
+        binaries.extend(retried_binaries);
+    }
+    if !status.success() {
+        if xwin.explain_errors {
+            for hint in explain_link_errors(&captured) {
+                eprintln!("{}", decorate(color, "💡", &hint));
+            }
+        }
+        if let Some(lines) = xwin.failure_summary {
+            eprintln!("{}", failure_summary(subcommand, status, &captured, lines));
+        }
+        process::exit(status.code().unwrap_or(1));
+    }
+    Ok(binaries)
+}
+
+/// Signs and runs `--post-build-exec` for each of the linked `binaries`
+///
+/// The command is split into arguments like a shell would without running one, `{bin}` is
+/// replaced in every argument so that paths with spaces are passed as is.
+pub(crate) fn run_post_build(xwin: &XWinOptions, binaries: &[PathBuf]) -> Result<()> {
+    if !xwin.sign.enabled() && xwin.post_build_exec.is_none() {
+        return Ok(());
+    }
+    let signer = if xwin.sign.enabled() {
+        Some(xwin.sign.resolve_signer()?)
+    } else {
+        None
+    };
+    let args = match &xwin.post_build_exec {
+        Some(command) => {
+            let args = shlex::split(command).with_context(|| {
+                format!("Failed to parse --post-build-exec command `{command}`")
+            })?;
+            if args.is_empty() {
+                bail!("--post-build-exec command is empty");
+            }
+            args
+        }
+        None => Vec::new(),
+    };
+    for bin in binaries {
+        if let Some(signer) = &signer {
+            xwin.sign.sign(signer, bin)?;
+        }
+        let Some((program, args)) = args.split_first() else {
+            continue;
+        };
+        let bin = bin.to_string_lossy();
+        let status = Command::new(program)
+            .args(args.iter().map(|arg| arg.replace("{bin}", &bin)))
+            .status()
+            .with_context(|| format!("Failed to run post-build command `{program}`"))?;
+        if !status.success() {
+            bail!("Post-build command failed for {bin} with {status}");
+        }
+    }
+    Ok(())
+}
+
+type ArtifactReader = thread::JoinHandle<io::Result<Vec<PathBuf>>>;
+
+/// Reads cargo's JSON messages from the piped stdout of `child` on another thread, other lines
+/// and the messages requested by the user are forwarded to our stdout
+fn read_artifact_messages(child: &mut Child, artifacts: ArtifactMessages) -> ArtifactReader {
+    let child_stdout = child.stdout.take().expect("stdout is piped");
+    thread::spawn(move || {
+        let mut binaries = Vec::new();
+        let mut stdout = io::stdout();
+        for line in BufReader::new(child_stdout).lines() {
+            let line = line?;
+            match serde_json::from_str::<serde_json::Value>(&line) {
+                Ok(message) => {
+                    binaries.extend(linked_binaries(&message));
+                    if artifacts == ArtifactMessages::Forwarded {
+                        writeln!(stdout, "{line}")?;
+                    }
+                }
+                Err(_) => writeln!(stdout, "{line}")?,
+            }
+        }
+        Ok(binaries)
+    })
+}
+
+fn join_artifact_reader(reader: Option<ArtifactReader>) -> Result<Vec<PathBuf>> {
+    let Some(reader) = reader else {
+        return Ok(Vec::new());
+    };
+    let binaries = reader
+        .join()
+        .expect("Failed to read cargo's JSON messages")
+        .context("Failed to read cargo's JSON messages")?;
+    Ok(binaries)
+}
+
+/// Returns the executables and DLLs of a `compiler-artifact` message that were linked by this
+/// build, fresh artifacts were already up to date and build scripts and proc macros are for the host
+fn linked_binaries(message: &serde_json::Value) -> Vec<PathBuf> {
+    if message["reason"] != "compiler-artifact" || message["fresh"] == true {
+        return Vec::new();
+    }
+    let is_host = message["target"]["kind"].as_array().is_some_and(|kinds| {
+        kinds
+            .iter()
+            .any(|kind| kind == "custom-build" || kind == "proc-macro")
+    });
+    if is_host {
+        return Vec::new();
+    }
+    message["filenames"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|filename| filename.as_str())
+        .map(PathBuf::from)
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext == "exe" || ext == "dll")
+        })
+        .collect()
+}
+
+/// Runs `cmd` with piped stderr, forwarding it to our stderr and returning a copy along with
+/// the binaries linked according to the `artifacts` messages
+fn run_captured(
+    cmd: &mut Command,
+    subcommand: &str,
+    artifacts: Option<ArtifactMessages>,
+) -> Result<(ExitStatus, String, Vec<PathBuf>)> {
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("Failed to run cargo {subcommand}"))?;
+    let reader = artifacts.map(|artifacts| read_artifact_messages(&mut child, artifacts));
+    let mut child_stderr = child.stderr.take().expect("stderr is piped");
+    let mut captured = Vec::new();
+    let mut buf = [0u8; 8192];
+    let mut stderr = io::stderr();
+    loop {
+        let n = child_stderr.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        stderr.write_all(&buf[..n])?;
+        captured.extend_from_slice(&buf[..n]);
+    }
+    let status = child
+        .wait()
+        .unwrap_or_else(|_| panic!("Failed to wait on cargo {subcommand} process"));
+    let binaries = join_artifact_reader(reader)?;
+    Ok((
+        status,
+        String::from_utf8_lossy(&captured).into_owned(),
+        binaries,
+    ))
+}
+
+/// Returns the `--failure-summary` JSON line with the last `lines` lines of `stderr`
+fn failure_summary(subcommand: &str, status: ExitStatus, stderr: &str, lines: usize) -> String {
+    let stderr = strip_ansi_escapes(stderr);
+    let all_lines: Vec<_> = stderr.lines().collect();
+    let tail = &all_lines[all_lines.len().saturating_sub(lines)..];
+    serde_json::json!({
+        "reason": "cargo-xwin-failure",
+        "subcommand": subcommand,
+        "exit_code": status.code(),
+        "stderr_tail": tail,
+    })
+    .to_string()
+}
+
+/// Removes the escape sequences cargo writes with `CARGO_TERM_COLOR=always`, CSI sequences such
+/// as the colors `ESC [ 1;31m` and OSC sequences such as the hyperlinks `ESC ] 8 ;; url ESC \`,
+/// which end with `ESC \` or BEL
+fn strip_ansi_escapes(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        match chars.next() {
+            // CSI sequences end with a byte in `@`..=`~`
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Other escapes are followed by a single character
+            _ => {}
+        }
+    }
+    stripped
+}
+
+/// Whether lld-link failed with an I/O error that is likely to go away when linking again,
+/// such as those seen with the CRT and SDK on network filesystems
+fn is_transient_link_error(stderr: &str) -> bool {
+    const TRANSIENT_ERRORS: &[&str] = &[
+        "Input/output error",
+        "Stale file handle",
+        "Resource temporarily unavailable",
+        "Device or resource busy",
+        "Text file busy",
+        "Interrupted system call",
+    ];
+    stderr.lines().any(|line| {
+        line.contains("lld-link: error:") && TRANSIENT_ERRORS.iter().any(|err| line.contains(err))
+    })
+}
+
+/// Returns remediation hints for well-known lld-link errors found in `stderr`.
+fn explain_link_errors(stderr: &str) -> Vec<String> {
+    let mut hints = BTreeSet::new();
+    for line in stderr.lines() {
+        if let Some(symbol) = undefined_symbol(line) {
+            hints.insert(undefined_symbol_hint(&symbol));
+        } else if line.contains("could not open") && line.contains(".lib") {
+            hints.insert(
+                "A library could not be found, make sure the MSVC CRT and Windows SDK were \
+                 downloaded for the target architecture with `--xwin-arch` and `--xwin-variant`"
+                    .to_string(),
+            );
+        } else if line.contains("machine type") && line.contains("conflicts with") {
+            hints.insert(
+                "Some object files were built for a different architecture, check the C \
+                 compiler and flags used by build scripts, e.g. `CC_<target>` and `CFLAGS_<target>`"
+                    .to_string(),
+            );
+        } else if line.contains("mismatch detected for 'RuntimeLibrary'") {
+            hints.insert(
+                "Objects linked against both the static and the dynamic CRT, make sure C \
+                 dependencies use the same CRT as Rust (`-C target-feature=+crt-static`)"
+                    .to_string(),
+            );
+        } else if line.contains("linker `lld-link` not found") {
+            hints.insert(
+                "lld-link is missing, install LLVM or run `rustup component add llvm-tools`"
+                    .to_string(),
+            );
+        }
+    }
+    hints.into_iter().collect()
+}
+
+/// Extracts the symbol from an lld-link undefined symbol error line
+///
+/// lld-link demangles dllimport symbols as `__declspec(dllimport) name`,
+/// those are returned with the `__imp_` prefix.
+fn undefined_symbol(line: &str) -> Option<String> {
+    let (_, symbol) = line
+        .split_once("undefined symbol: ")
+        .or_else(|| line.split_once("unresolved external symbol "))?;
+    let (symbol, imported) = match symbol.strip_prefix("__declspec(dllimport) ") {
+        Some(symbol) => (symbol, true),
+        None => (symbol, false),
+    };
+    let symbol = symbol
+        .split_whitespace()
+        .next()?
+        .trim_matches(|c| c == '"' || c == '\'');
+    if imported {
+        Some(format!("__imp_{symbol}"))
+    } else {
+        Some(symbol.to_string())
+    }
+}
+
+fn undefined_symbol_hint(symbol: &str) -> String {
+    const VCRUNTIME_SYMBOLS: &[&str] = &[
+        "memcpy",
+        "memmove",
+        "memset",
+        "memcmp",
+        "__CxxFrameHandler3",
+        "__CxxFrameHandler4",
+        "__C_specific_handler",
+        "__std_terminate",
+        "_CxxThrowException",
+        "__security_check_cookie",
+        "__GSHandlerCheck",
+    ];
+    const UCRT_SYMBOLS: &[&str] = &[
+        "__stdio_common_",
+        "__acrt_iob_func",
+        "_errno",
+        "_invalid_parameter_noinfo",
+        "_invalid_parameter_noinfo_noreturn",
+        "malloc",
+        "calloc",
+        "realloc",
+        "free",
+        "strlen",
+        "abort",
+        "exit",
+    ];
+
+    let imported = symbol.starts_with("__imp_");
+    let name = symbol.trim_start_matches("__imp_");
+    // Names ending with `_` are prefixes of a family of functions
+    let matches = |names: &[&str]| {
+        names
+            .iter()
+            .any(|x| name == *x || (x.ends_with('_') && name.starts_with(x)))
+    };
+    if matches(VCRUNTIME_SYMBOLS) {
+        format!(
+            "`{symbol}` is provided by the Visual C++ runtime (vcruntime.lib), make sure the \
+             MSVC CRT libs were downloaded for the target architecture with `--xwin-arch`"
+        )
+    } else if matches(UCRT_SYMBOLS) {
+        format!(
+            "`{symbol}` is provided by the Universal CRT (ucrt.lib), make sure the ucrt libs \
+             were downloaded for the target architecture with `--xwin-arch`"
+        )
+    } else if imported {
+        format!(
+            "`{symbol}` is imported from a Windows system DLL, link its import library with \
+             `#[link(name = \"...\")]` or `cargo:rustc-link-lib` in a build script"
+        )
+    } else {
+        format!(
+            "`{symbol}` is undefined, make sure the library providing it is linked and was \
+             built for the same target architecture"
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_csi_and_osc_escapes() {
+        assert_eq!(
+            strip_ansi_escapes("\x1b[1m\x1b[31merror\x1b[0m: linking failed"),
+            "error: linking failed"
+        );
+        // Hyperlinks terminated by `ESC \` and by BEL
+        assert_eq!(
+            strip_ansi_escapes("see \x1b]8;;https://example.com\x1b\\the docs\x1b]8;;\x1b\\."),
+            "see the docs."
+        );
+        assert_eq!(
+            strip_ansi_escapes("\x1b]8;;file:///tmp/a.rs\x07a.rs\x1b]8;;\x07:1"),
+            "a.rs:1"
+        );
+        assert_eq!(strip_ansi_escapes("plain"), "plain");
+    }
+
+    #[test]
+    fn explain_colored_lld_output() {
+        let stderr = "\x1b[0m\x1b[1m\x1b[38;5;9merror\x1b[0m: linking with `lld-link` failed\n  \
+             = note: \x1b]8;;file:///tmp/lld-link\x1b\\lld-link\x1b]8;;\x1b\\: error: \
+             undefined symbol: __declspec(dllimport) MessageBoxW\x1b[0m\n";
+        let hints = explain_link_errors(&strip_ansi_escapes(stderr));
+        assert_eq!(hints.len(), 1);
+        assert!(hints[0].starts_with("`__imp_MessageBoxW` is imported from a Windows system DLL"));
+    }
+
+    #[test]
+    fn parse_undefined_symbols() {
+        assert_eq!(
+            undefined_symbol("lld-link: error: undefined symbol: memcpy").as_deref(),
+            Some("memcpy")
+        );
+        assert_eq!(
+            undefined_symbol(
+                "lld-link: error: undefined symbol: __declspec(dllimport) CreateFileW"
+            )
+            .as_deref(),
+            Some("__imp_CreateFileW")
+        );
+        assert_eq!(
+            undefined_symbol(
+                "foo.obj : error LNK2019: unresolved external symbol \"free\" referenced in main"
+            )
+            .as_deref(),
+            Some("free")
+        );
+        assert_eq!(
+            undefined_symbol("lld-link: error: could not open 'a.lib'"),
+            None
+        );
+    }
+
+    #[test]
+    fn explain_link_error_kinds() {
+        let hints = explain_link_errors(
+            "lld-link: error: undefined symbol: memset\n\
+             lld-link: error: undefined symbol: __stdio_common_vfprintf\n\
+             lld-link: error: could not open 'ws2_32.lib': No such file or directory\n\
+             lld-link: error: undefined symbol: memset\n",
+        );
+        assert_eq!(hints.len(), 3, "{hints:?}");
+        assert!(hints.iter().any(|hint| hint.contains("vcruntime.lib")));
+        assert!(hints.iter().any(|hint| hint.contains("ucrt.lib")));
+        assert!(hints
+            .iter()
+            .any(|hint| hint.starts_with("A library could not be found")));
+        assert!(explain_link_errors("warning: unused variable").is_empty());
+    }
+
+    #[test]
+    fn transient_link_errors() {
+        assert!(is_transient_link_error(
+            "error: linking failed\nlld-link: error: failed to open a.exe: Text file busy\n"
+        ));
+        assert!(!is_transient_link_error(
+            "lld-link: error: undefined symbol: memcpy\n"
+        ));
+        // Only errors reported by lld-link are retried
+        assert!(!is_transient_link_error("cc: Input/output error\n"));
+    }
+
+    #[test]
+    fn linked_binaries_from_artifact_messages() {
+        let message = |kind: &str, fresh: bool, filenames: &[&str]| {
+            serde_json::json!({
+                "reason": "compiler-artifact",
+                "target": { "kind": [kind] },
+                "filenames": filenames,
+                "fresh": fresh,
+            })
+        };
+        assert_eq!(
+            linked_binaries(&message(
+                "bin",
+                false,
+                &["/t/x86_64-pc-windows-msvc/debug/a b.exe"]
+            )),
+            [PathBuf::from("/t/x86_64-pc-windows-msvc/debug/a b.exe")]
+        );
+        assert_eq!(
+            linked_binaries(&message(
+                "cdylib",
+                false,
+                &[
+                    "/t/debug/hello.dll",
+                    "/t/debug/hello.dll.lib",
+                    "/t/debug/hello.pdb"
+                ]
+            )),
+            [PathBuf::from("/t/debug/hello.dll")]
+        );
+        assert!(linked_binaries(&message("bin", true, &["/t/debug/a.exe"])).is_empty());
+        assert!(
+            linked_binaries(&message("proc-macro", false, &["/t/debug/deps/m.dll"])).is_empty()
+        );
+        assert!(linked_binaries(&message(
+            "custom-build",
+            false,
+            &["/t/debug/build/build-script-build.exe"]
+        ))
+        .is_empty());
+        assert!(linked_binaries(&serde_json::json!({ "reason": "build-finished" })).is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+
+use crate::options::XWinOptions;
+
+/// External cargo subcommands that compile for the target and need the cross compilation environment,
+/// cargo-nextest has its own `Nextest` command
+const CROSS_SUBCOMMANDS: &[&str] = &["bench", "hack"];
+
+/// An external cargo subcommand, e.g. `cargo xwin bench`
+///
+/// Known subcommands that compile code get the same environment as `cargo xwin build`,
+/// configured from the `XWIN_*` environment variables, others are run with plain cargo.
+#[derive(Clone, Debug, Default)]
+pub struct External {
+    /// The subcommand followed by its arguments
+    pub args: Vec<OsString>,
+}
+
+impl External {
+    pub fn new(args: Vec<OsString>) -> Self {
+        Self { args }
+    }
+
+    /// Execute the external cargo subcommand
+    pub fn execute(&self) -> Result<()> {
+        let mut cmd = self.command()?;
+        let mut child = cmd.spawn().context("Failed to run cargo")?;
+        let status = child.wait().expect("Failed to wait on cargo process");
+        if !status.success() {
+            process::exit(status.code().unwrap_or(1));
+        }
+        Ok(())
+    }
+
+    /// Generate cargo subcommand
+    pub fn command(&self) -> Result<Command> {
+        let mut cmd = Command::new(env::var_os("CARGO").unwrap_or("cargo".into()));
+        cmd.env_remove("CARGO");
+        let needs_env = self
+            .args
+            .first()
+            .and_then(|subcommand| subcommand.to_str())
+            .is_some_and(|subcommand| CROSS_SUBCOMMANDS.contains(&subcommand));
+        if !needs_env {
+            cmd.args(&self.args);
+            return Ok(cmd);
+        }
+
+        // Arguments after `--` belong to the test binaries, keep them last since
+        // `--target` may be appended when no target is given
+        let split = self
+            .args
+            .iter()
+            .position(|arg| arg == "--")
+            .unwrap_or(self.args.len());
+        let (args, trailing) = self.args.split_at(split);
+        cmd.args(args);
+
+        let mut cargo = cargo_options::CommonOptions::default();
+        let mut manifest_path = None;
+        let mut args = args.iter().filter_map(|arg| arg.to_str());
+        while let Some(arg) = args.next() {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (arg, None),
+            };
+            if !matches!(name, "--target" | "--color" | "--manifest-path") {
+                continue;
+            }
+            let Some(value) = value.or_else(|| args.next().map(String::from)) else {
+                break;
+            };
+            match name {
+                "--target" => cargo.target.push(value),
+                "--color" => cargo.color = Some(value),
+                _ => manifest_path = Some(PathBuf::from(value)),
+            }
+        }
+        let xwin = XWinOptions::try_parse_from(["xwin"])?;
+        xwin.apply_command_env(manifest_path.as_deref(), &cargo, &mut cmd)?;
+        cmd.args(trailing);
+        Ok(cmd)
+    }
+}
@@ -1,10 +1,81 @@
+mod add;
+mod cache;
 mod compiler;
+mod dump_config;
+mod env;
+mod exec;
+mod external;
+mod lock;
 mod macros;
+mod nextest;
 mod options;
+mod provision;
+mod remove;
 mod run;
+mod sign;
+mod targets;
 mod test;
+mod tree;
+mod verify;
+mod wine;
 
-pub use macros::{build::Build, check::Check, clippy::Clippy, rustc::Rustc};
-pub use options::XWinOptions;
-pub use run::Run;
+pub use add::Add;
+pub use cache::{Cache, CacheSubcommand, CacheWindowsMsvcSysroot, CacheXwin};
+pub use dump_config::DumpConfig;
+pub use env::Env;
+pub use external::External;
+pub use lock::Lock;
+pub use macros::{build::Build, check::Check, clippy::Clippy, doc::Doc, rustc::Rustc};
+pub use nextest::Nextest;
+pub use options::{CacheLockedError, SdkInclude, XWinOptions};
+pub use provision::Provision;
+pub use remove::Remove;
+pub use run::{CwdRunner, Run};
+pub use sign::SignOptions;
+pub use targets::Targets;
 pub use test::Test;
+pub use tree::Tree;
+pub use verify::Verify;
+pub use wine::WineOptions;
+
+/// A cargo command run with the cross compilation environment set up
+///
+/// Downstream tools can construct a command type such as [`Build`] programmatically and either
+/// [`execute`](CrossCommand::execute) it or take the ready to run [`Command`](std::process::Command)
+/// from [`build_command`](CrossCommand::build_command) to run it themselves.
+///
+/// ```no_run
+/// use cargo_xwin::{Build, CrossCommand};
+///
+/// let mut build = Build::new(Some("Cargo.toml".into()));
+/// build.target = vec!["x86_64-pc-windows-msvc".to_string()];
+/// let mut cmd = build.build_command()?;
+/// let status = cmd.status()?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub trait CrossCommand {
+    /// Returns the cargo command with the cross compilation environment applied,
+    /// downloading the toolchain if needed
+    fn build_command(&self) -> anyhow::Result<std::process::Command>;
+
+    /// Runs the command, exiting the process with cargo's exit code if it fails
+    fn execute(&self) -> anyhow::Result<()>;
+}
+
+macro_rules! impl_cross_command {
+    ($($command: ty),*) => {
+        $(
+            impl CrossCommand for $command {
+                fn build_command(&self) -> anyhow::Result<std::process::Command> {
+                    <$command>::build_command(self)
+                }
+
+                fn execute(&self) -> anyhow::Result<()> {
+                    <$command>::execute(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_cross_command!(Build, Check, Clippy, Doc, Rustc, Run, Test, Nextest);
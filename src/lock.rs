@@ -0,0 +1,161 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use fs_err as fs;
+use serde::{Deserialize, Serialize};
+
+use crate::compiler::clang::Clang;
+use crate::compiler::clang_cl::ClangCl;
+use crate::compiler::common::{decorate, use_color};
+use crate::options::{ClangSysroot, CrossCompiler, XWinOptions};
+
+/// Name of the toolchain lockfile written by `cargo xwin lock`
+pub(crate) const LOCKFILE_NAME: &str = "xwin.lock";
+
+/// The resolved versions and checksums of the cross compilation toolchain
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ToolchainLock {
+    /// MSVC CRT and Windows SDK splatted by xwin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crt: Option<CrtLock>,
+    /// windows-msvc-sysroot used by the clang backend
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msvc_sysroot: Option<SysrootLock>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CrtLock {
+    /// The Visual Studio manifest version, see `--xwin-version`
+    pub manifest_version: String,
+    pub crt_version: String,
+    pub sdk_version: String,
+    pub payloads: Vec<PayloadLock>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PayloadLock {
+    pub filename: String,
+    pub url: String,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SysrootLock {
+    pub url: String,
+    pub sha256: String,
+}
+
+impl ToolchainLock {
+    /// Loads the lockfile from `dir` or the closest parent directory containing one
+    pub(crate) fn find(dir: &Path) -> Result<Self> {
+        let Some(path) = dir
+            .ancestors()
+            .map(|dir| dir.join(LOCKFILE_NAME))
+            .find(|path| path.is_file())
+        else {
+            bail!(
+                "Failed to find {LOCKFILE_NAME} in {} or its parent directories, run `cargo xwin lock` to create it",
+                dir.display()
+            );
+        };
+        let content = fs::read_to_string(&path)?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    pub(crate) fn crt(&self) -> Result<&CrtLock> {
+        self.crt.as_ref().with_context(|| {
+            format!("{LOCKFILE_NAME} doesn't contain the MSVC CRT, run `cargo xwin lock` with the same cross compiler")
+        })
+    }
+
+    pub(crate) fn msvc_sysroot(&self) -> Result<&SysrootLock> {
+        self.msvc_sysroot.as_ref().with_context(|| {
+            format!("{LOCKFILE_NAME} doesn't contain the MSVC sysroot, run `cargo xwin lock` with the same cross compiler")
+        })
+    }
+
+    /// Loads the lockfile when builds are locked with `--xwin-locked`
+    pub(crate) fn load_if_locked(xwin: &XWinOptions, workdir: &Path) -> Result<Option<Self>> {
+        if xwin.xwin_locked {
+            Self::find(workdir).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl CrtLock {
+    pub(crate) fn new(manifest_version: &str, pruned: &xwin::PrunedPackageList) -> Self {
+        let mut payloads: Vec<_> = pruned
+            .payloads
+            .iter()
+            .map(|payload| PayloadLock {
+                filename: payload.filename.to_string(),
+                url: payload.url.clone(),
+                sha256: payload.sha256.to_string(),
+            })
+            .collect();
+        payloads.sort_by(|a, b| a.filename.cmp(&b.filename));
+        Self {
+            manifest_version: manifest_version.to_string(),
+            crt_version: pruned.crt_version.clone(),
+            sdk_version: pruned.sdk_version.clone(),
+            payloads,
+        }
+    }
+}
+
+/// Resolve the MSVC CRT, Windows SDK and sysroot versions and checksums and write them to `xwin.lock`
+///
+/// Builds with `--xwin-locked` then download exactly these versions and fail if the checksums
+/// don't match.
+#[derive(Clone, Debug, Default, Parser)]
+#[command(display_order = 1)]
+pub struct Lock {
+    #[command(flatten)]
+    pub xwin: XWinOptions,
+
+    /// Path to Cargo.toml, the lockfile is written next to it
+    #[arg(long, value_name = "PATH")]
+    pub manifest_path: Option<PathBuf>,
+
+    /// Coloring: auto, always, never
+    #[arg(long, value_name = "WHEN")]
+    pub color: Option<String>,
+}
+
+impl Lock {
+    /// Execute `cargo xwin lock` command
+    pub fn execute(&self) -> Result<()> {
+        let color = use_color(self.color.as_deref());
+        let dir = match self
+            .manifest_path
+            .as_deref()
+            .and_then(|p| p.parent())
+            .filter(|p| !p.as_os_str().is_empty())
+        {
+            Some(dir) => dir.to_path_buf(),
+            None => env::current_dir()?,
+        };
+        let mut lock = ToolchainLock::default();
+        match (self.xwin.resolve_cross_compiler()?, self.xwin.clang_sysroot) {
+            (CrossCompiler::Clang, ClangSysroot::WindowsMsvcSysroot) => {
+                lock.msvc_sysroot = Some(Clang::new(&self.xwin).resolve_msvc_sysroot_lock(color)?);
+            }
+            _ => {
+                let xwin_cache_dir = self.xwin.xwin_cache_dir()?;
+                lock.crt = Some(ClangCl::new(&self.xwin).resolve_crt_lock(xwin_cache_dir, color)?);
+            }
+        }
+        let path = dir.join(LOCKFILE_NAME);
+        fs::write(&path, serde_json::to_string_pretty(&lock)? + "\n")?;
+        eprintln!(
+            "{}",
+            decorate(color, "🔒", &format!("Wrote {}", path.display()))
+        );
+        Ok(())
+    }
+}
@@ -6,11 +6,14 @@ macro_rules! cargo_command {
             pub mod [<$command:lower>] {
                 use std::ops::{Deref, DerefMut};
                 use std::path::PathBuf;
-                use std::process::{self, Command};
+                use std::process::Command;
 
-                use anyhow::{Context, Result};
+                use anyhow::Result;
                 use clap::Parser;
 
+                use crate::compiler::common::split_targets;
+                use crate::exec::{artifact_messages, execute_cargo, run_post_build};
+                use crate::macros::Links as _;
                 use crate::options::XWinOptions;
 
                 #[derive(Clone, Debug, Default, Parser)]
@@ -39,19 +42,32 @@ macro_rules! cargo_command {
                     /// Execute cargo command
                     pub fn execute(&self) -> Result<()> {
                         let current_command = stringify!([<$command:lower>]);
-                        let mut build = self.build_command()?;
-                        let mut child = build.spawn().with_context(|| format!("Failed to run cargo {current_command}"))?;
-                        let status = child.wait().expect(&format!("Failed to wait on cargo {current_command} process"));
-                        if !status.success() {
-                            process::exit(status.code().unwrap_or(1));
-                        }
-                        Ok(())
+                        let mut this = self.clone();
+                        let artifacts =
+                            artifact_messages(&self.xwin, &mut this.cargo.common.message_format)?;
+                        let build = this.build_command()?;
+                        let binaries = execute_cargo(
+                            build,
+                            current_command,
+                            &self.xwin,
+                            &self.cargo.common,
+                            artifacts,
+                        )?;
+                        run_post_build(&self.xwin, &binaries)
                     }
 
                     /// Generate cargo subcommand
                     pub fn build_command(&self) -> Result<Command> {
-                        let mut build = self.cargo.command();
-                        self.xwin.apply_command_env(
+                        let mut cargo = self.cargo.clone();
+                        // `CARGO_BUILD_TARGET` may contain multiple comma or space separated targets
+                        cargo.common.target = split_targets(&cargo.common.target);
+                        let mut build = cargo.command();
+                        let xwin = if cargo.links() {
+                            self.xwin.clone()
+                        } else {
+                            self.xwin.without_link_options()
+                        };
+                        xwin.apply_command_env(
                             self.manifest_path.as_deref(),
                             &self.cargo.common,
                             &mut build,
@@ -88,7 +104,55 @@ macro_rules! cargo_command {
     };
 }
 
+/// Whether a cargo command may link binaries, link-only options are skipped otherwise
+trait Links {
+    fn links(&self) -> bool {
+        true
+    }
+}
+
+impl Links for cargo_options::Build {}
+
+/// `check`, `clippy` and `doc` only link build scripts and proc macros, which are built for the host
+impl Links for cargo_options::Check {
+    fn links(&self) -> bool {
+        false
+    }
+}
+
+impl Links for cargo_options::Clippy {
+    fn links(&self) -> bool {
+        false
+    }
+}
+
+impl Links for cargo_options::Doc {
+    fn links(&self) -> bool {
+        false
+    }
+}
+
+impl Links for cargo_options::Rustc {
+    /// `--emit` modes without `link`, such as `obj` or `llvm-ir`, stop before linking
+    fn links(&self) -> bool {
+        let mut emit_kinds = Vec::new();
+        let mut args = self.args.iter();
+        while let Some(arg) = args.next() {
+            if let Some(kinds) = arg.strip_prefix("--emit=") {
+                emit_kinds.extend(kinds.split(','));
+            } else if arg == "--emit" {
+                emit_kinds.extend(args.next().into_iter().flat_map(|kinds| kinds.split(',')));
+            }
+        }
+        emit_kinds.is_empty()
+            || emit_kinds
+                .iter()
+                .any(|kind| kind.split_once('=').map_or(*kind, |(kind, _)| kind) == "link")
+    }
+}
+
 cargo_command!(Build);
 cargo_command!(Check);
 cargo_command!(Clippy);
+cargo_command!(Doc);
 cargo_command!(Rustc);
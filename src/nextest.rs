@@ -0,0 +1,124 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Result};
+use clap::{ArgAction, Parser};
+
+use crate::compiler::common::split_targets;
+use crate::exec::execute_cargo;
+use crate::options::XWinOptions;
+use crate::wine::{setup_wine_env, wine_runner_configs, WineOptions};
+
+/// Run tests with cargo-nextest
+///
+/// Test binaries are run with wine on non-Windows hosts, like `cargo xwin test`.
+#[derive(Clone, Debug, Default, Parser)]
+#[command(
+    display_order = 1,
+    after_help = "Run `cargo nextest run --help` for more detailed information."
+)]
+pub struct Nextest {
+    #[command(flatten)]
+    pub xwin: XWinOptions,
+
+    #[command(flatten)]
+    pub wine: WineOptions,
+
+    /// The nextest subcommand to run
+    #[arg(default_value = "run", value_parser = ["run", "list", "archive"])]
+    pub subcommand: String,
+
+    /// Path to Cargo.toml
+    #[arg(long, value_name = "PATH")]
+    pub manifest_path: Option<PathBuf>,
+
+    /// Build for the target triple
+    #[arg(long, value_name = "TRIPLE", env = "CARGO_BUILD_TARGET", action = ArgAction::Append)]
+    pub target: Vec<String>,
+
+    /// Directory for all generated artifacts
+    #[arg(long, value_name = "DIRECTORY")]
+    pub target_dir: Option<PathBuf>,
+
+    /// Build artifacts in release mode, with optimizations
+    #[arg(short = 'r', long)]
+    pub release: bool,
+
+    /// Build artifacts with the specified Cargo profile
+    #[arg(long, value_name = "PROFILE-NAME")]
+    pub cargo_profile: Option<String>,
+
+    /// Other arguments passed to cargo-nextest
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<String>,
+}
+
+impl Nextest {
+    /// Execute `cargo nextest` command
+    pub fn execute(&self) -> Result<()> {
+        let cargo = self.common_options();
+        let mut nextest = self.build_command()?;
+        setup_wine_env(
+            &mut nextest,
+            self.manifest_path.as_deref(),
+            &cargo,
+            self.release,
+            &self.wine,
+        )?;
+        execute_cargo(nextest, "nextest", &self.xwin, &cargo, None)?;
+        Ok(())
+    }
+
+    /// Generate cargo subcommand
+    pub fn build_command(&self) -> Result<Command> {
+        let cargo_bin = env::var_os("CARGO").unwrap_or("cargo".into());
+        let installed = Command::new(&cargo_bin)
+            .args(["nextest", "--version"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if !installed {
+            bail!(
+                "cargo-nextest is not installed, install it with `cargo install cargo-nextest --locked`"
+            );
+        }
+
+        let cargo = self.common_options();
+        let mut cmd = Command::new(cargo_bin);
+        cmd.env_remove("CARGO").arg("nextest").arg(&self.subcommand);
+        if let Some(manifest_path) = &self.manifest_path {
+            cmd.arg("--manifest-path").arg(manifest_path);
+        }
+        for target in &cargo.target {
+            cmd.arg("--target").arg(target);
+        }
+        if let Some(target_dir) = &self.target_dir {
+            cmd.arg("--target-dir").arg(target_dir);
+        }
+        if self.release {
+            cmd.arg("--release");
+        }
+        if let Some(profile) = &self.cargo_profile {
+            cmd.arg("--cargo-profile").arg(profile);
+        }
+        for config in wine_runner_configs(self.manifest_path.as_deref(), &cargo, &self.wine)? {
+            cmd.arg("--config").arg(config);
+        }
+        self.xwin
+            .apply_command_env(self.manifest_path.as_deref(), &cargo, &mut cmd)?;
+        cmd.args(&self.args);
+        Ok(cmd)
+    }
+
+    /// The cargo options understood by the cross compilation and wine setup
+    fn common_options(&self) -> cargo_options::CommonOptions {
+        cargo_options::CommonOptions {
+            target: split_targets(&self.target),
+            target_dir: self.target_dir.clone(),
+            profile: self.cargo_profile.clone(),
+            ..Default::default()
+        }
+    }
+}
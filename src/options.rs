@@ -1,34 +1,165 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{self, IsTerminal};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::{
     builder::{PossibleValuesParser, TypedValueParser as _},
     Parser, ValueEnum,
 };
 use fs_err as fs;
+use path_slash::PathExt;
+use serde::{Serialize, Serializer};
+
+use crate::sign::SignOptions;
+
+/// The license of the MSVC CRT and Windows SDK
+const LICENSE_URL: &str = "https://go.microsoft.com/fwlink/?LinkId=2086102";
 
 /// MSVC cross compiler
-#[derive(Clone, Debug, Default, ValueEnum)]
+#[derive(Clone, Debug, Default, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
 pub enum CrossCompiler {
     /// clang-cl backend
     #[default]
     ClangCl,
     /// clang backend
     Clang,
+    /// zig cc backend
+    ZigCc,
+    /// MSVC of a local Visual Studio installation, only on Windows hosts
+    Msvc,
+    /// msvc on Windows hosts with Visual Studio, else clang-cl if the xwin CRT is cached,
+    /// else clang if the prebuilt sysroot is cached, else clang-cl
+    Auto,
+}
+
+/// Windows SDK and CRT used by the clang backend
+#[derive(Clone, Copy, Debug, Default, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClangSysroot {
+    /// prebuilt windows-msvc-sysroot
+    #[default]
+    WindowsMsvcSysroot,
+    /// MSVC CRT and Windows SDK splatted by xwin, shared with the clang-cl backend
+    Xwin,
+}
+
+/// Payloads of the MSVC CRT and Windows SDK that can be left out of the cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum XWinExclude {
+    /// CRT and SDK headers, only needed to compile C/C++ code
+    Headers,
+    /// Windows Store (UWP) libraries of the SDK
+    StoreLibs,
+}
+
+impl XWinExclude {
+    /// Whether payloads of `kind` are left out
+    pub(crate) fn matches(self, kind: xwin::PayloadKind) -> bool {
+        match self {
+            XWinExclude::Headers => matches!(
+                kind,
+                xwin::PayloadKind::CrtHeaders
+                    | xwin::PayloadKind::SdkHeaders
+                    | xwin::PayloadKind::AtlHeaders
+            ),
+            XWinExclude::StoreLibs => kind == xwin::PayloadKind::SdkStoreLibs,
+        }
+    }
 }
 
+/// Include directories whose search order can be changed with `--sdk-include-order`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SdkInclude {
+    /// `--extra-include` directories
+    Extra,
+    /// MSVC CRT and STL headers in `crt/include`
+    Crt,
+    /// Universal CRT headers in `sdk/include/ucrt`
+    Ucrt,
+    /// Win32 API headers in `sdk/include/um`
+    Um,
+    /// Headers shared by user and kernel mode in `sdk/include/shared`
+    Shared,
+}
+
+impl SdkInclude {
+    /// The default search order
+    pub(crate) const DEFAULT_ORDER: [SdkInclude; 5] = [
+        SdkInclude::Extra,
+        SdkInclude::Crt,
+        SdkInclude::Ucrt,
+        SdkInclude::Um,
+        SdkInclude::Shared,
+    ];
+}
+
+/// Returned when `--cache-locked` forbids downloading a missing or outdated toolchain
+#[derive(Debug)]
+pub struct CacheLockedError {
+    what: &'static str,
+    dir: PathBuf,
+}
+
+impl CacheLockedError {
+    /// The exit code of cargo-xwin for this error
+    pub const EXIT_CODE: i32 = 3;
+}
+
+impl fmt::Display for CacheLockedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in {} is missing or outdated and `--cache-locked` forbids downloading it",
+            self.what,
+            self.dir.display()
+        )
+    }
+}
+
+impl std::error::Error for CacheLockedError {}
+
 /// common xwin options
-#[derive(Clone, Debug, Parser)]
+#[derive(Clone, Debug, Parser, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub struct XWinOptions {
     /// The cross compiler to use
     #[arg(long, env = "XWIN_CROSS_COMPILER", default_value = "clang-cl")]
     pub cross_compiler: CrossCompiler,
 
+    /// The Windows SDK and CRT to use with the clang backend
+    #[arg(
+        long,
+        env = "XWIN_CLANG_SYSROOT",
+        default_value = "windows-msvc-sysroot"
+    )]
+    pub clang_sysroot: ClangSysroot,
+
     /// xwin cache directory
     #[arg(long, env = "XWIN_CACHE_DIR", hide = true)]
+    #[serde(rename = "cache-dir")]
     pub xwin_cache_dir: Option<PathBuf>,
 
+    /// Use `xwin-cache` in the target directory of the workspace as the xwin cache directory
+    /// instead of the user cache, unless `XWIN_CACHE_DIR` is set
+    #[arg(long, env = "XWIN_PROJECT_CACHE", value_parser = env_flag())]
+    pub project_cache: bool,
+
+    /// Cache directory of the MSVC CRT and Windows SDK splatted by xwin, defaults to the xwin cache directory
+    #[arg(long, env = "XWIN_CRT_CACHE_DIR", hide = true)]
+    pub crt_cache_dir: Option<PathBuf>,
+
+    /// Cache directory of the prebuilt MSVC sysroot used by the clang backend,
+    /// defaults to the xwin cache directory
+    #[arg(long, env = "XWIN_SYSROOT_CACHE_DIR", hide = true)]
+    pub sysroot_cache_dir: Option<PathBuf>,
+
     /// The architectures to include in CRT/SDK
     #[arg(
         long,
@@ -39,6 +170,7 @@ pub struct XWinOptions {
         default_values_t = vec![xwin::Arch::X86_64, xwin::Arch::Aarch64],
         hide = true,
     )]
+    #[serde(serialize_with = "serialize_display_seq")]
     pub xwin_arch: Vec<xwin::Arch>,
 
     /// The variants to include
@@ -51,6 +183,7 @@ pub struct XWinOptions {
         default_values_t = vec![xwin::Variant::Desktop],
         hide = true,
     )]
+    #[serde(serialize_with = "serialize_display_seq")]
     pub xwin_variant: Vec<xwin::Variant>,
 
     /// The version to retrieve, can either be a major version of 15, 16 or 17, or
@@ -58,25 +191,324 @@ pub struct XWinOptions {
     #[arg(long, env = "XWIN_VERSION", default_value = "16", hide = true)]
     pub xwin_version: String,
 
+    /// The Windows SDK version to download, defaults to the latest one of the manifest
+    #[arg(long, env = "XWIN_SDK_VERSION", hide = true)]
+    pub xwin_sdk_version: Option<String>,
+
+    /// Fail instead of warning when the cached toolchain doesn't match the requested versions
+    #[arg(long, env = "XWIN_STRICT", value_parser = env_flag())]
+    pub strict: bool,
+
+    #[command(flatten)]
+    #[serde(flatten)]
+    pub sign: SignOptions,
+
+    /// Run a command for each executable and DLL linked by a successful build,
+    /// `{bin}` is replaced with the path of the binary
+    #[arg(long, env = "XWIN_POST_BUILD_EXEC", value_name = "COMMAND")]
+    pub post_build_exec: Option<String>,
+
+    /// Fail instead of downloading when the cached toolchain is missing or outdated
+    #[arg(long, env = "XWIN_CACHE_LOCKED", value_parser = env_flag())]
+    pub cache_locked: bool,
+
+    /// Accept the Visual Studio license of the downloaded MSVC CRT and Windows SDK
+    #[arg(
+        long,
+        env = "XWIN_ACCEPT_LICENSE",
+        value_parser = env_flag()
+    )]
+    pub accept_license: bool,
+
     /// Whether or not to include debug libs
-    #[arg(long, env = "XWIN_INCLUDE_DEBUG_LIBS", hide = true)]
+    #[arg(long, env = "XWIN_INCLUDE_DEBUG_LIBS", hide = true, value_parser = env_flag())]
     pub xwin_include_debug_libs: bool,
 
     /// Whether or not to include debug symbols (PDBs)
-    #[arg(long, env = "XWIN_INCLUDE_DEBUG_SYMBOLS", hide = true)]
+    #[arg(long, env = "XWIN_INCLUDE_DEBUG_SYMBOLS", hide = true, value_parser = env_flag())]
     pub xwin_include_debug_symbols: bool,
+
+    /// Fail instead of falling back to a previously resolved or pinned MSVC sysroot
+    /// when the latest release can't be resolved, only used by the clang backend
+    #[arg(long, env = "XWIN_NO_SYSROOT_FALLBACK", hide = true, value_parser = env_flag())]
+    pub xwin_no_sysroot_fallback: bool,
+
+    /// The GitHub repository to download the MSVC sysroot releases from, in `owner/name` form,
+    /// only used by the clang backend
+    #[arg(
+        long,
+        env = "XWIN_SYSROOT_REPO",
+        value_name = "OWNER/NAME",
+        hide = true
+    )]
+    pub sysroot_repo: Option<String>,
+
+    /// Download exactly the toolchain recorded in `xwin.lock` by `cargo xwin lock`,
+    /// failing if the versions or checksums don't match
+    #[arg(long, env = "XWIN_LOCKED", value_parser = env_flag())]
+    pub xwin_locked: bool,
+
+    /// An xwin splat map file to remap or override where the MSVC CRT and Windows SDK files are placed
+    #[arg(long, env = "XWIN_SPLAT_MAP", value_name = "FILE")]
+    pub xwin_splat_map: Option<PathBuf>,
+
+    /// Payloads to leave out of the MSVC CRT and Windows SDK to reduce the download size
+    #[arg(
+        long,
+        env = "XWIN_EXCLUDE",
+        value_enum,
+        value_delimiter = ',',
+        value_name = "PAYLOAD"
+    )]
+    pub xwin_exclude: Vec<XWinExclude>,
+
+    /// Keep the `dl` and `unpack` directories of the splat for debugging instead of removing them
+    #[arg(long, env = "XWIN_KEEP_TEMP", hide = true, value_parser = env_flag())]
+    pub keep_temp: bool,
+
+    /// The number of payloads to download in parallel when splatting the CRT and SDK,
+    /// defaults to the number of CPUs
+    #[arg(long, env = "XWIN_DOWNLOAD_JOBS", value_name = "N", hide = true)]
+    pub download_jobs: Option<usize>,
+
+    /// Fail before downloading when the MSVC CRT and Windows SDK payloads add up to more than SIZE,
+    /// in bytes or with a `K`, `M` or `G` suffix
+    #[arg(long, env = "XWIN_MAX_DOWNLOAD_SIZE", value_name = "SIZE", value_parser = parse_size)]
+    pub max_download_size: Option<u64>,
+
+    /// Seconds to wait for connecting and for every network read or write while downloading
+    /// the toolchain, defaults to no limit once connected
+    #[arg(long, env = "XWIN_DOWNLOAD_TIMEOUT", value_name = "SECS")]
+    pub download_timeout: Option<u64>,
+
+    /// Only connect over IPv4 when downloading, for networks with broken IPv6 routes
+    #[arg(long, env = "XWIN_PREFER_IPV4", value_parser = env_flag())]
+    pub prefer_ipv4: bool,
+
+    /// Skip TLS certificate verification when downloading, only for internal mirrors
+    /// with self-signed certificates. Never use this on untrusted networks!
+    #[arg(long, env = "XWIN_ALLOW_INSECURE_DOWNLOAD", value_parser = env_flag())]
+    pub allow_insecure_download: bool,
+
+    /// Hide the download progress bars but keep the download status messages, e.g. for CI logs
+    #[arg(long, env = "XWIN_NO_PROGRESS", value_parser = env_flag())]
+    pub no_progress: bool,
+
+    /// Write the generated CMake toolchain files to DIR instead of the cache directory,
+    /// e.g. when the cache is read-only
+    #[arg(long, env = "XWIN_CMAKE_DIR", value_name = "DIR")]
+    pub cmake_dir: Option<PathBuf>,
+
+    /// The CMake generator for the cmake crate, requires `ninja` by default
+    #[arg(
+        long,
+        env = "XWIN_CMAKE_GENERATOR",
+        value_name = "GENERATOR",
+        default_value = "Ninja"
+    )]
+    pub cmake_generator: String,
+
+    /// Set `CMAKE_SYSTEM_VERSION` in the CMake toolchain files, e.g. `10.0.19041.0`,
+    /// for C dependencies that branch on the targeted Windows version
+    #[arg(
+        long,
+        env = "XWIN_CMAKE_SYSTEM_VERSION",
+        value_name = "VERSION",
+        value_parser = parse_system_version
+    )]
+    pub cmake_system_version: Option<String>,
+
+    /// Skip setting up the CMake toolchain and environment variables
+    #[arg(long, env = "XWIN_NO_CMAKE", value_parser = env_flag())]
+    pub no_cmake: bool,
+
+    /// Print hints for common link errors when the cargo command fails,
+    /// requires capturing the stderr of cargo
+    #[arg(long, env = "XWIN_EXPLAIN_ERRORS", value_parser = env_flag())]
+    pub explain_errors: bool,
+
+    /// Run the cargo command once more when linking failed with a transient lld-link I/O error,
+    /// requires capturing the stderr of cargo
+    #[arg(long, env = "XWIN_RETRY_ON_LINK_ERROR", value_parser = env_flag())]
+    pub retry_on_link_error: bool,
+
+    /// Print a JSON line with the cargo exit code and the last LINES lines of its stderr
+    /// when the cargo command fails, requires capturing the stderr of cargo
+    #[arg(
+        long,
+        env = "XWIN_FAILURE_SUMMARY",
+        value_name = "LINES",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "20"
+    )]
+    pub failure_summary: Option<usize>,
+
+    /// The C compiler to use for the target, defaults to the cross compiler
+    #[arg(long, env = "XWIN_CC", value_name = "COMPILER")]
+    pub cc: Option<String>,
+
+    /// The C++ compiler to use for the target, defaults to the cross compiler
+    #[arg(long, env = "XWIN_CXX", value_name = "COMPILER")]
+    pub cxx: Option<String>,
+
+    /// Extra include directories searched before the CRT and SDK headers, for headers of
+    /// third-party SDKs such as the Windows Driver Kit
+    #[arg(
+        long,
+        env = "XWIN_EXTRA_INCLUDE",
+        value_name = "DIR",
+        value_delimiter = ','
+    )]
+    pub extra_include: Vec<PathBuf>,
+
+    /// Extra library search directories for the target, for prebuilt third-party `.lib` files
+    #[arg(
+        long,
+        env = "XWIN_EXTRA_LIB_DIR",
+        value_name = "DIR",
+        value_delimiter = ','
+    )]
+    pub extra_lib_dir: Vec<PathBuf>,
+
+    /// Instrument the build for profile-guided optimization, writing the profiles to DIR
+    #[arg(
+        long,
+        env = "XWIN_PGO_GENERATE",
+        value_name = "DIR",
+        conflicts_with = "pgo_use"
+    )]
+    pub pgo_generate: Option<PathBuf>,
+
+    /// Optimize the build with a merged `.profdata` profile
+    #[arg(long, env = "XWIN_PGO_USE", value_name = "FILE")]
+    pub pgo_use: Option<PathBuf>,
+
+    /// Write a linker map file, next to the linked binary by default
+    #[arg(
+        long,
+        env = "XWIN_EMIT_LINK_MAP",
+        value_name = "PATH",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    pub emit_link_map: Option<Option<PathBuf>>,
+
+    /// Link the C runtime statically, also compiling C/C++ code with `/MT`
+    #[arg(long, env = "XWIN_STATIC_CRT", value_parser = env_flag())]
+    pub static_crt: bool,
+
+    /// Enable Control Flow Guard for Rust and C/C++ code
+    #[arg(long, env = "XWIN_CONTROL_FLOW_GUARD", value_parser = env_flag())]
+    pub control_flow_guard: bool,
+
+    /// Make the linked binaries reproducible, they're timestamped with `SOURCE_DATE_EPOCH` if set
+    #[arg(long, env = "XWIN_REPRODUCIBLE", value_parser = env_flag())]
+    pub reproducible: bool,
+
+    /// Keep the lld-link ThinLTO cache in DIR to speed up incremental LTO builds,
+    /// only used when the rustflags enable `-C linker-plugin-lto` or `-C lto`
+    #[arg(long, env = "XWIN_LTO_CACHE_DIR", value_name = "DIR")]
+    pub lto_cache_dir: Option<PathBuf>,
+
+    /// The search order of the include directories, unlisted ones follow in the default order,
+    /// zig and the windows-msvc-sysroot of the clang backend reject orders they can't honor
+    #[arg(
+        long,
+        env = "XWIN_SDK_INCLUDE_ORDER",
+        value_name = "DIRS",
+        value_delimiter = ',',
+        default_value = "extra,crt,ucrt,um,shared"
+    )]
+    pub sdk_include_order: Vec<SdkInclude>,
+
+    /// The clang resource directory to find the sanitizer and profiling runtime libraries in,
+    /// defaults to the output of `clang -print-resource-dir`
+    #[arg(long, env = "XWIN_CLANG_RESOURCE_DIR", value_name = "DIR")]
+    pub clang_resource_dir: Option<PathBuf>,
+
+    /// Extra rustflags appended after the linker flags set up by cargo-xwin, separated by spaces
+    #[arg(
+        long,
+        env = "XWIN_RUSTFLAGS",
+        value_name = "FLAGS",
+        allow_hyphen_values = true
+    )]
+    pub xwin_rustflags: Option<String>,
+
+    /// Read `KEY=VALUE` lines, e.g. `CFLAGS_x86_64_pc_windows_msvc=...`, from this file and set them
+    /// after the variables of cargo-xwin, `*FLAGS*` values are appended to the ones already set
+    #[arg(long, env = "XWIN_TARGET_ENV_FILE", value_name = "PATH")]
+    pub target_env_file: Option<PathBuf>,
+
+    /// Reference the rustc provided LLVM tools by absolute path instead of symlinking them into
+    /// the cache directory, for read-only caches, the `clang-cl` symlink and zig wrappers are
+    /// written to `<target-dir>/xwin-tools` and the CMake toolchain files to
+    /// `<target-dir>/xwin-cmake` unless `--cmake-dir` is set
+    #[arg(long, env = "XWIN_NO_SYMLINK_TOOLS", value_parser = env_flag())]
+    pub no_symlink_tools: bool,
+
+    /// Use the absolute path of lld-link as the target linker instead of relying on PATH
+    #[arg(long, env = "XWIN_ABSOLUTE_LINKER", value_parser = env_flag())]
+    pub absolute_linker: bool,
 }
 
 impl Default for XWinOptions {
     fn default() -> Self {
         Self {
             xwin_cache_dir: None,
+            project_cache: false,
+            crt_cache_dir: None,
+            sysroot_cache_dir: None,
             xwin_arch: vec![xwin::Arch::X86_64, xwin::Arch::Aarch64],
             xwin_variant: vec![xwin::Variant::Desktop],
             xwin_version: "16".to_string(),
+            xwin_sdk_version: None,
+            strict: false,
+            sign: SignOptions::default(),
+            post_build_exec: None,
+            cache_locked: false,
+            accept_license: false,
+            xwin_splat_map: None,
             xwin_include_debug_libs: false,
             xwin_include_debug_symbols: false,
+            xwin_no_sysroot_fallback: false,
+            sysroot_repo: None,
+            xwin_locked: false,
+            xwin_exclude: Vec::new(),
+            keep_temp: false,
+            download_jobs: None,
+            download_timeout: None,
+            max_download_size: None,
+            prefer_ipv4: false,
+            allow_insecure_download: false,
+            no_progress: false,
+            cmake_dir: None,
+            cmake_generator: "Ninja".to_string(),
+            cmake_system_version: None,
+            no_cmake: false,
+            explain_errors: false,
+            retry_on_link_error: false,
+            failure_summary: None,
             cross_compiler: CrossCompiler::ClangCl,
+            clang_sysroot: ClangSysroot::WindowsMsvcSysroot,
+            cc: None,
+            cxx: None,
+            absolute_linker: false,
+            no_symlink_tools: false,
+            xwin_rustflags: None,
+            target_env_file: None,
+            extra_include: Vec::new(),
+            extra_lib_dir: Vec::new(),
+            emit_link_map: None,
+            static_crt: false,
+            control_flow_guard: false,
+            reproducible: false,
+            lto_cache_dir: None,
+            pgo_generate: None,
+            pgo_use: None,
+            sdk_include_order: SdkInclude::DEFAULT_ORDER.to_vec(),
+            clang_resource_dir: None,
         }
     }
 }
@@ -88,25 +520,856 @@ impl XWinOptions {
         cargo: &cargo_options::CommonOptions,
         cmd: &mut Command,
     ) -> Result<()> {
-        let cache_dir = {
-            let cache_dir = self.xwin_cache_dir.clone().unwrap_or_else(|| {
-                dirs::cache_dir()
-                    .unwrap_or_else(|| std::env::current_dir().expect("Failed to get current dir"))
-                    .join(env!("CARGO_PKG_NAME"))
-            });
-            fs::create_dir_all(&cache_dir)?;
-            cache_dir.canonicalize()?
-        };
-        match self.cross_compiler {
+        if self.project_cache && self.xwin_cache_dir.is_none() {
+            let mut xwin = self.clone();
+            xwin.xwin_cache_dir = Some(project_cache_dir(manifest_path, cargo)?);
+            return xwin.apply_command_env(manifest_path, cargo, cmd);
+        }
+        if self.no_symlink_tools && self.cmake_dir.is_none() && !self.no_cmake {
+            // The cache directory may be read-only, write the CMake toolchain files to the
+            // target directory instead
+            let mut xwin = self.clone();
+            let target_dir = crate::compiler::common::resolve_target_dir(manifest_path, cargo)?;
+            xwin.cmake_dir = Some(target_dir.join("xwin-cmake"));
+            return xwin.apply_command_env(manifest_path, cargo, cmd);
+        }
+        let cache_dir = self.cache_dir()?;
+        match self.resolve_cross_compiler()? {
             CrossCompiler::ClangCl => {
                 let clang_cl = crate::compiler::clang_cl::ClangCl::new(self);
                 clang_cl.apply_command_env(manifest_path, cargo, cache_dir, cmd)?;
             }
             CrossCompiler::Clang => {
-                let clang = crate::compiler::clang::Clang::new();
+                let clang = crate::compiler::clang::Clang::new(self);
                 clang.apply_command_env(manifest_path, cargo, cache_dir, cmd)?;
             }
+            CrossCompiler::ZigCc => {
+                let zig = crate::compiler::zig::ZigCc::new(self);
+                zig.apply_command_env(manifest_path, cargo, cache_dir, cmd)?;
+            }
+            CrossCompiler::Msvc => {
+                let msvc = crate::compiler::msvc::Msvc::new(self);
+                msvc.apply_command_env(manifest_path, cargo, cmd)?;
+            }
+            CrossCompiler::Auto => unreachable!("auto is resolved to a backend"),
+        }
+        self.apply_target_env_file(cmd)
+    }
+
+    /// Sets the variables of `--target-env-file`, flags are appended to the ones set up for the
+    /// targets so that they can add to or override the defaults, other variables are replaced
+    fn apply_target_env_file(&self, cmd: &mut Command) -> Result<()> {
+        let Some(path) = &self.target_env_file else {
+            return Ok(());
+        };
+        let content = fs::read_to_string(expand_path(path))?;
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = parse_env_line(line) else {
+                bail!(
+                    "{}:{}: expected KEY=VALUE, got `{line}`",
+                    path.display(),
+                    index + 1
+                );
+            };
+            // Variables the command doesn't set are inherited from our own environment
+            let current = match cmd.get_envs().find(|(k, _)| *k == key) {
+                Some((_, v)) => v.map(|v| v.to_string_lossy().into_owned()),
+                None => std::env::var(key).ok(),
+            }
+            .filter(|v| !v.is_empty());
+            match current {
+                // Encoded rustflags and rustdocflags are separated by 0x1f, splitting them on
+                // spaces would break flags with embedded spaces
+                Some(current) if key.starts_with("CARGO_ENCODED_") => {
+                    cmd.env(key, format!("{current}\x1f{value}"));
+                }
+                Some(current) if key.contains("FLAGS") => {
+                    cmd.env(key, format!("{current} {value}"));
+                }
+                _ => {
+                    cmd.env(key, value);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the cross compiler to use, resolving `auto` by what's already cached
+    pub(crate) fn resolve_cross_compiler(&self) -> Result<CrossCompiler> {
+        if !matches!(self.cross_compiler, CrossCompiler::Auto) {
+            return Ok(self.cross_compiler.clone());
+        }
+        let cross_compiler = if crate::compiler::msvc::find_visual_studio().is_some() {
+            CrossCompiler::Msvc
+        } else if self.xwin_cache_dir()?.join("DONE").is_file() {
+            CrossCompiler::ClangCl
+        } else if self
+            .sysroot_cache_dir()?
+            .join("windows-msvc-sysroot")
+            .join("DONE")
+            .is_file()
+        {
+            CrossCompiler::Clang
+        } else {
+            CrossCompiler::ClangCl
+        };
+        Ok(cross_compiler)
+    }
+
+    /// Returns the CRT library directory of `arch` relative to the xwin cache directory
+    ///
+    /// xwin splats the OneCore and Spectre-mitigated CRT libraries into their own subdirectories,
+    /// the Desktop libraries are used if they're included.
+    pub(crate) fn crt_lib_dir(&self, arch: &str) -> String {
+        let mut dir = "crt/lib/".to_string();
+        if self.xwin_variant.contains(&xwin::Variant::Spectre) {
+            dir.push_str("spectre/");
+        }
+        if !self.xwin_variant.contains(&xwin::Variant::Desktop)
+            && self.xwin_variant.contains(&xwin::Variant::OneCore)
+        {
+            dir.push_str("onecore/");
+        }
+        dir.push_str(arch);
+        dir
+    }
+
+    /// Fails with [`CacheLockedError`] under `--cache-locked`, called before the cache is changed
+    pub(crate) fn ensure_cache_unlocked(&self, what: &'static str, dir: &Path) -> Result<()> {
+        if self.cache_locked {
+            return Err(CacheLockedError {
+                what,
+                dir: dir.to_path_buf(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Makes sure the license was accepted before the MSVC CRT and Windows SDK are downloaded,
+    /// either splatted by xwin or in the prebuilt windows-msvc-sysroot
+    ///
+    /// The acceptance is remembered in the cache directory, interactive sessions are prompted
+    /// while others need `--accept-license`.
+    pub(crate) fn ensure_license_accepted(&self) -> Result<()> {
+        let accepted_file = self.cache_dir()?.join("LICENSE_ACCEPTED");
+        if accepted_file.is_file() {
+            return Ok(());
+        }
+        if !self.accept_license {
+            if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+                bail!(
+                    "The MSVC CRT and Windows SDK are licensed under {LICENSE_URL}, \
+                     pass `--accept-license` or set `XWIN_ACCEPT_LICENSE=1` to accept it"
+                );
+            }
+            eprint!("Do you accept the license at {LICENSE_URL} (yes | no)? ");
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                bail!("The license must be accepted to download the MSVC CRT and Windows SDK");
+            }
         }
+        fs::write(&accepted_file, LICENSE_URL)?;
         Ok(())
     }
+
+    /// Returns the `--xwin-rustflags` to append to the target rustflags
+    pub(crate) fn extra_rustflags(&self) -> Vec<String> {
+        self.xwin_rustflags
+            .as_deref()
+            .map(|flags| cargo_config2::Flags::from_space_separated(flags).flags)
+            .unwrap_or_default()
+    }
+
+    /// Returns the rustflags for `--pgo-generate` and `--pgo-use`
+    ///
+    /// rustc links its own `profiler_builtins` into instrumented builds, the clang profiling
+    /// runtime isn't linked explicitly since it defines the same symbols.
+    pub(crate) fn pgo_rustflags(&self) -> Result<Vec<String>> {
+        let cwd = std::env::current_dir()?;
+        let mut rustflags = Vec::new();
+        if let Some(dir) = &self.pgo_generate {
+            let dir = cwd.join(expand_path(dir));
+            fs::create_dir_all(&dir)?;
+            // The path is embedded into the binary which usually runs under wine
+            rustflags.push("-C".to_string());
+            rustflags.push(format!(
+                "profile-generate={}",
+                crate::wine::wine_path(&dir.canonicalize()?)
+            ));
+        }
+        if let Some(file) = &self.pgo_use {
+            let file = cwd.join(expand_path(file));
+            if !file.is_file() {
+                bail!(
+                    "PGO profile {} doesn't exist, merge the collected `.profraw` files \
+                     with `llvm-profdata merge -o {} <dir>`",
+                    file.display(),
+                    file.display()
+                );
+            }
+            rustflags.push("-C".to_string());
+            rustflags.push(format!(
+                "profile-use={}",
+                crate::compiler::common::adjust_canonicalization(
+                    file.canonicalize()?.to_slash_lossy().to_string()
+                )
+            ));
+        }
+        Ok(rustflags)
+    }
+
+    /// Returns where the download progress bars are drawn
+    pub(crate) fn progress_target(&self) -> xwin::util::ProgressTarget {
+        if self.no_progress {
+            xwin::util::ProgressTarget::Hidden
+        } else {
+            xwin::util::ProgressTarget::Stderr
+        }
+    }
+
+    /// Returns the HTTP agent for downloads with `--download-timeout`, `--prefer-ipv4`
+    /// and `--allow-insecure-download`
+    pub(crate) fn http_agent(&self, color: bool) -> Result<ureq::Agent> {
+        crate::compiler::common::http_agent(
+            self.download_timeout.map(Duration::from_secs),
+            self.prefer_ipv4,
+            self.allow_insecure_download,
+            color,
+        )
+    }
+
+    /// Returns the rustflags for `--static-crt` and `--control-flow-guard`
+    pub(crate) fn abi_rustflags(&self) -> Vec<String> {
+        let mut rustflags = Vec::new();
+        if self.static_crt {
+            rustflags.extend(["-C".to_string(), "target-feature=+crt-static".to_string()]);
+        }
+        if self.control_flow_guard {
+            rustflags.extend([
+                "-C".to_string(),
+                "control-flow-guard".to_string(),
+                "-C".to_string(),
+                "link-arg=/guard:cf".to_string(),
+            ]);
+        }
+        rustflags
+    }
+
+    /// Returns the C/C++ flags for `--static-crt` and `--control-flow-guard`,
+    /// in `cl` syntax or else in clang driver syntax
+    pub(crate) fn abi_c_flags(&self, cl: bool) -> String {
+        let mut flags = Vec::new();
+        if self.static_crt {
+            flags.push(if cl { "/MT" } else { "-fms-runtime-lib=static" });
+        }
+        if self.control_flow_guard {
+            flags.push(if cl { "/guard:cf" } else { "-cfguard" });
+        }
+        flags.iter().map(|flag| format!("{flag} ")).collect()
+    }
+
+    /// Returns the options without the ones that only matter when linking, for cargo commands
+    /// that don't link, e.g. `cargo rustc -- --emit=llvm-ir`
+    ///
+    /// `--absolute-linker` would fail without `lld-link` and `--lto-cache-dir` would create
+    /// a directory that is never used.
+    pub(crate) fn without_link_options(&self) -> Self {
+        Self {
+            absolute_linker: false,
+            emit_link_map: None,
+            lto_cache_dir: None,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the rustflags for `--emit-link-map`, `--reproducible` and `--lto-cache-dir`
+    ///
+    /// lld-link only writes map files for executables and DLLs, rlibs aren't linked. The LTO cache
+    /// is only passed when `user_rustflags` or `--xwin-rustflags` enable LTO.
+    pub(crate) fn link_arg_rustflags(&self, user_rustflags: &[String]) -> Result<Vec<String>> {
+        let mut link_args = Vec::new();
+        match &self.emit_link_map {
+            None => {}
+            Some(None) => link_args.push("/MAP".to_string()),
+            Some(Some(path)) => {
+                let path = std::env::current_dir()?.join(expand_path(path));
+                link_args.push(format!(
+                    "/MAP:{}",
+                    crate::compiler::common::adjust_canonicalization(
+                        path.to_slash_lossy().to_string()
+                    )
+                ));
+            }
+        }
+        if self.reproducible {
+            // `/Brepro` derives the timestamp from a hash of the output
+            match std::env::var("SOURCE_DATE_EPOCH") {
+                Ok(epoch) if !epoch.is_empty() => {
+                    epoch.parse::<u32>().with_context(|| {
+                        format!("Invalid SOURCE_DATE_EPOCH {epoch:?}, expected seconds fitting in 32 bits")
+                    })?;
+                    link_args.push(format!("/timestamp:{epoch}"));
+                }
+                _ => link_args.push("/Brepro".to_string()),
+            }
+            // Only embed the PDB file name instead of its absolute path
+            link_args.push("/pdbaltpath:%_PDB%".to_string());
+        }
+        // Only bitcode linked by lld-link uses the cache, which needs (linker-plugin) LTO
+        let lto = self.has_rustflag_option(user_rustflags, "-C", "linker-plugin-lto")
+            || self.has_rustflag_option(user_rustflags, "-C", "lto");
+        if let (Some(dir), true) = (&self.lto_cache_dir, lto) {
+            let dir = std::env::current_dir()?.join(expand_path(dir));
+            fs::create_dir_all(&dir).context("Failed to create LTO cache dir")?;
+            link_args.push(format!(
+                "/lldltocache:{}",
+                crate::compiler::common::adjust_canonicalization(
+                    dir.canonicalize()?.to_slash_lossy().to_string()
+                )
+            ));
+        }
+        Ok(link_args
+            .into_iter()
+            .flat_map(|arg| ["-C".to_string(), format!("link-arg={arg}")])
+            .collect())
+    }
+
+    /// Returns the `--extra-include` directories as absolute paths with forward slashes
+    pub(crate) fn extra_include_dirs(&self) -> Result<Vec<String>> {
+        absolute_slash_paths(&self.extra_include)
+    }
+
+    /// Returns the `--sdk-include-order` without duplicates, completed with the default order
+    pub(crate) fn sdk_include_order(&self) -> Vec<SdkInclude> {
+        let mut order = Vec::new();
+        for include in self
+            .sdk_include_order
+            .iter()
+            .chain(&SdkInclude::DEFAULT_ORDER)
+        {
+            if !order.contains(include) {
+                order.push(*include);
+            }
+        }
+        order
+    }
+
+    /// Fails for a `--sdk-include-order` that `backend` can't honor, `reason` tells why
+    pub(crate) fn unsupported_sdk_include_order(
+        &self,
+        backend: &str,
+        reason: &str,
+    ) -> anyhow::Error {
+        let order: Vec<_> = self
+            .sdk_include_order
+            .iter()
+            .filter_map(|include| include.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        anyhow::anyhow!(
+            "`--sdk-include-order {}` isn't supported with {backend}, {reason}",
+            order.join(",")
+        )
+    }
+
+    /// Returns the `--extra-include` and the CRT and SDK include directories of `xwin_dir`
+    /// in the `--sdk-include-order`
+    pub(crate) fn xwin_include_dirs(&self, xwin_dir: &str) -> Result<Vec<String>> {
+        let mut dirs = Vec::new();
+        for include in self.sdk_include_order() {
+            match include {
+                SdkInclude::Extra => dirs.extend(self.extra_include_dirs()?),
+                SdkInclude::Crt => dirs.push(format!("{xwin_dir}/crt/include")),
+                SdkInclude::Ucrt => dirs.push(format!("{xwin_dir}/sdk/include/ucrt")),
+                SdkInclude::Um => dirs.push(format!("{xwin_dir}/sdk/include/um")),
+                SdkInclude::Shared => dirs.push(format!("{xwin_dir}/sdk/include/shared")),
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// Returns the `--extra-lib-dir` directories as absolute paths with forward slashes
+    pub(crate) fn extra_lib_dirs(&self) -> Result<Vec<String>> {
+        absolute_slash_paths(&self.extra_lib_dir)
+    }
+
+    /// Returns the clang runtime library directories for `target`, only PGO and sanitizer builds
+    /// link those runtimes, so there are none to search for other builds
+    pub(crate) fn clang_runtime_lib_dirs(
+        &self,
+        target: &str,
+        env_path: &OsStr,
+        user_rustflags: &[String],
+    ) -> Vec<String> {
+        let needs_runtime = self.pgo_generate.is_some()
+            || self.pgo_use.is_some()
+            || self.has_rustflag_option(user_rustflags, "-Z", "sanitizer");
+        if !needs_runtime {
+            return Vec::new();
+        }
+        let resource_dir = self.clang_resource_dir.as_deref().map(|dir| {
+            std::env::current_dir()
+                .unwrap_or_default()
+                .join(expand_path(dir))
+        });
+        crate::compiler::common::clang_runtime_lib_dirs(resource_dir.as_deref(), target, env_path)
+    }
+
+    /// Whether `user_rustflags` or `--xwin-rustflags` enable the rustc option `name`
+    fn has_rustflag_option(&self, user_rustflags: &[String], kind: &str, name: &str) -> bool {
+        use crate::compiler::common::has_flag_option;
+
+        has_flag_option(user_rustflags, kind, name)
+            || has_flag_option(&self.extra_rustflags(), kind, name)
+    }
+
+    /// Returns the canonicalized xwin cache directory, creating it if needed
+    pub fn cache_dir(&self) -> Result<PathBuf> {
+        let cache_dir = self.cache_dir_path()?;
+        fs::create_dir_all(&cache_dir)?;
+        Ok(cache_dir.canonicalize()?)
+    }
+
+    /// Returns the xwin cache directory without creating it
+    pub(crate) fn cache_dir_path(&self) -> Result<PathBuf> {
+        Ok(match self.xwin_cache_dir.as_deref() {
+            Some(dir) => expand_path(dir),
+            None if self.project_cache => {
+                project_cache_dir(None, &cargo_options::CommonOptions::default())?
+            }
+            None => dirs::cache_dir()
+                .unwrap_or_else(|| std::env::current_dir().expect("Failed to get current dir"))
+                .join(env!("CARGO_PKG_NAME")),
+        })
+    }
+
+    /// Returns the directories toolchains are cached in without creating them, the cache directory
+    /// followed by the `XWIN_CRT_CACHE_DIR` and `XWIN_SYSROOT_CACHE_DIR` overrides
+    pub(crate) fn cache_roots(&self) -> Result<Vec<PathBuf>> {
+        let mut roots = vec![self.cache_dir_path()?];
+        for dir in [&self.crt_cache_dir, &self.sysroot_cache_dir]
+            .into_iter()
+            .flatten()
+        {
+            let dir = expand_path(dir);
+            if !roots.contains(&dir) {
+                roots.push(dir);
+            }
+        }
+        Ok(roots)
+    }
+
+    /// Returns the canonicalized directory the MSVC CRT and Windows SDK are splatted to
+    ///
+    /// `XWIN_CRT_CACHE_DIR` takes precedence over the xwin cache directory.
+    pub(crate) fn xwin_cache_dir(&self) -> Result<PathBuf> {
+        let base = match &self.crt_cache_dir {
+            Some(dir) => expand_path(dir),
+            None => self.cache_dir()?,
+        };
+        let xwin_cache_dir = base.join("xwin");
+        fs::create_dir_all(&xwin_cache_dir).context("Failed to create xwin cache dir")?;
+        xwin_cache_dir
+            .canonicalize()
+            .context("Failed to canonicalize xwin cache dir")
+    }
+
+    /// Returns the directory the `clang-cl` symlink and the zig wrappers are written to and that
+    /// is added to `PATH`, `<target-dir>/xwin-tools` with `--no-symlink-tools` as the cache
+    /// directory may be read-only
+    pub(crate) fn tools_dir(
+        &self,
+        manifest_path: Option<&Path>,
+        cargo: &cargo_options::CommonOptions,
+        cache_dir: &Path,
+    ) -> Result<PathBuf> {
+        if !self.no_symlink_tools {
+            return Ok(cache_dir.to_path_buf());
+        }
+        let dir =
+            crate::compiler::common::resolve_target_dir(manifest_path, cargo)?.join("xwin-tools");
+        fs::create_dir_all(&dir).context("Failed to create tools dir")?;
+        Ok(dir)
+    }
+
+    /// Returns the canonicalized directory the CMake toolchain files of `backend` are written to
+    ///
+    /// `XWIN_CMAKE_DIR` takes precedence over the `cmake` directory in `cache_dir`.
+    pub(crate) fn cmake_dir(&self, cache_dir: &Path, backend: &str) -> Result<PathBuf> {
+        let dir = match &self.cmake_dir {
+            Some(dir) => expand_path(dir).join(backend),
+            None => cache_dir.join("cmake").join(backend),
+        };
+        fs::create_dir_all(&dir).context("Failed to create CMake toolchain dir")?;
+        Ok(dir.canonicalize()?)
+    }
+
+    /// Returns the `CMAKE_SYSTEM_VERSION` line of the CMake toolchain files, empty unless
+    /// `--cmake-system-version` is set
+    pub(crate) fn cmake_system_version_line(&self) -> String {
+        self.cmake_system_version
+            .as_deref()
+            .map(|version| format!("\nset(CMAKE_SYSTEM_VERSION {version})"))
+            .unwrap_or_default()
+    }
+
+    /// Returns the canonicalized directory the prebuilt MSVC sysroot is stored in
+    ///
+    /// `XWIN_SYSROOT_CACHE_DIR` takes precedence over the xwin cache directory.
+    pub(crate) fn sysroot_cache_dir(&self) -> Result<PathBuf> {
+        match &self.sysroot_cache_dir {
+            Some(dir) => {
+                let dir = expand_path(dir);
+                fs::create_dir_all(&dir)?;
+                Ok(dir.canonicalize()?)
+            }
+            None => self.cache_dir(),
+        }
+    }
+}
+
+/// Serializes values such as the xwin architectures that don't implement `Serialize` by name
+fn serialize_display_seq<T: fmt::Display, S: Serializer>(
+    values: &[T],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().map(ToString::to_string))
+}
+
+/// Parses a `KEY=VALUE` line of a target env file, `export ` prefixes are allowed
+fn parse_env_line(line: &str) -> Option<(&str, String)> {
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    line.split_once('=')
+        .map(|(key, value)| (key.trim(), unquote(value.trim())))
+        .filter(|(key, _)| !key.is_empty())
+}
+
+/// Removes the quotes of a dotenv value, single quotes keep it literal,
+/// in double quotes a backslash escapes the next character
+fn unquote(value: &str) -> String {
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        return value[1..value.len() - 1].to_string();
+    }
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        let mut unquoted = String::with_capacity(value.len());
+        let mut chars = value[1..value.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => unquoted.extend(chars.next()),
+                c => unquoted.push(c),
+            }
+        }
+        return unquoted;
+    }
+    value.to_string()
+}
+
+/// Parses a dotted Windows version such as `10.0.19041.0`
+fn parse_system_version(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.is_empty()
+        || !s
+            .split('.')
+            .all(|part| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return Err(format!("expected a version like `10.0.19041.0`, got `{s}`"));
+    }
+    Ok(s.to_string())
+}
+
+/// Value parser of boolean flags that can be set with an environment variable
+///
+/// clap's default only accepts `true` and `false`, this also takes `1`, `yes` or `on`, while
+/// `0`, `no`, `off` and an empty value disable the flag.
+pub(crate) fn env_flag() -> clap::builder::FalseyValueParser {
+    clap::builder::FalseyValueParser::new()
+}
+
+/// Parses a size in bytes with an optional binary `K`, `M` or `G` suffix,
+/// which may be followed by `B` or `iB`, e.g. `500M`, `500MB` or `500MiB`
+fn parse_size(s: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid size `{s}`, expected bytes or e.g. `500M` or `2G`");
+    let lower = s.trim().to_ascii_lowercase();
+    let (rest, binary_suffix) = match lower.strip_suffix("ib") {
+        Some(rest) => (rest, true),
+        None => (lower.strip_suffix('b').unwrap_or(&lower), false),
+    };
+    let (number, shift) = match rest.as_bytes().last() {
+        Some(b'k') => (&rest[..rest.len() - 1], 10),
+        Some(b'm') => (&rest[..rest.len() - 1], 20),
+        Some(b'g') => (&rest[..rest.len() - 1], 30),
+        // `iB` needs a unit, `5iB` isn't a size
+        _ if binary_suffix => return Err(invalid()),
+        _ => (rest, 0),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .ok_or_else(invalid)
+}
+
+/// Returns `xwin-cache` in the target directory of the workspace of `manifest_path`,
+/// or of the current directory, honoring `--target-dir` and `CARGO_TARGET_DIR`
+fn project_cache_dir(
+    manifest_path: Option<&Path>,
+    cargo: &cargo_options::CommonOptions,
+) -> Result<PathBuf> {
+    let target_dir = crate::compiler::common::resolve_target_dir(manifest_path, cargo)
+        .context("--project-cache requires a cargo project")?;
+    Ok(target_dir.join("xwin-cache"))
+}
+
+fn absolute_slash_paths(dirs: &[PathBuf]) -> Result<Vec<String>> {
+    let cwd = std::env::current_dir()?;
+    Ok(dirs
+        .iter()
+        .map(|dir| {
+            crate::compiler::common::adjust_canonicalization(
+                cwd.join(expand_path(dir)).to_slash_lossy().to_string(),
+            )
+        })
+        .collect())
+}
+
+/// Expands a leading `~` and `$VAR`/`${VAR}` references in `path`
+///
+/// Cache directories are often set in files that aren't expanded by a shell,
+/// references to unset variables are kept as is.
+fn expand_path(path: &Path) -> PathBuf {
+    let Some(path) = path.to_str() else {
+        return path.to_path_buf();
+    };
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        if let Some(home) = dirs::home_dir() {
+            expanded.push_str(&home.to_string_lossy());
+            rest = &rest[1..];
+        }
+    }
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => ("", 0),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+        match std::env::var(name) {
+            Ok(value) if !name.is_empty() => expanded.push_str(&value),
+            _ => expanded.push_str(&rest[start..start + 1 + len]),
+        }
+        rest = &after[len..];
+    }
+    expanded.push_str(rest);
+    PathBuf::from(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{ArgAction, CommandFactory};
+
+    use super::*;
+
+    #[test]
+    fn expand_home_and_variables() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_path(Path::new("~")), home);
+        assert_eq!(
+            expand_path(Path::new("~/.cache/xwin")),
+            PathBuf::from(format!("{}/.cache/xwin", home.display()))
+        );
+        // Only a leading `~` is the home directory
+        assert_eq!(expand_path(Path::new("a/~/b")), Path::new("a/~/b"));
+        assert_eq!(expand_path(Path::new("~user/b")), Path::new("~user/b"));
+
+        std::env::set_var("CARGO_XWIN_TEST_EXPAND", "/mnt/cache");
+        assert_eq!(
+            expand_path(Path::new("$CARGO_XWIN_TEST_EXPAND/xwin")),
+            Path::new("/mnt/cache/xwin")
+        );
+        assert_eq!(
+            expand_path(Path::new("${CARGO_XWIN_TEST_EXPAND}-xwin")),
+            Path::new("/mnt/cache-xwin")
+        );
+        std::env::remove_var("CARGO_XWIN_TEST_EXPAND");
+        // References to unset variables are kept
+        assert_eq!(
+            expand_path(Path::new(
+                "$CARGO_XWIN_TEST_UNSET/${CARGO_XWIN_TEST_UNSET}/${open"
+            )),
+            Path::new("$CARGO_XWIN_TEST_UNSET/${CARGO_XWIN_TEST_UNSET}/${open")
+        );
+        assert_eq!(expand_path(Path::new("cost$")), Path::new("cost$"));
+    }
+
+    #[test]
+    fn unquote_dotenv_values() {
+        assert_eq!(unquote("plain"), "plain");
+        assert_eq!(unquote("'single $HOME \\n'"), "single $HOME \\n");
+        assert_eq!(unquote(r#""a \"b\" \\c""#), r#"a "b" \c"#);
+        assert_eq!(unquote(r#""""#), "");
+        assert_eq!(unquote("'"), "'");
+        assert_eq!(unquote(r#""unterminated"#), r#""unterminated"#);
+    }
+
+    #[test]
+    fn parse_target_env_lines() {
+        assert_eq!(
+            parse_env_line("CC=clang"),
+            Some(("CC", "clang".to_string()))
+        );
+        assert_eq!(
+            parse_env_line("export CFLAGS = \"-O2 -g\""),
+            Some(("CFLAGS", "-O2 -g".to_string()))
+        );
+        assert_eq!(parse_env_line("A=b=c"), Some(("A", "b=c".to_string())));
+        assert_eq!(parse_env_line("EMPTY="), Some(("EMPTY", String::new())));
+        assert_eq!(parse_env_line("=value"), None);
+        assert_eq!(parse_env_line("no value"), None);
+    }
+
+    #[test]
+    fn target_env_file_appends_flags() {
+        let path = std::env::temp_dir().join(format!("cargo-xwin-env-{}", std::process::id()));
+        fs::write(
+            &path,
+            "# comment\nCARGO_ENCODED_RUSTFLAGS=-Cdebuginfo=1\n\
+             CARGO_ENCODED_RUSTDOCFLAGS=--cfg=docsrs\nCFLAGS=-O2\nCC=clang-cl\n",
+        )
+        .unwrap();
+        let options = XWinOptions {
+            target_env_file: Some(path.clone()),
+            ..Default::default()
+        };
+        let mut cmd = Command::new("cargo");
+        cmd.env("CARGO_ENCODED_RUSTFLAGS", "-Clink-arg=/a b");
+        cmd.env("CARGO_ENCODED_RUSTDOCFLAGS", "--html-in-header=a b.html");
+        cmd.env("CFLAGS", "-g");
+        cmd.env("CC", "cl");
+        options.apply_target_env_file(&mut cmd).unwrap();
+        fs::remove_file(&path).unwrap();
+        let env = |key: &str| {
+            cmd.get_envs()
+                .find(|(k, _)| *k == key)
+                .and_then(|(_, v)| v)
+                .map(|v| v.to_string_lossy().into_owned())
+        };
+        assert_eq!(
+            env("CARGO_ENCODED_RUSTFLAGS").unwrap(),
+            "-Clink-arg=/a b\x1f-Cdebuginfo=1"
+        );
+        assert_eq!(
+            env("CARGO_ENCODED_RUSTDOCFLAGS").unwrap(),
+            "--html-in-header=a b.html\x1f--cfg=docsrs"
+        );
+        assert_eq!(env("CFLAGS").unwrap(), "-g -O2");
+        assert_eq!(env("CC").unwrap(), "clang-cl");
+    }
+
+    #[test]
+    fn project_cache_in_workspace_target_dir() {
+        let workspace =
+            std::env::temp_dir().join(format!("cargo-xwin-project-cache-{}", std::process::id()));
+        let member = workspace.join("member");
+        fs::create_dir_all(member.join("src")).unwrap();
+        fs::write(
+            workspace.join("Cargo.toml"),
+            "[workspace]\nmembers = [\"member\"]\nresolver = \"2\"\n",
+        )
+        .unwrap();
+        fs::write(
+            member.join("Cargo.toml"),
+            "[package]\nname = \"member\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        fs::write(member.join("src/lib.rs"), "").unwrap();
+        let manifest_path = member.join("Cargo.toml");
+        let cargo = cargo_options::CommonOptions::default();
+
+        // No other unit test reads `CARGO_TARGET_DIR`
+        std::env::set_var("CARGO_TARGET_DIR", workspace.join("custom-target"));
+        assert_eq!(
+            project_cache_dir(Some(&manifest_path), &cargo).unwrap(),
+            workspace.join("custom-target/xwin-cache")
+        );
+        let cargo_with_target_dir = cargo_options::CommonOptions {
+            target_dir: Some(workspace.join("cli-target")),
+            ..Default::default()
+        };
+        assert_eq!(
+            project_cache_dir(Some(&manifest_path), &cargo_with_target_dir).unwrap(),
+            workspace.join("cli-target/xwin-cache")
+        );
+        std::env::remove_var("CARGO_TARGET_DIR");
+        // cargo metadata reports the canonical path
+        let expected = workspace.canonicalize().unwrap().join("target/xwin-cache");
+        let cache_dir = project_cache_dir(Some(&manifest_path), &cargo).unwrap();
+        fs::remove_dir_all(&workspace).unwrap();
+        assert_eq!(cache_dir, expected);
+    }
+
+    #[test]
+    fn parse_download_sizes() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("10B"), Ok(10));
+        assert_eq!(parse_size("1K"), Ok(1 << 10));
+        assert_eq!(parse_size("500M"), Ok(500 << 20));
+        assert_eq!(parse_size("500mb"), Ok(500 << 20));
+        assert_eq!(parse_size(" 2GiB "), Ok(2 << 30));
+        assert_eq!(parse_size("2 G"), Ok(2 << 30));
+        for invalid in [
+            "",
+            "M",
+            "5MBB",
+            "5IB",
+            "5iB",
+            "5KK",
+            "5T",
+            "-5M",
+            "99999999999G",
+        ] {
+            assert!(parse_size(invalid).is_err(), "{invalid:?} is accepted");
+        }
+    }
+
+    #[test]
+    fn env_bool_flags_accept_numbers() {
+        let cmd = XWinOptions::command();
+        let flags: Vec<_> = cmd
+            .get_arguments()
+            .filter(|arg| arg.get_env().is_some() && matches!(arg.get_action(), ArgAction::SetTrue))
+            .map(|arg| arg.get_id().clone())
+            .collect();
+        assert!(!flags.is_empty());
+        for id in flags {
+            // A variable only this test reads, clap wants a `'static` name
+            let env_key: &'static str =
+                format!("CARGO_XWIN_TEST_FLAG_{}", id.as_str().to_uppercase()).leak();
+            for (value, expected) in [("1", true), ("0", false), ("true", true), ("", false)] {
+                std::env::set_var(env_key, value);
+                let matches = XWinOptions::command()
+                    .mut_arg(&id, |arg| arg.env(env_key))
+                    .try_get_matches_from(["xwin"])
+                    .unwrap_or_else(|err| panic!("{id}={value:?} is rejected: {err}"));
+                assert_eq!(matches.get_flag(id.as_str()), expected, "{id}={value:?}");
+            }
+            std::env::remove_var(env_key);
+        }
+    }
 }
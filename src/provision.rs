@@ -0,0 +1,49 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::Result;
+use clap::Parser;
+
+use crate::compiler::common::{decorate, use_color};
+use crate::options::XWinOptions;
+
+/// Download the MSVC CRT and Windows SDK and generate the toolchain files without building
+#[derive(Clone, Debug, Default, Parser)]
+#[command(
+    display_order = 1,
+    after_help = "Combine with `cargo xwin env` to run plain cargo commands afterwards."
+)]
+pub struct Provision {
+    #[command(flatten)]
+    pub xwin: XWinOptions,
+
+    #[command(flatten)]
+    pub cargo: cargo_options::CommonOptions,
+
+    /// Path to Cargo.toml
+    #[arg(long, value_name = "PATH", help_heading = cargo_options::heading::MANIFEST_OPTIONS)]
+    pub manifest_path: Option<PathBuf>,
+}
+
+impl Provision {
+    /// Provision the cross compilation toolchain for the targets
+    pub fn execute(&self) -> Result<()> {
+        // The environment is only computed for its side effects on the cache
+        let mut cmd = Command::new("cargo");
+        self.xwin
+            .apply_command_env(self.manifest_path.as_deref(), &self.cargo, &mut cmd)?;
+        let color = use_color(self.cargo.color.as_deref());
+        eprintln!(
+            "{}",
+            decorate(
+                color,
+                "✅",
+                &format!(
+                    "Provisioned the toolchain in {}",
+                    self.xwin.cache_dir()?.display()
+                )
+            )
+        );
+        Ok(())
+    }
+}
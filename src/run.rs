@@ -1,12 +1,16 @@
 use std::env;
+use std::ffi::OsString;
 use std::ops::{Deref, DerefMut};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{self, Command};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
 
+use crate::compiler::common::split_targets;
+use crate::exec::execute_cargo;
 use crate::options::XWinOptions;
+use crate::wine::{setup_wine_env, wine_runner, wine_runner_configs, WineOptions};
 
 /// Run a binary or example of the local package
 #[derive(Clone, Debug, Default, Parser)]
@@ -18,6 +22,13 @@ pub struct Run {
     #[command(flatten)]
     pub xwin: XWinOptions,
 
+    #[command(flatten)]
+    pub wine: WineOptions,
+
+    /// Run the binary in DIR instead of the current directory
+    #[arg(long, value_name = "DIR")]
+    pub cwd: Option<PathBuf>,
+
     #[command(flatten)]
     pub cargo: cargo_options::Run,
 }
@@ -33,32 +44,94 @@ impl Run {
 
     /// Execute `cargo run` command
     pub fn execute(&self) -> Result<()> {
-        let mut run = self.build_command()?;
-
-        for target in &self.cargo.target {
-            if target.contains("msvc") {
-                if env::var_os("WINEDEBUG").is_none() {
-                    run.env("WINEDEBUG", "-all");
-                }
-                let env_target = target.to_uppercase().replace('-', "_");
-                let runner_env = format!("CARGO_TARGET_{}_RUNNER", env_target);
-                if env::var_os(&runner_env).is_none() {
-                    run.env(runner_env, "wine");
-                }
-            }
+        let mut this = self.clone();
+        match &self.cwd {
+            // The `--cwd` runners already run wine for MSVC targets
+            Some(dir) => this
+                .cargo
+                .common
+                .config
+                .extend(self.cwd_runner_configs(dir)?),
+            None => this.cargo.common.config.extend(wine_runner_configs(
+                self.manifest_path.as_deref(),
+                &self.cargo.common,
+                &self.wine,
+            )?),
         }
+        let mut run = this.build_command()?;
+        setup_wine_env(
+            &mut run,
+            self.manifest_path.as_deref(),
+            &self.cargo.common,
+            self.cargo.release,
+            &self.wine,
+        )?;
+        execute_cargo(run, "run", &self.xwin, &self.cargo.common, None)?;
+        Ok(())
+    }
 
-        let mut child = run.spawn().context("Failed to run cargo run")?;
-        let status = child.wait().expect("Failed to wait on cargo run process");
-        if !status.success() {
-            process::exit(status.code().unwrap_or(1));
+    /// Returns the `--config` values wrapping the runner of each target to start the binary in `dir`.
+    ///
+    /// cargo itself keeps running in the current directory so that the package and the config
+    /// files resolve as usual. The configured runner is kept, MSVC targets without one get wine.
+    fn cwd_runner_configs(&self, dir: &Path) -> Result<Vec<String>> {
+        let current_dir = env::current_dir()?;
+        let dir = current_dir.join(dir);
+        if !dir.is_dir() {
+            bail!("--cwd {} is not a directory", dir.display());
         }
-        Ok(())
+        let workdir = match self
+            .manifest_path
+            .as_deref()
+            .and_then(|p| p.parent())
+            .filter(|p| !p.as_os_str().is_empty())
+        {
+            Some(dir) => current_dir.join(dir),
+            None => current_dir,
+        };
+        let cargo_config = cargo_config2::Config::load_with_cwd(workdir)?;
+        let mut targets = split_targets(&self.cargo.common.target);
+        if targets.is_empty() {
+            targets.push(cargo_config.host_triple()?.to_string());
+        }
+        let cargo_xwin = env::current_exe()?;
+        let mut configs = Vec::new();
+        for target in targets {
+            let runner: Vec<String> = match cargo_config.runner(target.as_str())? {
+                Some(runner) => std::iter::once(runner.path.into_os_string())
+                    .chain(runner.args)
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect(),
+                None if target.contains("msvc") => {
+                    let wine = wine_runner(&target, &self.wine)?;
+                    shlex::split(&wine).with_context(|| format!("Failed to split `{wine}`"))?
+                }
+                None => Vec::new(),
+            };
+            let wrapper: toml_edit::Array = [
+                cargo_xwin.to_string_lossy().into_owned(),
+                "xwin".to_string(),
+                "cwd-runner".to_string(),
+                "--cwd".to_string(),
+                dir.to_string_lossy().into_owned(),
+                "--runner-args".to_string(),
+                runner.len().to_string(),
+                "--".to_string(),
+            ]
+            .into_iter()
+            .chain(runner)
+            .collect();
+            configs.push(format!("target.{target}.runner={wrapper}"));
+        }
+        Ok(configs)
     }
 
     /// Generate cargo subcommand
     pub fn build_command(&self) -> Result<Command> {
-        let mut build = self.cargo.command();
+        let mut cargo = self.cargo.clone();
+        // `CARGO_BUILD_TARGET` may contain multiple comma or space separated targets
+        cargo.common.target = split_targets(&cargo.common.target);
+        let mut build = cargo.command();
         self.xwin.apply_command_env(
             self.manifest_path.as_deref(),
             &self.cargo.common,
@@ -68,6 +141,61 @@ impl Run {
     }
 }
 
+/// Starts a binary in another working directory, the runner set up by `cargo xwin run --cwd`
+#[derive(Clone, Debug, Parser)]
+#[command(hide = true)]
+pub struct CwdRunner {
+    /// The working directory of the binary
+    #[arg(long, value_name = "DIR")]
+    pub cwd: PathBuf,
+
+    /// The number of leading arguments that are the configured runner
+    #[arg(long, value_name = "N")]
+    pub runner_args: usize,
+
+    /// The runner, followed by the binary and its arguments as passed by cargo
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<OsString>,
+}
+
+impl CwdRunner {
+    /// Runs the binary in `--cwd` and exits with its exit code
+    pub fn execute(&self) -> Result<()> {
+        let current_dir = env::current_dir()?;
+        let split = self.runner_args.min(self.args.len());
+        let (runner, rest) = self.args.split_at(split);
+        let (binary, args) = rest
+            .split_first()
+            .context("cargo didn't pass the binary to run")?;
+        // cargo passes the binary relative to its own working directory
+        let binary = current_dir.join(binary);
+        let mut cmd = match runner.split_first() {
+            Some((program, runner_args)) => {
+                let program = Path::new(program);
+                // Relative runner paths are resolved by cargo's working directory too
+                let program = if program.components().count() > 1 {
+                    current_dir.join(program)
+                } else {
+                    program.to_path_buf()
+                };
+                let mut cmd = Command::new(program);
+                cmd.args(runner_args).arg(binary);
+                cmd
+            }
+            None => Command::new(binary),
+        };
+        let status = cmd
+            .args(args)
+            .current_dir(&self.cwd)
+            .status()
+            .context("Failed to run the binary")?;
+        if !status.success() {
+            process::exit(status.code().unwrap_or(1));
+        }
+        Ok(())
+    }
+}
+
 impl Deref for Run {
     type Target = cargo_options::Run;
 
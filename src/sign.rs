@@ -0,0 +1,136 @@
+use std::env;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use fs_err as fs;
+use serde::Serialize;
+
+/// Authenticode signing options
+#[derive(Clone, Debug, Default, Parser, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SignOptions {
+    /// Sign the linked executables and DLLs with a PKCS#12 (`.pfx`) certificate,
+    /// its password is read from `XWIN_SIGN_PASSWORD`
+    #[arg(
+        long,
+        env = "XWIN_SIGN_PKCS12",
+        value_name = "FILE",
+        conflicts_with = "sign_cert"
+    )]
+    pub sign_pkcs12: Option<PathBuf>,
+
+    /// Sign the linked executables and DLLs with a certificate and `--sign-key`
+    #[arg(
+        long,
+        env = "XWIN_SIGN_CERT",
+        value_name = "FILE",
+        requires = "sign_key"
+    )]
+    pub sign_cert: Option<PathBuf>,
+
+    /// The private key of `--sign-cert`
+    #[arg(
+        long,
+        env = "XWIN_SIGN_KEY",
+        value_name = "FILE",
+        requires = "sign_cert"
+    )]
+    pub sign_key: Option<PathBuf>,
+
+    /// The RFC 3161 timestamp server to countersign with
+    #[arg(long, env = "XWIN_SIGN_TIMESTAMP_URL", value_name = "URL")]
+    pub sign_timestamp_url: Option<String>,
+
+    /// The signing tool taking osslsigncode arguments, defaults to `osslsigncode`
+    #[arg(long, env = "XWIN_SIGNER", value_name = "PROGRAM")]
+    pub signer: Option<PathBuf>,
+}
+
+impl SignOptions {
+    /// Whether signing was requested
+    pub(crate) fn enabled(&self) -> bool {
+        self.sign_pkcs12.is_some() || self.sign_cert.is_some()
+    }
+
+    /// Returns the path of the signing tool
+    pub(crate) fn resolve_signer(&self) -> Result<PathBuf> {
+        let signer = self
+            .signer
+            .as_deref()
+            .unwrap_or_else(|| Path::new("osslsigncode"));
+        which::which(signer).with_context(|| {
+            format!(
+                "Failed to find `{}` in PATH, install osslsigncode or set `--signer`",
+                signer.display()
+            )
+        })
+    }
+
+    /// Signs `bin` in place with `signer`
+    ///
+    /// osslsigncode can't overwrite its input, the signed binary is written next to it first.
+    pub(crate) fn sign(&self, signer: &Path, bin: &Path) -> Result<()> {
+        let mut signed = bin.as_os_str().to_owned();
+        signed.push(".signed");
+        let signed = PathBuf::from(signed);
+        if signed.exists() {
+            fs::remove_file(&signed)?;
+        }
+
+        let mut cmd = Command::new(signer);
+        cmd.arg("sign");
+        if let Some(pkcs12) = &self.sign_pkcs12 {
+            cmd.arg("-pkcs12").arg(pkcs12);
+        } else if let (Some(cert), Some(key)) = (&self.sign_cert, &self.sign_key) {
+            cmd.arg("-certs").arg(cert).arg("-key").arg(key);
+        }
+        let password_file = match env::var_os("XWIN_SIGN_PASSWORD") {
+            Some(password) => {
+                let path = write_password_file(&password)?;
+                cmd.arg("-readpass").arg(&path);
+                Some(path)
+            }
+            None => None,
+        };
+        if let Some(url) = &self.sign_timestamp_url {
+            cmd.arg("-ts").arg(url);
+        }
+        cmd.arg("-in").arg(bin).arg("-out").arg(&signed);
+        let output = cmd.output();
+        if let Some(path) = password_file {
+            let _ = fs::remove_file(path);
+        }
+        let output = output.with_context(|| format!("Failed to run `{}`", signer.display()))?;
+        if !output.status.success() {
+            let _ = fs::remove_file(&signed);
+            bail!(
+                "Failed to sign {} with {}: {}",
+                bin.display(),
+                signer.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        fs::rename(&signed, bin)?;
+        Ok(())
+    }
+}
+
+/// Writes the signing password to a file only the current user can read, it's passed with
+/// `-readpass` so that it doesn't show up in the process list
+fn write_password_file(password: &OsStr) -> Result<PathBuf> {
+    let path = env::temp_dir().join(format!("cargo-xwin-sign-{}.pass", process::id()));
+    if path.exists() {
+        fs::remove_file(&path)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    fs::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&path)?;
+    file.write_all(password.as_encoded_bytes())?;
+    Ok(path)
+}
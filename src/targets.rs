@@ -0,0 +1,40 @@
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+
+use crate::compiler::common::xwin_arch;
+
+/// List the Windows MSVC target triples supported by cargo-xwin
+///
+/// The triples come from `rustc --print target-list`, so only targets known to the
+/// installed Rust toolchain are listed.
+#[derive(Clone, Debug, Default, Parser)]
+#[command(display_order = 1)]
+pub struct Targets {}
+
+impl Targets {
+    /// Execute `cargo xwin targets` command
+    pub fn execute(&self) -> Result<()> {
+        let output = Command::new("rustc")
+            .args(["--print", "target-list"])
+            .output()
+            .context("Failed to run rustc")?;
+        if !output.status.success() {
+            bail!(
+                "Failed to get the target list from rustc: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let stdout = String::from_utf8(output.stdout)?;
+        for target in stdout.lines().filter(|x| x.ends_with("-windows-msvc")) {
+            let arch = xwin_arch(target)?;
+            if arch == "arm64ec" {
+                println!("{target} (requires a clang/LLVM release with Arm64EC support)");
+            } else if arch.parse::<xwin::Arch>().is_ok() {
+                println!("{target}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,12 +1,14 @@
-use std::env;
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
-use std::process::{self, Command};
+use std::process::Command;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use clap::Parser;
 
+use crate::compiler::common::split_targets;
+use crate::exec::execute_cargo;
 use crate::options::XWinOptions;
+use crate::wine::{setup_wine_env, wine_runner_configs, WineOptions};
 
 /// Execute all unit and integration tests and build examples of a local package
 #[derive(Clone, Debug, Default, Parser)]
@@ -18,6 +20,9 @@ pub struct Test {
     #[command(flatten)]
     pub xwin: XWinOptions,
 
+    #[command(flatten)]
+    pub wine: WineOptions,
+
     #[command(flatten)]
     pub cargo: cargo_options::Test,
 }
@@ -33,32 +38,35 @@ impl Test {
 
     /// Execute `cargo test` command
     pub fn execute(&self) -> Result<()> {
-        let mut test = self.build_command()?;
-
-        for target in &self.cargo.target {
-            if target.contains("msvc") {
-                if env::var_os("WINEDEBUG").is_none() {
-                    test.env("WINEDEBUG", "-all");
-                }
-                let env_target = target.to_uppercase().replace('-', "_");
-                let runner_env = format!("CARGO_TARGET_{}_RUNNER", env_target);
-                if env::var_os(&runner_env).is_none() {
-                    test.env(runner_env, "wine");
-                }
-            }
+        // Nothing is run with `--no-run`, so wine isn't needed
+        let mut this = self.clone();
+        if !self.cargo.no_run {
+            this.cargo.common.config.extend(wine_runner_configs(
+                self.manifest_path.as_deref(),
+                &self.cargo.common,
+                &self.wine,
+            )?);
         }
-
-        let mut child = test.spawn().context("Failed to run cargo test")?;
-        let status = child.wait().expect("Failed to wait on cargo test process");
-        if !status.success() {
-            process::exit(status.code().unwrap_or(1));
+        let mut test = this.build_command()?;
+        if !self.cargo.no_run {
+            setup_wine_env(
+                &mut test,
+                self.manifest_path.as_deref(),
+                &self.cargo.common,
+                self.cargo.release,
+                &self.wine,
+            )?;
         }
+        execute_cargo(test, "test", &self.xwin, &self.cargo.common, None)?;
         Ok(())
     }
 
     /// Generate cargo subcommand
     pub fn build_command(&self) -> Result<Command> {
-        let mut build = self.cargo.command();
+        let mut cargo = self.cargo.clone();
+        // `CARGO_BUILD_TARGET` may contain multiple comma or space separated targets
+        cargo.common.target = split_targets(&cargo.common.target);
+        let mut build = cargo.command();
         self.xwin.apply_command_env(
             self.manifest_path.as_deref(),
             &self.cargo.common,
@@ -0,0 +1,59 @@
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::Result;
+use clap::{ArgAction, Parser};
+
+use crate::compiler::common::split_targets;
+use crate::external::External;
+
+/// Display a tree visualization of a dependency graph
+///
+/// Unlike the build commands, this doesn't need the MSVC CRT and Windows SDK,
+/// so nothing is downloaded.
+#[derive(Clone, Debug, Default, Parser)]
+#[command(
+    display_order = 1,
+    after_help = "Run `cargo help tree` for more detailed information."
+)]
+pub struct Tree {
+    /// Path to Cargo.toml
+    #[arg(long, value_name = "PATH")]
+    pub manifest_path: Option<PathBuf>,
+
+    /// Filter dependencies matching the given target triple
+    #[arg(long, value_name = "TRIPLE", env = "CARGO_BUILD_TARGET", action = ArgAction::Append)]
+    pub target: Vec<String>,
+
+    /// Other arguments passed to `cargo tree`
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    pub args: Vec<String>,
+}
+
+impl Tree {
+    /// Execute `cargo tree` command
+    pub fn execute(&self) -> Result<()> {
+        self.external().execute()
+    }
+
+    /// Generate cargo subcommand
+    pub fn command(&self) -> Result<Command> {
+        self.external().command()
+    }
+
+    /// `cargo tree` with the arguments, run as a plain external cargo subcommand
+    fn external(&self) -> External {
+        let mut args: Vec<OsString> = vec!["tree".into()];
+        if let Some(manifest_path) = &self.manifest_path {
+            args.push("--manifest-path".into());
+            args.push(manifest_path.into());
+        }
+        for target in split_targets(&self.target) {
+            args.push("--target".into());
+            args.push(target.into());
+        }
+        args.extend(self.args.iter().map(OsString::from));
+        External::new(args)
+    }
+}
@@ -0,0 +1,178 @@
+use std::env;
+use std::path::Path;
+use std::process;
+
+use anyhow::{bail, Context, Result};
+use clap::{ArgAction, Parser};
+use fs_err as fs;
+
+use crate::compiler::common::{decorate, use_color};
+use crate::options::XWinOptions;
+
+const VERIFY_CARGO_TOML: &str = r#"[package]
+name = "cargo-xwin-verify"
+version = "0.1.0"
+edition = "2021"
+build = "build.rs"
+
+[workspace]
+"#;
+
+const VERIFY_BUILD_RS: &str = r#"use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Splits a multi-word tool like `sccache clang-cl` into the program and its arguments the way
+/// the cc crate does, unless the whole value is an existing path
+fn tool_command(tool: &str) -> Command {
+    if Path::new(tool).exists() {
+        return Command::new(tool);
+    }
+    let mut parts = tool.split_whitespace();
+    let mut cmd = Command::new(parts.next().unwrap_or(tool));
+    cmd.args(parts);
+    cmd
+}
+
+fn main() {
+    let target = env::var("TARGET").unwrap();
+    let env_target = target.replace('-', "_");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let cc = env::var(format!("CC_{env_target}")).expect("C compiler is not configured");
+    let cflags = env::var(format!("CFLAGS_{env_target}")).unwrap_or_default();
+    let ar = env::var(format!("AR_{env_target}")).expect("archiver is not configured");
+
+    let obj = out_dir.join("hello.obj");
+    let status = tool_command(&cc)
+        .args(cflags.split_whitespace())
+        .args(["-c", "hello.c", "-o"])
+        .arg(&obj)
+        .status()
+        .expect("Failed to run C compiler");
+    assert!(status.success(), "Failed to compile hello.c with {cc}");
+
+    let lib = out_dir.join("hello.lib");
+    let status = tool_command(&ar)
+        .arg(format!("/OUT:{}", lib.display()))
+        .arg(&obj)
+        .status()
+        .expect("Failed to run archiver");
+    assert!(status.success(), "Failed to archive hello.obj with {ar}");
+
+    println!("cargo:rustc-link-search=native={}", out_dir.display());
+    println!("cargo:rustc-link-lib=static=hello");
+    println!("cargo:rerun-if-changed=hello.c");
+}
+"#;
+
+const VERIFY_HELLO_C: &str = r#"#include <stdio.h>
+#include <windows.h>
+
+int xwin_verify(void) {
+    printf("hello from C\n");
+    return GetCurrentProcessId() != 0;
+}
+"#;
+
+const VERIFY_MAIN_RS: &str = r#"extern "C" {
+    fn xwin_verify() -> i32;
+}
+
+fn main() {
+    assert_eq!(unsafe { xwin_verify() }, 1);
+}
+"#;
+
+/// Verify the cached MSVC toolchain by building a small C and Rust program
+#[derive(Clone, Debug, Default, Parser)]
+#[command(display_order = 1)]
+pub struct Verify {
+    #[command(flatten)]
+    pub xwin: XWinOptions,
+
+    /// Target triples to verify
+    #[arg(
+        long,
+        value_name = "TRIPLE",
+        action = ArgAction::Append,
+        default_value = "x86_64-pc-windows-msvc",
+    )]
+    pub target: Vec<String>,
+
+    /// Coloring: auto, always, never
+    #[arg(long, value_name = "WHEN")]
+    pub color: Option<String>,
+}
+
+impl Verify {
+    /// Execute the toolchain verification
+    pub fn execute(&self) -> Result<()> {
+        let project_dir = env::temp_dir().join(format!("cargo-xwin-verify-{}", process::id()));
+        write_verify_project(&project_dir).context("Failed to create verify project")?;
+        let result = self.verify_targets(&project_dir);
+        let _ = fs::remove_dir_all(&project_dir);
+        result
+    }
+
+    fn verify_targets(&self, project_dir: &Path) -> Result<()> {
+        let manifest_path = project_dir.join("Cargo.toml");
+        let color = use_color(self.color.as_deref());
+        let mut failed_targets = Vec::new();
+        for target in &self.target {
+            let build = cargo_options::Build {
+                common: cargo_options::CommonOptions {
+                    target: vec![target.clone()],
+                    target_dir: Some(project_dir.join("target")),
+                    ..Default::default()
+                },
+                manifest_path: Some(manifest_path.clone()),
+                ..Default::default()
+            };
+            let mut cmd = build.command();
+            if let Err(err) =
+                self.xwin
+                    .apply_command_env(Some(&manifest_path), &build.common, &mut cmd)
+            {
+                eprintln!(
+                    "{}",
+                    decorate(
+                        color,
+                        "❌",
+                        &format!("{target}: failed to setup toolchain: {err:#}")
+                    )
+                );
+                failed_targets.push(target.as_str());
+                continue;
+            }
+            let output = cmd.output().context("Failed to run cargo build")?;
+            if output.status.success() {
+                eprintln!("{}", decorate(color, "✅", &format!("{target}: ok")));
+            } else {
+                eprintln!(
+                    "{}",
+                    decorate(color, "❌", &format!("{target}: failed to build and link"))
+                );
+                eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+                failed_targets.push(target.as_str());
+            }
+        }
+        if !failed_targets.is_empty() {
+            bail!(
+                "Failed to verify toolchain for {}",
+                failed_targets.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Writes a throwaway crate with a C dependency into `project_dir`
+fn write_verify_project(project_dir: &Path) -> Result<()> {
+    let src_dir = project_dir.join("src");
+    fs::create_dir_all(&src_dir)?;
+    fs::write(project_dir.join("Cargo.toml"), VERIFY_CARGO_TOML)?;
+    fs::write(project_dir.join("build.rs"), VERIFY_BUILD_RS)?;
+    fs::write(project_dir.join("hello.c"), VERIFY_HELLO_C)?;
+    fs::write(src_dir.join("main.rs"), VERIFY_MAIN_RS)?;
+    Ok(())
+}
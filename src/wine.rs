@@ -0,0 +1,304 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use clap::builder::{PossibleValuesParser, TypedValueParser as _};
+use clap::Parser;
+use fs_err as fs;
+
+use crate::compiler::common::{profile_dir_name, resolve_target_dir, split_targets};
+use crate::options::env_flag;
+
+/// wine runner options
+#[derive(Clone, Debug, Default, Parser)]
+pub struct WineOptions {
+    /// Run binaries with 32-bit `wine` or 64-bit `wine64`, defaults to the target architecture
+    #[arg(
+        long,
+        env = "XWIN_WINE_BITS",
+        value_parser = PossibleValuesParser::new(["32", "64"]).map(|s| s.parse::<u8>().unwrap()),
+    )]
+    pub wine_bits: Option<u8>,
+
+    /// Set the wine runner with `--config target.<triple>.runner` instead of environment variables,
+    /// runners configured in cargo config files take precedence
+    #[arg(long, env = "XWIN_WINE_RUNNER_CONFIG", value_parser = env_flag())]
+    pub wine_runner_config: bool,
+
+    /// Command wine is run with on aarch64 hosts for x86 targets, e.g. `box64` or `arch -x86_64`,
+    /// defaults to box64 on Linux and Rosetta on macOS, an empty value runs wine directly
+    #[arg(long, env = "XWIN_WINE_ARCH_BRIDGE", value_name = "COMMAND")]
+    pub wine_arch_bridge: Option<String>,
+
+    /// Set an environment variable for the binaries run with wine, e.g. `WINEDLLOVERRIDES=...`,
+    /// can be repeated and takes precedence over the `WINEDEBUG=-all` default
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_wine_env)]
+    pub wine_env: Vec<(String, String)>,
+
+    /// Copy this wine prefix, e.g. one with fonts and registry keys for GUI tests, into a
+    /// `WINEPREFIX` in the target directory, it's copied again when the template changes
+    #[arg(long, env = "XWIN_WINE_PREFIX_TEMPLATE", value_name = "DIR")]
+    pub wine_prefix_template: Option<PathBuf>,
+}
+
+fn parse_wine_env(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got `{s}`")),
+    }
+}
+
+/// Sets up the environment to run binaries of MSVC targets with wine on non-Windows hosts.
+///
+/// Unless already set by the user, `WINEDEBUG` is set to `-all` and `wine` or `wine64` is
+/// used as the `CARGO_TARGET_<triple>_RUNNER`. The output directories of the workspace target
+/// directory are added to `WINEPATH` so that DLLs next to the binaries can be found.
+/// With `--wine-prefix-template`, `WINEPREFIX` is seeded from the template when it changes.
+/// The `--wine-env` variables are set last.
+pub(crate) fn setup_wine_env(
+    cmd: &mut Command,
+    manifest_path: Option<&Path>,
+    cargo: &cargo_options::CommonOptions,
+    release: bool,
+    wine: &WineOptions,
+) -> Result<()> {
+    // Windows hosts run the binaries natively, Windows on ARM emulates x86 and x86_64
+    if cfg!(windows) {
+        return Ok(());
+    }
+    let msvc_targets: Vec<_> = split_targets(&cargo.target)
+        .into_iter()
+        .filter(|target| target.contains("msvc"))
+        .collect();
+    if msvc_targets.is_empty() {
+        return Ok(());
+    }
+
+    let target_dir = resolve_target_dir(manifest_path, cargo)?;
+    let profile_dir = profile_dir_name(release, cargo.profile.as_deref());
+    let mut wine_paths = Vec::new();
+    for target in &msvc_targets {
+        if env::var_os("WINEDEBUG").is_none() {
+            cmd.env("WINEDEBUG", "-all");
+        }
+        let env_target = target.to_uppercase().replace('-', "_");
+        let runner_env = format!("CARGO_TARGET_{}_RUNNER", env_target);
+        if !wine.wine_runner_config && env::var_os(&runner_env).is_none() {
+            cmd.env(runner_env, wine_runner(target, wine)?);
+        }
+
+        let out_dir = target_dir.join(target).join(profile_dir);
+        wine_paths.push(wine_path(&out_dir));
+        wine_paths.push(wine_path(&out_dir.join("deps")));
+    }
+    if let Some(user_wine_path) = env::var("WINEPATH").ok().filter(|x| !x.is_empty()) {
+        wine_paths.push(user_wine_path);
+    }
+    cmd.env("WINEPATH", wine_paths.join(";"));
+    if let Some(template) = &wine.wine_prefix_template {
+        cmd.env("WINEPREFIX", seed_wine_prefix(template, &target_dir)?);
+    }
+    cmd.envs(wine.wine_env.iter().map(|(key, value)| (key, value)));
+    Ok(())
+}
+
+/// Seeds `<target-dir>/wine-prefix` from the `--wine-prefix-template`.
+///
+/// The prefix is kept between runs, so that wine doesn't have to boot a fresh one every time,
+/// and only copied again when it's missing or the template changed since it was seeded.
+fn seed_wine_prefix(template: &Path, target_dir: &Path) -> Result<PathBuf> {
+    if env::var_os("WINEPREFIX").is_some_and(|prefix| !prefix.is_empty()) {
+        bail!("--wine-prefix-template can't be used with WINEPREFIX set, unset one of them");
+    }
+    if !template.join("system.reg").is_file() {
+        bail!(
+            "Wine prefix template {} doesn't contain a `system.reg`, create it with \
+             `WINEPREFIX=<dir> wineboot --init`",
+            template.display()
+        );
+    }
+    let prefix = target_dir.join("wine-prefix");
+    let stamp_path = prefix.join(".cargo-xwin-template");
+    let stamp = template_stamp(template)?;
+    if fs::read_to_string(&stamp_path).is_ok_and(|seeded| seeded == stamp) {
+        return Ok(prefix);
+    }
+    if prefix.exists() {
+        fs::remove_dir_all(&prefix)?;
+    }
+    copy_dir(template, &prefix).context("Failed to copy the wine prefix template")?;
+    fs::write(&stamp_path, stamp)?;
+    Ok(prefix)
+}
+
+/// Identifies a wine prefix template by its path and the size and modification time of its
+/// top-level entries, wine rewrites the `*.reg` registry files whenever the prefix is changed.
+fn template_stamp(template: &Path) -> Result<String> {
+    let mut stamp = format!("{}\n", template.canonicalize()?.display());
+    let mut entries = fs::read_dir(template)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let modified = metadata
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        stamp.push_str(&format!(
+            "{} {} {}\n",
+            entry.file_name().to_string_lossy(),
+            metadata.len(),
+            modified.as_nanos()
+        ));
+    }
+    Ok(stamp)
+}
+
+/// Copies `src` to `dst` recursively, symlinks such as the `dosdevices` drives are kept as is
+fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let dst = dst.join(entry.file_name());
+        if file_type.is_symlink() {
+            #[cfg(unix)]
+            fs::os::unix::fs::symlink(fs::read_link(entry.path())?, &dst)?;
+        } else if file_type.is_dir() {
+            copy_dir(&entry.path(), &dst)?;
+        } else {
+            fs::copy(entry.path(), &dst)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the `--config` values setting the wine runner of MSVC targets for `--wine-runner-config`.
+///
+/// Targets that already have a runner configured, either in cargo config files or with
+/// `CARGO_TARGET_<triple>_RUNNER`, are left alone.
+pub(crate) fn wine_runner_configs(
+    manifest_path: Option<&Path>,
+    cargo: &cargo_options::CommonOptions,
+    wine: &WineOptions,
+) -> Result<Vec<String>> {
+    if cfg!(windows) || !wine.wine_runner_config {
+        return Ok(Vec::new());
+    }
+    let workdir = match manifest_path
+        .and_then(|p| p.parent())
+        .filter(|p| !p.as_os_str().is_empty())
+    {
+        Some(dir) => dir.to_path_buf(),
+        None => env::current_dir()?,
+    };
+    let cargo_config = cargo_config2::Config::load_with_cwd(workdir)?;
+    let mut configs = Vec::new();
+    for target in split_targets(&cargo.target) {
+        if !target.contains("msvc") || cargo_config.runner(target.as_str())?.is_some() {
+            continue;
+        }
+        // Split like cargo splits the runner environment variable
+        let runner: toml_edit::Array = wine_runner(&target, wine)?.split_whitespace().collect();
+        configs.push(format!("target.{target}.runner={runner}"));
+    }
+    Ok(configs)
+}
+
+/// Selects the wine runner for `target` based on the host and target architecture.
+///
+/// 32-bit targets are run with `wine`, 64-bit targets with `wine64` if it's installed,
+/// otherwise with `wine` which supports both on recent versions. wine can only run binaries
+/// of the host architecture, on aarch64 hosts x86 binaries are run through `--wine-arch-bridge`,
+/// which defaults to the box64 emulator on Linux if it's installed and to Rosetta on macOS.
+pub(crate) fn wine_runner(target: &str, wine_options: &WineOptions) -> Result<String> {
+    let target_arch = target
+        .split_once('-')
+        .map(|(arch, _)| arch)
+        .unwrap_or(target);
+    let bits = wine_options.wine_bits.unwrap_or(match target_arch {
+        "i586" | "i686" => 32,
+        _ => 64,
+    });
+    let wine = if bits == 64 && which::which("wine64").is_ok() {
+        "wine64"
+    } else {
+        "wine"
+    };
+    which::which(wine).with_context(|| {
+        format!("Failed to find `{wine}` in PATH, please install {bits}-bit wine to run {target} binaries")
+    })?;
+    let is_x86 = matches!(target_arch, "x86_64" | "i586" | "i686");
+    if env::consts::ARCH != "aarch64" || !is_x86 {
+        return Ok(wine.to_string());
+    }
+    match wine_options.wine_arch_bridge.as_deref().map(str::trim) {
+        Some("") => Ok(wine.to_string()),
+        Some(bridge) => Ok(format!("{bridge} {wine}")),
+        None if cfg!(target_os = "macos") => {
+            // wine builds for macOS are x86_64 binaries, arm64 ones can't load x86 code
+            if !Path::new("/Library/Apple/usr/share/rosetta/rosetta").exists() {
+                bail!(
+                    "Running {target} binaries on Apple Silicon requires Rosetta, install it with \
+                     `softwareupdate --install-rosetta` or set `--wine-arch-bridge`"
+                );
+            }
+            Ok(format!("arch -x86_64 {wine}"))
+        }
+        None if target_arch == "x86_64" && which::which("box64").is_ok() => {
+            Ok(format!("box64 {wine}"))
+        }
+        None => Ok(wine.to_string()),
+    }
+}
+
+/// Translates a host path into a path usable by programs running under wine.
+///
+/// Wine maps the host root directory to the `Z:` drive by default.
+pub(crate) fn wine_path(path: &Path) -> String {
+    if cfg!(windows) {
+        path.display().to_string()
+    } else {
+        format!("Z:{}", path.display().to_string().replace('/', "\\"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seed_wine_prefix_when_template_changes() {
+        let dir = env::temp_dir().join(format!("cargo-xwin-wine-prefix-{}", std::process::id()));
+        let template = dir.join("template");
+        let target_dir = dir.join("target");
+        fs::create_dir_all(template.join("drive_c")).unwrap();
+        fs::write(template.join("system.reg"), "WINE REGISTRY Version 2\n").unwrap();
+
+        let prefix = seed_wine_prefix(&template, &target_dir).unwrap();
+        assert_eq!(prefix, target_dir.join("wine-prefix"));
+        assert!(prefix.join("system.reg").is_file());
+        assert!(prefix.join("drive_c").is_dir());
+
+        // an unchanged template keeps what the binaries wrote into the prefix
+        fs::write(prefix.join("user.reg"), "written by wine").unwrap();
+        seed_wine_prefix(&template, &target_dir).unwrap();
+        assert!(prefix.join("user.reg").is_file());
+
+        // a changed template seeds the prefix again
+        fs::write(
+            template.join("system.reg"),
+            "WINE REGISTRY Version 2\n[Fonts]\n",
+        )
+        .unwrap();
+        seed_wine_prefix(&template, &target_dir).unwrap();
+        assert!(!prefix.join("user.reg").exists());
+        assert_eq!(
+            fs::read_to_string(prefix.join("system.reg")).unwrap(),
+            "WINE REGISTRY Version 2\n[Fonts]\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
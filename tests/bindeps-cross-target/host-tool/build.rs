@@ -0,0 +1,12 @@
+use std::env;
+
+fn main() {
+    // Cargo passes the rustflags of the target being built to build scripts
+    let rustflags = env::var("CARGO_ENCODED_RUSTFLAGS").unwrap_or_default();
+    for flag in rustflags.split('\x1f') {
+        assert!(
+            !flag.contains("lld-link") && !flag.starts_with("-Lnative="),
+            "MSVC rustflag `{flag}` leaked into the host tool build"
+        );
+    }
+}
@@ -0,0 +1,3 @@
+fn main() {
+    println!("host tool: {}", env!("CARGO_BIN_FILE_HOST_TOOL"));
+}
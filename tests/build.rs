@@ -0,0 +1,1095 @@
+//! Builds the fixture crates under `tests/` with `cargo xwin build`.
+//!
+//! These tests download the MSVC CRT and Windows SDK and require the
+//! `x86_64-pc-windows-msvc` Rust target to be installed, so they are ignored by default.
+//! Run them with `cargo test --test build -- --ignored`.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+const TARGET: &str = "x86_64-pc-windows-msvc";
+
+/// Serializes builds so that the shared cache directory is only populated once
+static CACHE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Serializes the tests sharing the cache directory, see [`CACHE_LOCK`]
+fn lock_cache() -> MutexGuard<'static, ()> {
+    CACHE_LOCK.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Returns a `cargo-xwin` command using the shared cache directory with the license accepted
+fn xwin_command() -> Command {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_cargo-xwin"));
+    cmd.env(
+        "XWIN_CACHE_DIR",
+        Path::new(env!("CARGO_TARGET_TMPDIR")).join("xwin-cache"),
+    )
+    .env("XWIN_ACCEPT_LICENSE", "1");
+    cmd
+}
+
+/// Builds `fixture` in a temporary target directory and returns the output directory
+fn xwin_build(fixture: &str) -> PathBuf {
+    xwin_build_with_args(fixture, fixture, &[])
+}
+
+/// Builds `fixture` with extra cargo `args` in the temporary target directory `name`
+fn xwin_build_with_args(fixture: &str, name: &str, args: &[&str]) -> PathBuf {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let manifest_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join(fixture)
+        .join("Cargo.toml");
+    let target_dir = tmp_dir.join(name);
+    let status = xwin_command()
+        .args(["xwin", "build", "--target", TARGET])
+        .args(args)
+        .arg("--manifest-path")
+        .arg(&manifest_path)
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .status()
+        .expect("Failed to run cargo-xwin");
+    assert!(status.success(), "Failed to build {fixture}");
+    target_dir.join(TARGET).join("debug")
+}
+
+#[test]
+#[ignore]
+fn build_libhello() {
+    let out_dir = xwin_build("libhello");
+    assert!(out_dir.join("hello.dll").is_file());
+}
+
+#[test]
+#[ignore]
+fn build_libhello_staticlib() {
+    let out_dir = xwin_build("libhello-static");
+    let archive = fs_err::read(out_dir.join("hello_static.lib")).unwrap();
+    assert!(archive.starts_with(b"!<arch>\n"), "not an archive");
+    // The C object archived by the build script is bundled into the staticlib
+    for symbol in [&b"hello_from_rust"[..], b"hello_from_c"] {
+        assert!(
+            archive.windows(symbol.len()).any(|window| window == symbol),
+            "{} not found in the archive",
+            String::from_utf8_lossy(symbol)
+        );
+    }
+}
+
+#[test]
+#[ignore]
+fn build_hello_windows() {
+    let out_dir = xwin_build("hello-windows");
+    assert!(out_dir.join("hello-windows.exe").is_file());
+}
+
+#[test]
+#[ignore]
+fn build_hello_windows_panic_abort() {
+    let out_dir = xwin_build_with_args(
+        "hello-windows",
+        "hello-windows-panic-abort",
+        &["--config", "profile.dev.panic=\"abort\""],
+    );
+    assert!(out_dir.join("hello-windows.exe").is_file());
+}
+
+#[test]
+#[ignore]
+fn build_hello_windows_link_map() {
+    let out_dir = xwin_build_with_args(
+        "hello-windows",
+        "hello-windows-link-map",
+        &["--emit-link-map"],
+    );
+    // lld-link writes the map next to the binary cargo links in `deps`
+    let link_map = fs_err::read_dir(out_dir.join("deps"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            name.starts_with("hello_windows-") && name.ends_with(".map")
+        });
+    assert!(link_map.is_some(), "linker map file not found");
+}
+
+#[test]
+#[ignore]
+fn build_hello_windows_codegen_options() {
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let manifest_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("hello-codegen")
+        .join("Cargo.toml");
+    let target_env = format!(
+        "CARGO_TARGET_{}_RUSTFLAGS",
+        TARGET.to_uppercase().replace('-', "_")
+    );
+    // hello-codegen exits with 1 for debug assertions and 2 for overflow checks, both are off
+    // in release builds unless the rustflags enable them
+    for (name, rustflags, exit_code) in [
+        ("opt-level-z", "-C opt-level=z", 0),
+        ("relocation-model-pic", "-C relocation-model=pic", 0),
+        ("overflow-checks", "-C overflow-checks=on", 2),
+        ("debug-assertions", "-C debug-assertions=on", 3),
+    ] {
+        let config = format!(
+            "build.rustflags={}",
+            rustflags.split(' ').collect::<toml_edit::Array>()
+        );
+        // The linker flags of cargo-xwin are added to the user's codegen options, whether they
+        // come from `RUSTFLAGS`, the target specific variable or a cargo config file
+        for source in ["RUSTFLAGS", target_env.as_str(), "config"] {
+            let _guard = lock_cache();
+            let mut cmd = xwin_command();
+            cmd.args(["xwin", "run", "--release", "--target", TARGET])
+                .arg("--manifest-path")
+                .arg(&manifest_path)
+                .arg("--target-dir")
+                .arg(tmp_dir.join(format!("hello-codegen-{name}-{source}")));
+            if source == "config" {
+                cmd.args(["--config", &config]);
+            } else {
+                cmd.env(source, rustflags);
+            }
+            let status = cmd.status().expect("Failed to run cargo-xwin");
+            assert_eq!(
+                status.code(),
+                Some(exit_code),
+                "{rustflags} from {source} isn't applied"
+            );
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn build_hello_windows_reproducible() {
+    let first = xwin_build_with_args(
+        "hello-windows",
+        "hello-windows-reproducible-1",
+        &["--reproducible"],
+    );
+    let second = xwin_build_with_args(
+        "hello-windows",
+        "hello-windows-reproducible-2",
+        &["--reproducible"],
+    );
+    let first = fs_err::read(first.join("hello-windows.exe")).unwrap();
+    let second = fs_err::read(second.join("hello-windows.exe")).unwrap();
+    assert!(first == second, "reproducible builds differ");
+}
+
+#[test]
+#[ignore]
+fn build_hello_windows_profile_static_crt() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let manifest_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("hello-windows")
+        .join("Cargo.toml");
+    let target_dir = tmp_dir.join("hello-windows-profile-static-crt");
+    // Only the `dist` profile links the static CRT, profile rustflags are unstable
+    for profile in ["release", "dist"] {
+        let status = xwin_command()
+            .args(["xwin", "build", "--target", TARGET, "--profile", profile])
+            .args(["-Z", "profile-rustflags", "--config"])
+            .arg(r#"profile.dist.inherits="release""#)
+            .arg("--config")
+            .arg(r#"profile.dist.rustflags=["-C", "target-feature=+crt-static"]"#)
+            .arg("--manifest-path")
+            .arg(&manifest_path)
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .env("RUSTC_BOOTSTRAP", "1")
+            .status()
+            .expect("Failed to run cargo-xwin");
+        assert!(status.success(), "Failed to build with --profile {profile}");
+    }
+    // The dynamic CRT is imported from vcruntime140.dll, the static one is linked in
+    let imports_vcruntime = |profile: &str| {
+        let exe = fs_err::read(
+            target_dir
+                .join(TARGET)
+                .join(profile)
+                .join("hello-windows.exe"),
+        )
+        .unwrap()
+        .to_ascii_lowercase();
+        exe.windows(b"vcruntime140.dll".len())
+            .any(|window| window == b"vcruntime140.dll")
+    };
+    assert!(imports_vcruntime("release"));
+    assert!(!imports_vcruntime("dist"));
+}
+
+#[test]
+#[ignore]
+fn build_hello_tls() {
+    let out_dir = xwin_build("hello-tls");
+    assert!(out_dir.join("hello-tls.exe").is_file());
+}
+
+#[test]
+#[ignore]
+fn build_hello_host_cc() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let manifest_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("hello-host-cc")
+        .join("Cargo.toml");
+    let target_dir = tmp_dir.join("hello-host-cc");
+    // A globally exported `CC` is the host compiler, the build script of the `host-c`
+    // build dependency must use it while the Windows target is compiled with clang-cl
+    let status = xwin_command()
+        .args(["xwin", "build", "--target", TARGET])
+        .arg("--manifest-path")
+        .arg(&manifest_path)
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .env("CC", "cc")
+        .status()
+        .expect("Failed to run cargo-xwin");
+    assert!(status.success(), "Failed to build hello-host-cc");
+    assert!(target_dir
+        .join(TARGET)
+        .join("debug")
+        .join("hello-host-cc.exe")
+        .is_file());
+}
+
+#[test]
+#[ignore]
+fn build_hello_rustls() {
+    let out_dir = xwin_build("hello-rustls");
+    assert!(out_dir.join("hello-rustls.exe").is_file());
+}
+
+#[test]
+#[ignore]
+fn build_hello_cmake_custom_target_dir() {
+    let out_dir = xwin_build("hello-cmake");
+    assert!(out_dir.join("hello-cmake.exe").is_file());
+    // The CMake build directory lives in `OUT_DIR` under the custom target directory
+    let cmake_cache = fs_err::read_dir(out_dir.join("build"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|dir| {
+            dir.file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("hello-cmake-")
+        })
+        .map(|dir| dir.join("out").join("build").join("CMakeCache.txt"))
+        .find(|file| file.is_file());
+    assert!(cmake_cache.is_some(), "CMake build directory not found");
+}
+
+#[test]
+#[ignore]
+fn build_hello_cmake_multiple_targets() {
+    const AARCH64_TARGET: &str = "aarch64-pc-windows-msvc";
+    let out_dir = xwin_build_with_args(
+        "hello-cmake",
+        "hello-cmake-multiple-targets",
+        &["--target", AARCH64_TARGET],
+    );
+    let target_dir = out_dir.parent().unwrap().parent().unwrap();
+    for (target, other) in [(TARGET, AARCH64_TARGET), (AARCH64_TARGET, TARGET)] {
+        let out_dir = target_dir.join(target).join("debug");
+        assert!(out_dir.join("hello-cmake.exe").is_file());
+        let cmake_cache = fs_err::read_dir(out_dir.join("build"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|dir| {
+                dir.file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .starts_with("hello-cmake-")
+            })
+            .map(|dir| dir.join("out").join("build").join("CMakeCache.txt"))
+            .find(|file| file.is_file())
+            .expect("CMake build directory not found");
+        let cmake_cache = fs_err::read_to_string(cmake_cache).unwrap();
+        // Each target is configured with its own toolchain file
+        assert!(cmake_cache.contains(&format!("{target}-toolchain.cmake")));
+        assert!(!cmake_cache.contains(&format!("{other}-toolchain.cmake")));
+    }
+}
+
+#[test]
+#[ignore]
+#[cfg(unix)]
+fn build_hello_cmake_relocated_cache() {
+    xwin_build("hello-cmake");
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let cache_dir = tmp_dir.join("xwin-cache");
+    let output = xwin_command()
+        .args(["xwin", "env", "--target", TARGET, "--relocatable-cache"])
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success());
+    let exports = String::from_utf8(output.stdout).unwrap();
+
+    // Build with plain cargo and the exported variables, the toolchain files aren't regenerated
+    let moved_cache_dir = tmp_dir.join("xwin-cache-moved");
+    fs_err::rename(&cache_dir, &moved_cache_dir).unwrap();
+    let manifest_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("hello-cmake")
+        .join("Cargo.toml");
+    let target_dir = tmp_dir.join("hello-cmake-relocated-cache");
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!(
+            "{exports}\ncargo build --target {TARGET} --manifest-path \"$0\" --target-dir \"$1\""
+        ))
+        .arg(&manifest_path)
+        .arg(&target_dir)
+        .env("XWIN_CACHE_DIR", &moved_cache_dir)
+        .status()
+        .expect("Failed to run cargo");
+    fs_err::rename(&moved_cache_dir, &cache_dir).unwrap();
+    assert!(
+        status.success(),
+        "Failed to build hello-cmake with the moved cache"
+    );
+    assert!(target_dir
+        .join(TARGET)
+        .join("debug")
+        .join("hello-cmake.exe")
+        .is_file());
+}
+
+#[test]
+#[ignore]
+#[cfg(unix)]
+fn build_hello_cmake_read_only_cache() {
+    use std::os::unix::fs::PermissionsExt;
+
+    xwin_build("hello-cmake");
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    // A cache directory with only the splatted CRT and SDK, without symlinks or toolchain files
+    let cache_dir = tmp_dir.join("xwin-cache-read-only");
+    if cache_dir.exists() {
+        fs_err::set_permissions(&cache_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        fs_err::remove_dir_all(&cache_dir).unwrap();
+    }
+    fs_err::create_dir(&cache_dir).unwrap();
+    fs_err::os::unix::fs::symlink(
+        tmp_dir.join("xwin-cache").join("xwin"),
+        cache_dir.join("xwin"),
+    )
+    .unwrap();
+    fs_err::set_permissions(&cache_dir, std::fs::Permissions::from_mode(0o555)).unwrap();
+
+    let manifest_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("hello-cmake")
+        .join("Cargo.toml");
+    let target_dir = tmp_dir.join("hello-cmake-read-only-cache");
+    let status = xwin_command()
+        .args(["xwin", "build", "--target", TARGET, "--no-symlink-tools"])
+        .arg("--manifest-path")
+        .arg(&manifest_path)
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .env("XWIN_CACHE_DIR", &cache_dir)
+        .status()
+        .expect("Failed to run cargo-xwin");
+    let entries: Vec<_> = fs_err::read_dir(&cache_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    fs_err::set_permissions(&cache_dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+    assert!(status.success(), "Failed to build with a read-only cache");
+    assert_eq!(entries, ["xwin"], "the cache directory was written to");
+    assert!(target_dir.join("xwin-tools").join("clang-cl").is_symlink());
+    assert!(target_dir
+        .join("xwin-cmake")
+        .join("clang-cl")
+        .join(format!("{TARGET}-toolchain.cmake"))
+        .is_file());
+    assert!(target_dir
+        .join(TARGET)
+        .join("debug")
+        .join("hello-cmake.exe")
+        .is_file());
+}
+
+#[test]
+#[ignore]
+fn test_doctests_under_wine() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let manifest_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("hello-doctest")
+        .join("Cargo.toml");
+    let output = xwin_command()
+        .args(["xwin", "test", "--doc", "--target", TARGET])
+        .arg("--manifest-path")
+        .arg(&manifest_path)
+        .arg("--target-dir")
+        .arg(tmp_dir.join("hello-doctest"))
+        .output()
+        .expect("Failed to run cargo-xwin");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // The doctest is linked for the target and run with wine instead of being skipped
+    assert!(stdout.contains("1 passed"), "{stdout}");
+}
+
+#[test]
+#[ignore]
+fn rustc_emit_without_linking() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let manifest_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("hello-windows")
+        .join("Cargo.toml");
+    let target_dir = tmp_dir.join("hello-windows-emit");
+    let lto_cache_dir = tmp_dir.join("emit-lto-cache");
+    for emit in ["obj", "llvm-ir"] {
+        // Link-only options are skipped when nothing is linked
+        let status = xwin_command()
+            .args(["xwin", "rustc", "--target", TARGET])
+            .arg("--lto-cache-dir")
+            .arg(&lto_cache_dir)
+            .arg("--manifest-path")
+            .arg(&manifest_path)
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .args(["--", &format!("--emit={emit}")])
+            .status()
+            .expect("Failed to run cargo-xwin");
+        assert!(status.success(), "Failed to emit {emit}");
+    }
+    let deps_dir = target_dir.join(TARGET).join("debug").join("deps");
+    let emitted: Vec<_> = fs_err::read_dir(&deps_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    for ext in ["o", "ll"] {
+        assert!(
+            emitted
+                .iter()
+                .any(|path| path.extension() == Some(ext.as_ref())),
+            "no .{ext} file emitted"
+        );
+    }
+    assert!(!lto_cache_dir.exists());
+}
+
+#[test]
+#[ignore]
+fn build_message_format_json_stdout() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let manifest_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("hello-windows")
+        .join("Cargo.toml");
+    // A fresh cache makes sure the download progress is printed during the build
+    let output = xwin_command()
+        .args([
+            "xwin",
+            "build",
+            "--message-format",
+            "json",
+            "--target",
+            TARGET,
+        ])
+        .arg("--manifest-path")
+        .arg(&manifest_path)
+        .arg("--target-dir")
+        .arg(tmp_dir.join("message-format-json"))
+        .env("XWIN_CACHE_DIR", tmp_dir.join("xwin-cache-json"))
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success(), "Failed to build hello-windows");
+    // IDEs parse every line of stdout as a cargo json message
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for line in stdout.lines() {
+        serde_json::from_str::<serde_json::Value>(line)
+            .unwrap_or_else(|err| panic!("non-json line on stdout: {line:?}: {err}"));
+    }
+    assert!(stdout.contains("\"reason\":\"build-finished\""));
+}
+
+#[test]
+#[ignore]
+fn cache_xwin_variant_change_splats_again() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let cache_dir = tmp_dir.join("xwin-cache-variants");
+    for variant in ["desktop", "onecore"] {
+        let status = xwin_command()
+            .args(["xwin", "cache", "xwin", "--xwin-arch", "x86_64"])
+            .env("XWIN_CACHE_DIR", &cache_dir)
+            .env("XWIN_VARIANT", variant)
+            .status()
+            .expect("Failed to run cargo-xwin");
+        assert!(status.success(), "Failed to cache the {variant} variant");
+    }
+    let xwin_dir = cache_dir.join("xwin");
+    assert_eq!(
+        fs_err::read_to_string(xwin_dir.join("VARIANTS")).unwrap(),
+        "onecore"
+    );
+    assert!(xwin_dir.join("crt/lib/onecore/x86_64").is_dir());
+    // The desktop libs of the first splat must not be picked up
+    assert!(!xwin_dir.join("crt/lib/x86_64").exists());
+}
+
+/// Starts an HTTPS proxy that closes the first tunnel to `host` after relaying `cut_after` bytes
+fn flaky_proxy(host: &'static str, cut_after: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let cut = Arc::new(AtomicBool::new(false));
+    thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            let cut = cut.clone();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(client.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let authority = request.split_whitespace().nth(1).unwrap().to_string();
+                let Ok(upstream) = TcpStream::connect(&authority) else {
+                    return;
+                };
+                let mut client = client;
+                client
+                    .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                    .unwrap();
+                let mut upstream_writer = upstream.try_clone().unwrap();
+                thread::spawn(move || std::io::copy(&mut reader, &mut upstream_writer));
+
+                let flaky = authority.starts_with(host) && !cut.swap(true, Ordering::SeqCst);
+                let mut upstream = upstream;
+                let mut relayed = 0;
+                let mut buf = [0; 8192];
+                while let Ok(n @ 1..) = upstream.read(&mut buf) {
+                    if client.write_all(&buf[..n]).is_err() {
+                        break;
+                    }
+                    relayed += n;
+                    if flaky && relayed >= cut_after {
+                        break;
+                    }
+                }
+                let _ = client.shutdown(Shutdown::Both);
+                let _ = upstream.shutdown(Shutdown::Both);
+            });
+        }
+    });
+    addr
+}
+
+#[test]
+#[ignore]
+fn cache_xwin_retries_truncated_downloads() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let cache_dir = tmp_dir.join("xwin-cache-flaky");
+    if cache_dir.exists() {
+        fs_err::remove_dir_all(&cache_dir).unwrap();
+    }
+    let proxy = flaky_proxy("download.visualstudio.microsoft.com", 64 * 1024);
+    let output = xwin_command()
+        .args(["xwin", "cache", "xwin", "--xwin-arch", "x86_64"])
+        .env("XWIN_CACHE_DIR", &cache_dir)
+        .env("HTTPS_PROXY", format!("http://{proxy}"))
+        .output()
+        .expect("Failed to run cargo-xwin");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("Retrying download"), "{stderr}");
+    assert!(cache_dir.join("xwin/crt/lib/x86_64/msvcrt.lib").is_file());
+}
+
+#[test]
+#[ignore]
+fn cache_xwin_new_arch_is_incremental() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let cache_dir = tmp_dir.join("xwin-cache-incremental");
+    let cache_arch = |arch: &str| {
+        let status = xwin_command()
+            .args(["xwin", "cache", "xwin", "--xwin-arch", arch])
+            .env("XWIN_CACHE_DIR", &cache_dir)
+            .status()
+            .expect("Failed to run cargo-xwin");
+        assert!(status.success(), "Failed to cache {arch}");
+    };
+    cache_arch("x86_64");
+    let xwin_dir = cache_dir.join("xwin");
+    let x86_64_lib = xwin_dir.join("crt/lib/x86_64/msvcrt.lib");
+    let modified = || fs_err::metadata(&x86_64_lib).unwrap().modified().unwrap();
+    let before = modified();
+    cache_arch("x86_64,aarch64");
+    assert!(xwin_dir.join("crt/lib/aarch64/msvcrt.lib").is_file());
+    // The x86_64 payloads aren't downloaded and splatted again
+    assert_eq!(before, modified());
+    assert_eq!(
+        fs_err::read_to_string(xwin_dir.join("DONE")).unwrap(),
+        "aarch64 x86_64"
+    );
+}
+
+#[test]
+#[ignore]
+fn cache_list() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let cache_xwin = |subcommand: &str| {
+        xwin_command()
+            .args(["xwin", "cache", subcommand])
+            .output()
+            .expect("Failed to run cargo-xwin")
+    };
+    assert!(cache_xwin("xwin").status.success());
+    let output = cache_xwin("list");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for line in [
+        "xwin: ",
+        "  Architectures: ",
+        "  MSVC CRT: ",
+        "  Windows SDK: ",
+        "  Size: ",
+    ] {
+        assert!(stdout.contains(line), "{line:?} not listed in {stdout}");
+    }
+
+    // Listing a cache directory that doesn't exist leaves it alone
+    let missing_cache_dir = tmp_dir.join("xwin-cache-missing");
+    let output = xwin_command()
+        .args(["xwin", "cache", "list"])
+        .env("XWIN_CACHE_DIR", &missing_cache_dir)
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("No toolchains cached"));
+    assert!(!missing_cache_dir.exists());
+}
+
+#[test]
+#[ignore]
+fn env_xwin_rustflags_after_linker_flags() {
+    let _guard = lock_cache();
+    let output = xwin_command()
+        .args(["xwin", "env", "--target", TARGET])
+        .args(["--xwin-rustflags", "-C target-feature=+crt-static"])
+        .env_remove("RUSTFLAGS")
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let rustflags = stdout
+        .lines()
+        .find(|line| line.starts_with("export CARGO_TARGET_X86_64_PC_WINDOWS_MSVC_RUSTFLAGS="))
+        .expect("target rustflags aren't set");
+    let linker_flavor = rustflags.find("linker-flavor=lld-link").unwrap();
+    let crt_static = rustflags.find("target-feature=+crt-static").unwrap();
+    assert!(linker_flavor < crt_static);
+}
+
+#[test]
+#[ignore]
+fn env_sdk_include_order() {
+    let _guard = lock_cache();
+    let output = xwin_command()
+        .args(["xwin", "env", "--target", TARGET])
+        .args(["--extra-include", "vendor/include"])
+        .args(["--sdk-include-order", "um,crt"])
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let cl_flags = stdout
+        .lines()
+        .find(|line| line.starts_with("export CL_FLAGS="))
+        .expect("CL_FLAGS isn't set");
+    let um = cl_flags.find("/sdk/include/um").unwrap();
+    let crt = cl_flags.find("/crt/include").unwrap();
+    let shared = cl_flags.find("/sdk/include/shared").unwrap();
+    // Unlisted directories follow in the default order
+    let extra = cl_flags.find("vendor/include").unwrap();
+    assert!(um < crt && crt < extra && extra < shared);
+}
+
+#[test]
+#[ignore]
+fn env_clang_resource_dir_runtime_libs() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let resource_dir = tmp_dir.join("clang-resource-dir");
+    fs_err::create_dir_all(resource_dir.join("lib").join("windows")).unwrap();
+    let output = xwin_command()
+        .args(["xwin", "env", "--target", TARGET])
+        .arg("--clang-resource-dir")
+        .arg(&resource_dir)
+        .env_remove("RUSTFLAGS")
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let rustflags = stdout
+        .lines()
+        .find(|line| line.starts_with("export CARGO_TARGET_X86_64_PC_WINDOWS_MSVC_RUSTFLAGS="))
+        .expect("target rustflags aren't set");
+    assert!(rustflags.contains("clang-resource-dir/lib/windows"));
+}
+
+#[test]
+#[ignore]
+fn env_preserves_cargo_and_rustc_control_vars() {
+    const PRESERVED: &[(&str, &str)] = &[
+        ("RUSTC_BOOTSTRAP", "1"),
+        ("RUSTC_WRAPPER", "sccache"),
+        ("RUSTDOCFLAGS", "--cfg docsrs"),
+        ("CARGO_INCREMENTAL", "0"),
+        (
+            "CARGO_TARGET_X86_64_PC_WINDOWS_MSVC_LINKER",
+            "/opt/llvm/bin/lld-link",
+        ),
+    ];
+    let _guard = lock_cache();
+    let output = xwin_command()
+        .args(["xwin", "env", "--target", TARGET])
+        .envs(PRESERVED.iter().copied())
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for (key, _) in PRESERVED {
+        assert!(
+            !stdout.contains(&format!("export {key}=")),
+            "{key} is overridden"
+        );
+    }
+}
+
+#[test]
+#[ignore]
+fn env_static_crt_and_control_flow_guard() {
+    let _guard = lock_cache();
+    let output = xwin_command()
+        .args([
+            "xwin",
+            "env",
+            "--target",
+            TARGET,
+            "--static-crt",
+            "--control-flow-guard",
+        ])
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let rustflags = stdout
+        .lines()
+        .find(|line| line.starts_with("export CARGO_TARGET_X86_64_PC_WINDOWS_MSVC_RUSTFLAGS="))
+        .expect("target rustflags aren't set");
+    assert!(rustflags.contains("target-feature=+crt-static"));
+    assert!(rustflags.contains("control-flow-guard"));
+    assert!(rustflags.contains("link-arg=/guard:cf"));
+    let cl_flags = stdout
+        .lines()
+        .find(|line| line.starts_with("export CL_FLAGS="))
+        .expect("CL_FLAGS isn't set");
+    assert!(cl_flags.contains("/MT") && cl_flags.contains("/guard:cf"));
+}
+
+#[test]
+#[ignore]
+fn build_failure_summary() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let output = xwin_command()
+        .args(["xwin", "build", "--target", TARGET, "--failure-summary=5"])
+        .arg("--manifest-path")
+        .arg(tmp_dir.join("missing").join("Cargo.toml"))
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let summary: serde_json::Value = serde_json::from_str(stderr.lines().last().unwrap()).unwrap();
+    assert_eq!(summary["reason"], "cargo-xwin-failure");
+    assert_eq!(summary["exit_code"], output.status.code().unwrap());
+    assert!(summary["stderr_tail"][0]
+        .as_str()
+        .unwrap()
+        .contains("does not exist"));
+}
+
+#[test]
+#[ignore]
+fn env_cmake_dir() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let cmake_dir = tmp_dir.join("cmake-dir");
+    let output = xwin_command()
+        .args(["xwin", "env", "--target", TARGET, "--cmake-dir"])
+        .arg(&cmake_dir)
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let toolchain_file = cmake_dir
+        .canonicalize()
+        .unwrap()
+        .join("clang-cl")
+        .join(format!("{TARGET}-toolchain.cmake"));
+    assert!(toolchain_file.is_file());
+    assert!(stdout.contains(&toolchain_file.display().to_string()));
+}
+
+#[test]
+#[ignore]
+fn env_cmake_system_version() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let cmake_dir = tmp_dir.join("cmake-system-version");
+    let status = xwin_command()
+        .args(["xwin", "env", "--target", TARGET])
+        .args(["--cmake-system-version", "10.0.19041.0", "--cmake-dir"])
+        .arg(&cmake_dir)
+        .status()
+        .expect("Failed to run cargo-xwin");
+    assert!(status.success());
+    let toolchain = fs_err::read_to_string(
+        cmake_dir
+            .join("clang-cl")
+            .join(format!("{TARGET}-toolchain.cmake")),
+    )
+    .unwrap();
+    assert!(toolchain.contains("set(CMAKE_SYSTEM_VERSION 10.0.19041.0)"));
+}
+
+#[test]
+#[ignore]
+fn env_target_env_file() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let env_file = tmp_dir.join("target.env");
+    let cflags_key = format!("CFLAGS_{}", TARGET.replace('-', "_"));
+    fs_err::write(
+        &env_file,
+        format!("# reviewed C flags\n{cflags_key}=\"-DHELLO=1\"\nHELLO_SDK=/opt/hello\n"),
+    )
+    .unwrap();
+    let output = xwin_command()
+        .args(["xwin", "env", "--target", TARGET, "--target-env-file"])
+        .arg(&env_file)
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let cflags = stdout
+        .lines()
+        .find(|line| line.starts_with(&format!("export {cflags_key}=")))
+        .expect("target C flags aren't set");
+    // The flags of the file come after the ones of cargo-xwin
+    assert!(cflags.contains("--target="));
+    assert!(cflags.ends_with(" -DHELLO=1\";"));
+    assert!(stdout.contains("export HELLO_SDK=\"/opt/hello\";"));
+}
+
+#[test]
+#[ignore]
+fn env_emit_env_file() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let env_file = tmp_dir.join("xwin.env");
+    let status = xwin_command()
+        .args(["xwin", "env", "--target", TARGET, "--emit-env-file"])
+        .arg(&env_file)
+        .arg("--xwin-rustflags=-Clink-arg=/DEF:$HOME\\hello.def")
+        .status()
+        .expect("Failed to run cargo-xwin");
+    assert!(status.success());
+    let content = fs_err::read_to_string(&env_file).unwrap();
+    let vars: Vec<(&str, &str)> = content
+        .lines()
+        .map(|line| {
+            let (key, value) = line.split_once('=').expect("not a KEY=VALUE line");
+            let value = value
+                .strip_prefix('\'')
+                .and_then(|value| value.strip_suffix('\''))
+                .expect("value isn't single quoted");
+            (key, value)
+        })
+        .collect();
+    let rustflags = vars
+        .iter()
+        .find(|(key, _)| *key == "CARGO_TARGET_X86_64_PC_WINDOWS_MSVC_RUSTFLAGS")
+        .expect("target rustflags aren't set")
+        .1;
+    assert!(rustflags.contains("-Clink-arg=/DEF:$HOME\\hello.def"));
+
+    // A shell sourcing the file reads every value back unchanged
+    let output = Command::new("sh")
+        .args(["-c", "set -a; . \"$1\"; env", "sh"])
+        .arg(&env_file)
+        .env_clear()
+        .output()
+        .expect("Failed to run sh");
+    assert!(output.status.success());
+    let sourced = String::from_utf8(output.stdout).unwrap();
+    for (key, value) in &vars {
+        assert!(
+            sourced.lines().any(|line| line == format!("{key}={value}")),
+            "{key} changed when sourced"
+        );
+    }
+
+    let status = xwin_command()
+        .args([
+            "xwin",
+            "env",
+            "--target",
+            TARGET,
+            "--emit",
+            "json",
+            "--emit-env-file",
+        ])
+        .arg(&env_file)
+        .status()
+        .expect("Failed to run cargo-xwin");
+    assert!(!status.success());
+}
+
+#[test]
+fn dump_config() {
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let cache_dir = tmp_dir.join("dump-config-cache");
+    let output = xwin_command()
+        .args([
+            "xwin",
+            "dump-config",
+            "--cross-compiler",
+            "clang",
+            "--static-crt",
+            "--sdk-include-order",
+            "crt,ucrt,um,shared,extra",
+        ])
+        .env("XWIN_CACHE_DIR", &cache_dir)
+        .env("XWIN_ARCH", "x86,aarch64")
+        .env("XWIN_RUSTFLAGS", "-Cdebuginfo=1 -Ctarget-cpu=native")
+        .output()
+        .expect("Failed to run cargo-xwin");
+    assert!(output.status.success());
+    let config: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let cache_dir = cache_dir.canonicalize().unwrap();
+    assert_eq!(config["cross-compiler"], "clang");
+    assert_eq!(config["static-crt"], true);
+    assert_eq!(config["accept-license"], true);
+    assert_eq!(
+        config["sdk-include-order"],
+        serde_json::json!(["crt", "ucrt", "um", "shared", "extra"])
+    );
+    assert_eq!(config["xwin-arch"], serde_json::json!(["x86", "aarch64"]));
+    assert_eq!(
+        config["xwin-rustflags"],
+        serde_json::json!(["-Cdebuginfo=1", "-Ctarget-cpu=native"])
+    );
+    assert_eq!(config["cache-dir"], cache_dir.display().to_string());
+    assert_eq!(
+        config["crt-cache-dir"],
+        cache_dir.join("xwin").display().to_string()
+    );
+}
+
+#[test]
+#[ignore]
+fn env_lto_cache_dir() {
+    let _guard = lock_cache();
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let lto_cache_dir = tmp_dir.join("lto-cache");
+    let target_rustflags = |xwin_rustflags: &str| {
+        let output = xwin_command()
+            .args(["xwin", "env", "--target", TARGET, "--lto-cache-dir"])
+            .arg(&lto_cache_dir)
+            .arg(format!("--xwin-rustflags={xwin_rustflags}"))
+            .output()
+            .expect("Failed to run cargo-xwin");
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .find(|line| line.starts_with("export CARGO_TARGET_X86_64_PC_WINDOWS_MSVC_RUSTFLAGS="))
+            .expect("target rustflags aren't set")
+            .to_string()
+    };
+    // The cache is only used when lld-link links bitcode
+    assert!(!target_rustflags("-Cdebuginfo=1").contains("/lldltocache:"));
+    assert!(target_rustflags("-Clinker-plugin-lto").contains("link-arg=/lldltocache:"));
+    assert!(lto_cache_dir.is_dir());
+}
+
+#[test]
+#[ignore]
+fn build_hello_windows_lto_cache_reused() {
+    let tmp_dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let lto_cache_dir = tmp_dir.join("hello-windows-lto-cache");
+    for dir in [
+        "hello-windows-lto-cache",
+        "hello-windows-lto-1",
+        "hello-windows-lto-2",
+    ] {
+        let _ = fs_err::remove_dir_all(tmp_dir.join(dir));
+    }
+    let args = [
+        "--lto-cache-dir",
+        lto_cache_dir.to_str().unwrap(),
+        "--xwin-rustflags=-Clinker-plugin-lto",
+    ];
+    let cache_entries = || {
+        let mut entries: Vec<_> = fs_err::read_dir(&lto_cache_dir)
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.file_name(), entry.metadata().unwrap().len())
+            })
+            .collect();
+        entries.sort();
+        entries
+    };
+
+    let out_dir = xwin_build_with_args("hello-windows", "hello-windows-lto-1", &args);
+    assert!(out_dir.join("hello-windows.exe").is_file());
+    let populated = cache_entries();
+    assert!(
+        !populated.is_empty(),
+        "lld-link didn't populate the LTO cache"
+    );
+    // A clean build of the same code links the same bitcode, which is served from the cache
+    xwin_build_with_args("hello-windows", "hello-windows-lto-2", &args);
+    assert_eq!(cache_entries(), populated, "the LTO cache wasn't reused");
+}
@@ -0,0 +1,5 @@
+fn main() {
+    let dst = cmake::build("hello-c");
+    println!("cargo:rustc-link-search=native={}/lib", dst.display());
+    println!("cargo:rustc-link-lib=static=hello");
+}
@@ -0,0 +1 @@
+int hello_answer(void) { return 42; }
@@ -0,0 +1,8 @@
+extern "C" {
+    fn hello_answer() -> i32;
+}
+
+fn main() {
+    let answer = unsafe { hello_answer() };
+    println!("Hello, {answer}!");
+}
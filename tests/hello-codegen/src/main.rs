@@ -0,0 +1,7 @@
+// Exits with the codegen options that are enabled, so that the tests can check that the
+// user's rustflags still apply when cargo-xwin adds its own
+fn main() {
+    let overflow_checks = std::panic::catch_unwind(|| std::hint::black_box(u8::MAX) + 1).is_err();
+    let code = i32::from(cfg!(debug_assertions)) | i32::from(overflow_checks) << 1;
+    std::process::exit(code);
+}
@@ -0,0 +1,11 @@
+/// Returns the id of the current process.
+///
+/// ```
+/// assert_ne!(hello_doctest::current_process_id(), 0);
+/// ```
+pub fn current_process_id() -> u32 {
+    extern "system" {
+        fn GetCurrentProcessId() -> u32;
+    }
+    unsafe { GetCurrentProcessId() }
+}
@@ -0,0 +1,5 @@
+fn main() {
+    // Only links and runs if the C code of `host-c` was compiled for the host
+    assert_eq!(host_c::answer(), 42);
+    cc::Build::new().file("src/hello.c").compile("hello_c");
+}
@@ -0,0 +1 @@
+int answer_from_c(void) { return 42; }
@@ -0,0 +1,7 @@
+extern "C" {
+    fn answer_from_c() -> i32;
+}
+
+pub fn answer() -> i32 {
+    unsafe { answer_from_c() }
+}
@@ -0,0 +1,7 @@
+extern "C" {
+    fn hello_from_c() -> i32;
+}
+
+fn main() {
+    println!("Hello, {}!", unsafe { hello_from_c() });
+}
@@ -0,0 +1,8 @@
+// Built with `RUSTFLAGS="-C target-cpu=x86-64-v3"`, make sure it isn't dropped
+// when cargo-xwin adds its own rustflags
+#[cfg(not(target_feature = "avx2"))]
+compile_error!("`-C target-cpu` from RUSTFLAGS is not applied");
+
+fn main() {
+    println!("Hello, world!");
+}
@@ -0,0 +1,4 @@
+fn main() {
+    // Archived with `TARGET_AR`, rustc bundles it into the staticlib
+    cc::Build::new().file("src/hello.c").compile("hello_c");
+}
@@ -0,0 +1 @@
+int hello_from_c(void) { return 42; }
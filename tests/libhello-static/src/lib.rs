@@ -0,0 +1,8 @@
+extern "C" {
+    fn hello_from_c() -> i32;
+}
+
+#[no_mangle]
+pub extern "C" fn hello_from_rust() -> i32 {
+    unsafe { hello_from_c() }
+}
@@ -2,3 +2,12 @@
 pub extern "C" fn hello() {
     println!("hello");
 }
+
+/// Returns the id of the current process, only available on Windows.
+#[cfg(windows)]
+pub fn current_process_id() -> u32 {
+    extern "system" {
+        fn GetCurrentProcessId() -> u32;
+    }
+    unsafe { GetCurrentProcessId() }
+}